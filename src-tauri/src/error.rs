@@ -0,0 +1,93 @@
+use serde::ser::SerializeStruct;
+use serde::{Serialize, Serializer};
+
+use rodio::decoder::DecoderError;
+use rodio::source::SeekError;
+
+use std::path::PathBuf;
+
+use crate::states::registry::{ClipId, TrackId};
+
+// Every command fails with one of these, the frontend receives `{ kind, message }`
+#[derive(Debug, thiserror::Error)]
+pub enum AllomereError {
+    #[error("Failed to read {path:?}: {source}")]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Can't decode {path:?}: {source}")]
+    Decode { path: PathBuf, source: DecoderError },
+    #[error("Unsupported format: {0}")]
+    UnsupportedFormat(String),
+    #[error("No clip with id {0}")]
+    ClipNotFound(ClipId),
+    #[error("No track with id {0}")]
+    TrackNotFound(TrackId),
+    #[error("No audio loaded for {0}")]
+    AudioDataNotFound(String),
+    #[error("{0} hasn't finished analysis")]
+    AnalysisPending(String),
+    #[error("Analysis failed: {0}")]
+    Analysis(String),
+    #[error("Beat {beat} is out of range, the clip has {beats} beats")]
+    BeatOutOfRange { beat: usize, beats: usize },
+    #[error("Channel {channel} is out of range, the file has {channels} channels")]
+    ChannelOutOfRange { channel: u16, channels: u16 },
+    #[error("Frame {frame} isn't inside clip {id}")]
+    FrameOutOfRange { id: ClipId, frame: u64 },
+    #[error("No output device called {0}")]
+    OutputDeviceNotFound(String),
+    #[error("Output device {0} was disconnected")]
+    OutputDeviceLost(String),
+    #[error("The preview bus isn't routed to an output")]
+    NoPreviewOutput,
+    #[error("The preview bus is on the main output's channels and the main output is paused")]
+    PreviewOutputPaused,
+    #[error("Beat index error: {0}")]
+    Index(String),
+    #[error("Failed to seek: {0}")]
+    Seek(#[from] SeekError),
+    #[error("Python error: {0}")]
+    Python(#[from] pyo3::PyErr),
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+impl AllomereError {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AllomereError::Io { .. } => "io",
+            AllomereError::Decode { .. } => "decode",
+            AllomereError::UnsupportedFormat(_) => "unsupportedFormat",
+            AllomereError::ClipNotFound(_) => "clipNotFound",
+            AllomereError::TrackNotFound(_) => "trackNotFound",
+            AllomereError::AudioDataNotFound(_) => "audioDataNotFound",
+            AllomereError::AnalysisPending(_) => "analysisPending",
+            AllomereError::Analysis(_) => "analysis",
+            AllomereError::BeatOutOfRange { .. } => "beatOutOfRange",
+            AllomereError::ChannelOutOfRange { .. } => "channelOutOfRange",
+            AllomereError::FrameOutOfRange { .. } => "frameOutOfRange",
+            AllomereError::OutputDeviceNotFound(_) => "outputDeviceNotFound",
+            AllomereError::OutputDeviceLost(_) => "outputDeviceLost",
+            AllomereError::NoPreviewOutput => "noPreviewOutput",
+            AllomereError::PreviewOutputPaused => "previewOutputPaused",
+            AllomereError::Index(_) => "index",
+            AllomereError::Seek(_) => "seek",
+            AllomereError::Python(_) => "python",
+            AllomereError::Other(_) => "other",
+        }
+    }
+}
+
+impl Serialize for AllomereError {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("AllomereError", 2)?;
+        state.serialize_field("kind", self.kind())?;
+        state.serialize_field("message", &self.to_string())?;
+        state.end()
+    }
+}
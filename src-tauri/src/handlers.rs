@@ -1,4 +1,10 @@
 pub mod audio;
+pub mod cache;
+pub mod export;
+pub mod history;
+pub mod output;
 pub mod playback;
+pub mod preview;
+pub mod project;
 pub mod state;
 pub mod window;
@@ -1,24 +1,58 @@
 use anyhow::{anyhow, Result};
 use numpy::PyArray2;
-use pyo3::ffi::c_str;
 use pyo3::prelude::*;
 use std::time::{Duration, Instant};
 
+use crate::error::AllomereError;
+use crate::states::beats::{self, BeatTrackerKind};
+use crate::states::features::{self, EmbeddingExtractorKind};
+use crate::states::library;
+use crate::states::playback;
+use crate::states::python;
+
 #[tauri::command]
-pub fn get_beats(path: &str, sample_rate: u32) -> (Vec<f32>, Vec<u32>) {
-    let now = Instant::now();
+pub fn get_beat_tracker() -> Result<BeatTrackerKind, AllomereError> {
+    Ok(beats::beat_tracker().kind())
+}
 
-    let py_result: Result<(Vec<f32>, Vec<u32>)> = Python::with_gil(|py| {
-        println!("Downloading and loading models...");
+// Clips analysed afterwards use the new tracker, cached results from the other one are kept apart
+#[tauri::command]
+pub fn set_beat_tracker(kind: BeatTrackerKind) -> Result<BeatTrackerKind, AllomereError> {
+    beats::set_beat_tracker(kind);
+    println!("Beat tracker set to {:?}", kind);
+    Ok(kind)
+}
+
+#[tauri::command]
+pub fn get_embedding_extractor() -> Result<EmbeddingExtractorKind, AllomereError> {
+    Ok(features::embedding_extractor().kind())
+}
+
+// Tracks created afterwards size their beat index for the new extractor. Changing
+// it empties the transition library, features of the two can't be compared, and
+// analyses every loaded file again to fill it
+#[tauri::command]
+pub fn set_embedding_extractor(
+    kind: EmbeddingExtractorKind,
+) -> Result<EmbeddingExtractorKind, AllomereError> {
+    if features::embedding_extractor().kind() != kind {
+        features::set_embedding_extractor(kind);
+        library::reset();
+        playback::reanalyse_all();
+        println!("Embedding extractor set to {:?}, library emptied", kind);
+    }
+    Ok(kind)
+}
 
-        let module = PyModule::from_code(
-            py,
-            c_str!(include_str!("../../../src-python/src/beats.py")),
-            c_str!("beats.py"),
-            c_str!("beats"),
-        )?;
+#[tauri::command]
+pub fn get_beats(path: &str, sample_rate: u32) -> Result<(Vec<f32>, Vec<u32>), AllomereError> {
+    let now = Instant::now();
 
-        let get_beats: Py<PyAny> = module.getattr("get_beats")?.into();
+    let py_result: Result<(Vec<f32>, Vec<u32>)> = Python::with_gil(|py| {
+        let get_beats: Py<PyAny> = python::beats_module(py)?
+            .bind(py)
+            .getattr("get_beats")?
+            .into();
 
         let (tempo, beat_track): (Vec<f32>, Vec<u32>) =
             get_beats.call1(py, (path, sample_rate))?.extract(py)?;
@@ -33,29 +67,26 @@ pub fn get_beats(path: &str, sample_rate: u32) -> (Vec<f32>, Vec<u32>) {
         now.elapsed().as_secs_f32()
     );
 
-    py_result.unwrap()
+    Ok(py_result?)
 }
 
 #[tauri::command]
-pub fn get_features(audio: Vec<Vec<f32>>, sample_rate: u32) -> Vec<Vec<f32>> {
+pub fn get_features(
+    audio: Vec<Vec<f32>>,
+    sample_rate: u32,
+) -> Result<Vec<Vec<f32>>, AllomereError> {
     let now = Instant::now();
 
-    let py_result: Result<(Vec<Vec<f32>>)> = Python::with_gil(|py| {
-        println!("Downloading and loading models...");
-
-        let module = PyModule::from_code_bound(
-            py,
-            include_str!("../../../src-python/src/features.py"),
-            "features.py",
-            "features",
-        )?;
-
-        let get_audio_features: Py<PyAny> = module.getattr("get_audio_features")?.into();
+    let py_result: Result<Vec<Vec<f32>>> = Python::with_gil(|py| {
+        let get_audio_features: Py<PyAny> = python::features_module(py)?
+            .bind(py)
+            .getattr("get_audio_features")?
+            .into();
 
         let mut features: Vec<Vec<f32>> = Vec::new();
 
         for (i, audio_chunk) in audio.chunks(10).enumerate() {
-            let audio_array = PyArray2::from_vec2_bound(py, audio_chunk)?;
+            let audio_array = PyArray2::from_vec2(py, audio_chunk)?;
 
             println!("Processing chunk: {}", i);
 
@@ -64,24 +95,9 @@ pub fn get_features(audio: Vec<Vec<f32>>, sample_rate: u32) -> Vec<Vec<f32>> {
                     .call1(py, (audio_array, sample_rate))?
                     .extract::<Vec<Vec<f32>>>(py)?,
             );
-
-            // features.push(
-            //     (get_audio_features
-            //         .call1(py, (audio_array, sample_rate))?
-            //         .extract::<Vec<Vec<f32>>>(py)?)[0]
-            //         .clone(),
-            // );
         }
 
-        // let audio_array = PyArray2::from_vec2_bound(py, &audio)?;
-
-        // let features: Vec<Vec<f32>> = get_audio_features
-        //     .call1(py, (audio_array, sample_rate))?
-        //     .extract(py)?;
-
-        // println!("{}", tempo[0]);
-
-        Ok((features))
+        Ok(features)
     });
 
     println!(
@@ -89,5 +105,5 @@ pub fn get_features(audio: Vec<Vec<f32>>, sample_rate: u32) -> Vec<Vec<f32>> {
         now.elapsed().as_secs_f32()
     );
 
-    py_result.unwrap()
+    Ok(py_result?)
 }
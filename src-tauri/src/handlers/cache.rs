@@ -0,0 +1,14 @@
+use crate::error::AllomereError;
+use crate::states::cache::{self, AnalysisCacheInfo};
+
+#[tauri::command]
+pub fn get_analysis_cache() -> Result<AnalysisCacheInfo, AllomereError> {
+    Ok(cache::info()?)
+}
+
+#[tauri::command]
+pub fn clear_analysis_cache() -> Result<usize, AllomereError> {
+    let removed = cache::clear()?;
+    println!("Removed {} analysis cache entries", removed);
+    Ok(removed)
+}
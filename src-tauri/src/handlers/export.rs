@@ -0,0 +1,74 @@
+use crate::error::AllomereError;
+use crate::states::{
+    self,
+    export::{ExportFormat, ExportProgress, MixExport},
+};
+
+use std::path::PathBuf;
+use std::time::Instant;
+use tauri::{Manager, State, WebviewWindow};
+
+#[tauri::command]
+pub fn export_mix(
+    window: WebviewWindow,
+    playback_state: State<states::playback::PlaybackState>,
+    global_app_state: State<states::GlobalAppState>,
+    path: String,
+    format: Option<ExportFormat>,
+    bits_per_sample: Option<u16>,
+    end_frame: Option<u64>,
+) -> Result<(), AllomereError> {
+    let path = PathBuf::from(path);
+    let format = format
+        .or_else(|| ExportFormat::from_path(&path))
+        .ok_or_else(|| AllomereError::UnsupportedFormat(path.to_string_lossy().to_string()))?;
+
+    let export = {
+        let tracks = global_app_state.tracks.lock();
+        MixExport::new(
+            &tracks,
+            path.clone(),
+            format,
+            playback_state.config.channels(),
+            playback_state.config.sample_rate().0,
+            bits_per_sample.unwrap_or(16),
+            end_frame,
+        )?
+    };
+
+    let app_handle = window.app_handle().clone();
+
+    // The render runs as fast as the mix can be computed, off the async workers
+    tauri::async_runtime::spawn_blocking(move || {
+        let now = Instant::now();
+        let mut progress = ExportProgress::new(path, export.total_frames);
+
+        let _ = states::emit_state_sync_handle("export", &progress, &app_handle);
+
+        let result = export.render(|rendered_frames| {
+            progress.rendered_frames = rendered_frames;
+            let _ = states::emit_state_sync_handle("export", &progress, &app_handle);
+        });
+
+        match result {
+            Ok(rendered_frames) => {
+                progress.rendered_frames = rendered_frames;
+                progress.state = "complete".to_string();
+            }
+            Err(e) => {
+                eprintln!("Failed to export_mix: {}", e);
+                progress.state = "failed".to_string();
+                progress.error = Some(e.to_string());
+            }
+        }
+
+        println!(
+            "export_mix - Elapsed time: {:?}",
+            now.elapsed().as_secs_f32()
+        );
+
+        let _ = states::emit_state_sync_handle("export", &progress, &app_handle);
+    });
+
+    Ok(())
+}
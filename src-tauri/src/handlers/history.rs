@@ -0,0 +1,49 @@
+use crate::error::AllomereError;
+use crate::states::{self, history::HistorySummary};
+
+use tauri::{State, WebviewWindow};
+
+fn sync(
+    window: &WebviewWindow,
+    playback_state: &states::playback::PlaybackState,
+    tracks: &[states::playback::Track],
+    history: &states::history::History,
+) {
+    let _ = states::emit_state_sync("history", &history.summary(), window);
+    let _ = states::emit_state_sync("tracks", &tracks, window);
+    let _ = states::emit_state_sync("playback", playback_state, window);
+}
+
+// Returns the label of the edit undone, None when there was nothing to undo
+#[tauri::command]
+pub fn undo(
+    window: WebviewWindow,
+    playback_state: State<states::playback::PlaybackState>,
+    global_app_state: State<states::GlobalAppState>,
+) -> Result<Option<String>, AllomereError> {
+    let mut tracks = global_app_state.tracks.lock();
+    let mut history = global_app_state.history.lock();
+
+    let result = history.undo(&mut tracks, &playback_state);
+    sync(&window, &playback_state, &tracks, &history);
+    result
+}
+
+#[tauri::command]
+pub fn redo(
+    window: WebviewWindow,
+    playback_state: State<states::playback::PlaybackState>,
+    global_app_state: State<states::GlobalAppState>,
+) -> Result<Option<String>, AllomereError> {
+    let mut tracks = global_app_state.tracks.lock();
+    let mut history = global_app_state.history.lock();
+
+    let result = history.redo(&mut tracks, &playback_state);
+    sync(&window, &playback_state, &tracks, &history);
+    result
+}
+
+#[tauri::command]
+pub fn get_history(global_app_state: State<states::GlobalAppState>) -> HistorySummary {
+    global_app_state.history.lock().summary()
+}
@@ -0,0 +1,42 @@
+use crate::error::AllomereError;
+use crate::states::{
+    self,
+    output::{self, OutputDevice, OutputDeviceInfo},
+    preview::{PreviewOutput, PreviewState},
+};
+
+use tauri::{State, WebviewWindow};
+
+#[tauri::command]
+pub fn list_output_devices() -> Result<Vec<OutputDeviceInfo>, AllomereError> {
+    Ok(output::list_devices()?)
+}
+
+// None while playing without a device, on null output
+#[tauri::command]
+pub fn get_output_device(
+    playback_state: State<states::playback::PlaybackState>,
+) -> Option<OutputDevice> {
+    playback_state.backend.lock().device()
+}
+
+// A `name` of None is the default device. The sample rate and buffer size, in
+// frames, default to the device's own. A preview on channels the new device
+// doesn't have is taken off it
+#[tauri::command]
+pub fn set_output_device(
+    window: WebviewWindow,
+    playback_state: State<states::playback::PlaybackState>,
+    preview_state: State<PreviewState>,
+    name: Option<String>,
+    sample_rate: Option<u32>,
+    buffer_size: Option<u32>,
+) -> Result<Option<OutputDevice>, AllomereError> {
+    playback_state.set_output_device(name.as_deref(), sample_rate, buffer_size)?;
+    if preview_state.fit_main_output(&playback_state) {
+        let _ = states::emit_state_sync("previewOutput", &None::<PreviewOutput>, &window);
+    }
+
+    let _ = states::emit_state_sync("playback", playback_state.inner(), &window);
+    Ok(playback_state.backend.lock().device())
+}
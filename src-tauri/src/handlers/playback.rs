@@ -1,47 +1,221 @@
 use std::ops::Deref;
 
+use crate::error::AllomereError;
 use crate::states::{
-    self, playback::AllomereMutex, playback::AudioData, playback::Clip, playback::AUDIO_DATA_MAP,
+    self, endless, endless::EndlessSettings, history::Edit, history::TrackMix,
+    library::TransitionCandidate, library::TransitionScope, playback::AllomereMutex,
+    playback::AudioData, playback::Clip, playback::CrossfadeLength, playback::GainPoint,
+    playback::SourceFrame, playback::TimelineFrame, playback::AUDIO_DATA_MAP, registry,
+    registry::ClipId, registry::TrackId, transport::Position, transport::TimeSignature,
+    transport::Transport,
 };
 
-use rodio::cpal::traits::StreamTrait;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 use tauri::{State, WebviewWindow};
-use usearch;
 
-#[tauri::command]
-pub fn play(window: WebviewWindow, playback_state: State<states::playback::PlaybackState>) {
-    let stream_clone = playback_state.stream.clone();
-    let stream = stream_clone.lock();
+// Clips on tracks that aren't in `tracks`, like ones parked in the history, aren't found
+pub fn find_clip(
+    tracks: &[states::playback::Track],
+    id: ClipId,
+) -> Result<Arc<AllomereMutex<Clip>>, AllomereError> {
+    registry::clip(id)
+        .filter(|(_, track_id)| tracks.iter().any(|track| track.id == *track_id))
+        .map(|(clip, _)| clip)
+        .ok_or(AllomereError::ClipNotFound(id))
+}
+
+// The track clip `id` is on
+pub fn find_clip_track(
+    tracks: &mut [states::playback::Track],
+    id: ClipId,
+) -> Result<&mut states::playback::Track, AllomereError> {
+    let (_, track_id) = registry::clip(id).ok_or(AllomereError::ClipNotFound(id))?;
+    tracks
+        .iter_mut()
+        .find(|track| track.id == track_id)
+        .ok_or(AllomereError::ClipNotFound(id))
+}
+
+pub fn find_track(
+    tracks: &mut [states::playback::Track],
+    id: TrackId,
+) -> Result<&mut states::playback::Track, AllomereError> {
+    tracks
+        .iter_mut()
+        .find(|track| track.id == id)
+        .ok_or(AllomereError::TrackNotFound(id))
+}
+
+// Records an edit that's already been made and syncs the history
+pub fn record_edit(
+    global_app_state: &states::GlobalAppState,
+    window: &WebviewWindow,
+    label: &str,
+    edit: Edit,
+) {
+    let mut history = global_app_state.history.lock();
+    history.record(label, edit);
+    let _ = states::emit_state_sync("history", &history.summary(), window);
+}
+
+// Makes `change` to clip `id`, recording its loop settings before and after
+fn edit_clip_loop<T>(
+    global_app_state: &states::GlobalAppState,
+    window: &WebviewWindow,
+    id: ClipId,
+    label: &str,
+    change: impl FnOnce(&mut Clip) -> T,
+) -> Result<T, AllomereError> {
+    let tracks = global_app_state.tracks.lock();
+    let clip_ref = find_clip(&tracks, id)?;
+    let (before, after, result) = {
+        let mut clip = clip_ref.0.lock();
+        let before = clip.controller();
+        let result = change(&mut clip);
+        (before, clip.controller(), result)
+    };
 
-    match stream.play() {
-        Ok(_) => {}
-        Err(e) => {
-            eprintln!("Failed to play: {}", e);
-            // return;
+    if let (Some(before), Some(after)) = (before, after) {
+        if before != after {
+            record_edit(
+                global_app_state,
+                window,
+                label,
+                Edit::ClipLoop { id, before, after },
+            );
         }
+    }
+    Ok(result)
+}
+
+// Makes `change` to clip `id`, recording its trim, fades and envelope before and after
+fn edit_clip_edits(
+    global_app_state: &states::GlobalAppState,
+    window: &WebviewWindow,
+    id: ClipId,
+    label: &str,
+    change: impl FnOnce(&mut Clip),
+) -> Result<(), AllomereError> {
+    let tracks = global_app_state.tracks.lock();
+    let clip_ref = find_clip(&tracks, id)?;
+    let (before, after) = {
+        let mut clip = clip_ref.0.lock();
+        let before = clip.edits();
+        change(&mut clip);
+        (before, clip.edits())
     };
 
+    if let (Some(before), Some(after)) = (before, after) {
+        if before != after {
+            record_edit(
+                global_app_state,
+                window,
+                label,
+                Edit::ClipEdits { id, before, after },
+            );
+        }
+    }
+    // Trimming moves where the clip ends
+    let _ = states::emit_state_sync("tracks", &*tracks, window);
+    Ok(())
+}
+
+// Makes `change` to track `track_id`, recording its mix settings before and after
+fn edit_track_mix(
+    global_app_state: &states::GlobalAppState,
+    window: &WebviewWindow,
+    track_id: TrackId,
+    label: &str,
+    change: impl FnOnce(&mut states::playback::Track),
+) -> Result<(), AllomereError> {
+    let mut tracks = global_app_state.tracks.lock();
+    let track = find_track(&mut tracks, track_id)?;
+    let before = TrackMix::of(track);
+    change(track);
+    let after = TrackMix::of(track);
+    states::playback::Track::update_audible(&tracks);
+
+    if before != after {
+        record_edit(
+            global_app_state,
+            window,
+            label,
+            Edit::TrackMix {
+                track_id,
+                before,
+                after,
+            },
+        );
+    }
+
+    let _ = states::emit_state_sync("tracks", &*tracks, window);
+    Ok(())
+}
+
+// Makes `change` to the transport and restretches every clip, recording the
+// transport before and after
+fn edit_transport(
+    playback_state: &states::playback::PlaybackState,
+    global_app_state: &states::GlobalAppState,
+    window: &WebviewWindow,
+    label: &str,
+    change: impl FnOnce(&mut Transport),
+) -> Result<(), AllomereError> {
+    let tracks = global_app_state.tracks.lock();
+    let (before, after) = {
+        let mut transport = playback_state.transport.write();
+        let before = *transport;
+        change(&mut transport);
+        (before, *transport)
+    };
+    for track in &*tracks {
+        track.sync_tempo();
+    }
+
+    if before != after {
+        record_edit(
+            global_app_state,
+            window,
+            label,
+            Edit::Transport { before, after },
+        );
+    }
+
+    let _ = states::emit_state_sync("playback", playback_state, window);
+    let _ = states::emit_state_sync("tracks", &*tracks, window);
+    Ok(())
+}
+
+#[tauri::command]
+pub fn play(
+    window: WebviewWindow,
+    playback_state: State<states::playback::PlaybackState>,
+) -> Result<(), AllomereError> {
+    let backend_clone = playback_state.backend.clone();
+    let backend = backend_clone.lock();
+
+    backend.play()?;
+
     {
         let is_paused_clone = playback_state.is_paused.clone();
         *(is_paused_clone.write()) = false;
     }
 
     let _ = states::emit_state_sync("playback", playback_state.inner(), &window);
+
+    Ok(())
 }
 
 #[tauri::command]
-pub fn pause(window: WebviewWindow, playback_state: State<states::playback::PlaybackState>) {
-    let stream_clone = playback_state.stream.clone();
-    let stream = stream_clone.lock();
+pub fn pause(
+    window: WebviewWindow,
+    playback_state: State<states::playback::PlaybackState>,
+) -> Result<(), AllomereError> {
+    let backend_clone = playback_state.backend.clone();
+    let backend = backend_clone.lock();
 
-    match stream.pause() {
-        Ok(_) => {}
-        Err(e) => {
-            eprintln!("Failed to pause: {}", e);
-            // return;
-        }
-    };
+    backend.pause()?;
 
     {
         let is_paused_clone = playback_state.is_paused.clone();
@@ -49,19 +223,21 @@ pub fn pause(window: WebviewWindow, playback_state: State<states::playback::Play
     }
 
     let _ = states::emit_state_sync("playback", playback_state.inner(), &window);
+
+    Ok(())
 }
 
 #[tauri::command]
 pub fn toggle_playback(
     window: WebviewWindow,
     playback_state: State<states::playback::PlaybackState>,
-) {
+) -> Result<(), AllomereError> {
     let is_paused = { playback_state.is_paused.read().clone() };
 
     if is_paused {
-        play(window, playback_state);
+        play(window, playback_state)
     } else {
-        pause(window, playback_state);
+        pause(window, playback_state)
     }
 }
 
@@ -70,19 +246,20 @@ pub fn add_track(
     window: WebviewWindow,
     playback_state: State<states::playback::PlaybackState>,
     global_app_state: State<states::GlobalAppState>,
-) {
+) -> Result<(), AllomereError> {
     let mut tracks = global_app_state.tracks.lock();
     let mut track = states::playback::Track::new(
         None,
         playback_state.config.clone(),
         playback_state.total_frames.clone(),
+        playback_state.transport.clone(),
     );
 
     let mixer = playback_state.mixer.clone();
 
-    match track.sources_queue_output.take() {
-        Some(sources_queue_output) => {
-            mixer.add(sources_queue_output)
+    match track.output.take() {
+        Some(output) => {
+            mixer.add(output)
             // match mixer.add(sources_queue_output) {
             //     Ok(_) => {}
             //     Err(e) => {
@@ -96,11 +273,162 @@ pub fn add_track(
 
     // let mut tracks = global_app_state.tracks.lock();
 
+    let track_id = track.id;
     (*tracks).push(track);
 
-    println!("current tracks {:?}", tracks);
+    record_edit(
+        &global_app_state,
+        &window,
+        "Add track",
+        Edit::AddTrack {
+            track_id,
+            index: tracks.len() - 1,
+            track: None,
+        },
+    );
 
     let _ = states::emit_state_sync("tracks", &*tracks, &window);
+
+    Ok(())
+}
+
+#[tauri::command]
+pub fn set_track_gain(
+    window: WebviewWindow,
+    global_app_state: State<states::GlobalAppState>,
+    track_id: TrackId,
+    gain: f32,
+) -> Result<(), AllomereError> {
+    edit_track_mix(
+        &global_app_state,
+        &window,
+        track_id,
+        "Track gain",
+        |track| track.set_gain(gain),
+    )
+}
+
+#[tauri::command]
+pub fn set_track_pan(
+    window: WebviewWindow,
+    global_app_state: State<states::GlobalAppState>,
+    track_id: TrackId,
+    pan: f32,
+) -> Result<(), AllomereError> {
+    edit_track_mix(&global_app_state, &window, track_id, "Track pan", |track| {
+        track.set_pan(pan)
+    })
+}
+
+#[tauri::command]
+pub fn mute_track(
+    window: WebviewWindow,
+    global_app_state: State<states::GlobalAppState>,
+    track_id: TrackId,
+    muted: bool,
+) -> Result<(), AllomereError> {
+    edit_track_mix(
+        &global_app_state,
+        &window,
+        track_id,
+        "Mute track",
+        |track| track.set_muted(muted),
+    )
+}
+
+#[tauri::command]
+pub fn solo_track(
+    window: WebviewWindow,
+    global_app_state: State<states::GlobalAppState>,
+    track_id: TrackId,
+    soloed: bool,
+) -> Result<(), AllomereError> {
+    edit_track_mix(
+        &global_app_state,
+        &window,
+        track_id,
+        "Solo track",
+        |track| track.set_soloed(soloed),
+    )
+}
+
+// Stretches every clip to `tempo` in BPM, None turns tempo sync off
+#[tauri::command]
+pub fn set_master_tempo(
+    window: WebviewWindow,
+    playback_state: State<states::playback::PlaybackState>,
+    global_app_state: State<states::GlobalAppState>,
+    tempo: Option<f32>,
+) -> Result<(), AllomereError> {
+    edit_transport(
+        &playback_state,
+        &global_app_state,
+        &window,
+        "Master tempo",
+        |transport| match tempo.filter(|tempo| *tempo > 0.0) {
+            Some(tempo) => {
+                transport.tempo = tempo;
+                transport.tempo_sync = true;
+            }
+            None => transport.tempo_sync = false,
+        },
+    )
+}
+
+#[tauri::command]
+pub fn set_time_signature(
+    window: WebviewWindow,
+    playback_state: State<states::playback::PlaybackState>,
+    global_app_state: State<states::GlobalAppState>,
+    beats_per_bar: u32,
+    beat_unit: u32,
+) -> Result<(), AllomereError> {
+    edit_transport(
+        &playback_state,
+        &global_app_state,
+        &window,
+        "Time signature",
+        |transport| {
+            transport.time_signature = TimeSignature {
+                beats_per_bar: beats_per_bar.max(1),
+                beat_unit: beat_unit.max(1),
+            }
+        },
+    )
+}
+
+// Moves bar 1 to `position`, e.g. onto the first downbeat of the first clip
+#[tauri::command]
+pub fn set_grid_origin(
+    window: WebviewWindow,
+    playback_state: State<states::playback::PlaybackState>,
+    global_app_state: State<states::GlobalAppState>,
+    position: Position,
+) -> Result<(), AllomereError> {
+    let sample_rate = playback_state.config.sample_rate().0;
+    edit_transport(
+        &playback_state,
+        &global_app_state,
+        &window,
+        "Grid origin",
+        |transport| transport.grid_origin = transport.resolve(position, sample_rate),
+    )
+}
+
+// Same as try_seek_frame for a position in seconds, frames or bars and beats
+#[tauri::command]
+pub fn seek(
+    window: WebviewWindow,
+    playback_state: State<states::playback::PlaybackState>,
+    global_app_state: State<states::GlobalAppState>,
+    position: Position,
+) -> Result<u64, AllomereError> {
+    let frame = playback_state
+        .transport
+        .read()
+        .resolve(position, playback_state.config.sample_rate().0);
+
+    try_seek_frame(window, playback_state, global_app_state, frame)
 }
 
 #[tauri::command]
@@ -109,26 +437,36 @@ pub fn try_seek(
     playback_state: State<states::playback::PlaybackState>,
     global_app_state: State<states::GlobalAppState>,
     pos: f64,
-) {
-    println!("try_seek {:?}", pos);
+) -> Result<f64, AllomereError> {
+    let sample_rate = playback_state.config.sample_rate().0;
+    let frame = try_seek_frame(
+        window,
+        playback_state,
+        global_app_state,
+        (pos * (sample_rate as f64)).round() as u64,
+    )?;
+
+    Ok((frame as f64) / (sample_rate as f64))
+}
+
+#[tauri::command]
+pub fn try_seek_frame(
+    window: WebviewWindow,
+    playback_state: State<states::playback::PlaybackState>,
+    global_app_state: State<states::GlobalAppState>,
+    frame: u64,
+) -> Result<u64, AllomereError> {
     let mut tracks = global_app_state.tracks.lock();
 
     for track in &mut *tracks {
-        match track.try_seek(std::time::Duration::from_secs_f64(pos)) {
-            Ok(_) => {}
-            Err(e) => {
-                eprintln!("Failed to try_seek: {}", e);
-                return;
-            }
-        };
+        track.try_seek(TimelineFrame(frame))?;
     }
 
-    println!("Finished try_seek");
-
-    *(playback_state.total_frames.write()) =
-        (pos * (playback_state.config.sample_rate().0 as f64)) as u64;
+    *(playback_state.total_frames.write()) = frame;
 
     let _ = states::emit_state_sync("playback", playback_state.inner(), &window);
+
+    Ok(frame)
 }
 
 #[tauri::command]
@@ -136,26 +474,64 @@ pub fn get_clip(
     window: WebviewWindow,
     playback_state: State<states::playback::PlaybackState>,
     global_app_state: State<states::GlobalAppState>,
-    id: usize,
-) -> Option<Clip> {
-    let mut tracks = global_app_state.tracks.lock();
+    id: ClipId,
+) -> Result<Clip, AllomereError> {
+    let clip_ref = find_clip(&global_app_state.tracks.lock(), id)?;
 
-    let clip = (*tracks).iter().find_map(|track| {
-        track.clips.iter().find_map(|clip| {
-            if ((*clip).0.lock()).id == id {
-                Some((*clip).clone())
-            } else {
-                None
-            }
-        })
-    });
+    let clip = clip_ref.0.lock().clone();
+    Ok(clip)
+}
 
-    if let Some(clip) = clip {
-        println!("Found: {}", clip.0.lock().id);
-        Some(clip.0.lock().clone())
-    } else {
-        None
-    }
+// Beat positions in playback frames from the clip's start, following its tempo sync
+#[tauri::command]
+pub fn get_clip_beat_track(
+    playback_state: State<states::playback::PlaybackState>,
+    global_app_state: State<states::GlobalAppState>,
+    id: ClipId,
+) -> Result<Vec<i64>, AllomereError> {
+    let clip_ref = find_clip(&global_app_state.tracks.lock(), id)?;
+    let clip = clip_ref.0.lock();
+
+    clip.timeline_beat_track(playback_state.config.sample_rate().0)
+        .ok_or_else(|| AllomereError::AnalysisPending(clip.name.clone()))
+}
+
+// The beat of clip `id` playing at `position` on the timeline
+fn clip_beat_at(
+    playback_state: &states::playback::PlaybackState,
+    global_app_state: &states::GlobalAppState,
+    id: ClipId,
+    position: Position,
+) -> Result<usize, AllomereError> {
+    let frame = playback_state
+        .transport
+        .read()
+        .resolve(position, playback_state.config.sample_rate().0);
+
+    let tracks = global_app_state.tracks.lock();
+    let source_frame = tracks
+        .iter()
+        .find_map(|track| track.source_frame(id, TimelineFrame(frame)))
+        .ok_or(AllomereError::ClipNotFound(id))?;
+    let clip_ref = find_clip(&tracks, id)?;
+    let clip = clip_ref.0.lock();
+
+    clip.nearest_beat(source_frame)
+        .ok_or_else(|| AllomereError::AnalysisPending(clip.name.clone()))
+}
+
+#[tauri::command]
+pub fn get_clip_preferred_transition_beats_at(
+    window: WebviewWindow,
+    playback_state: State<states::playback::PlaybackState>,
+    global_app_state: State<states::GlobalAppState>,
+    id: ClipId,
+    position: Position,
+    count: usize,
+) -> Result<HashMap<u64, f32>, AllomereError> {
+    let beat = clip_beat_at(&playback_state, &global_app_state, id, position)?;
+
+    get_clip_preferred_transition_beats(window, playback_state, global_app_state, id, beat, count)
 }
 
 #[tauri::command]
@@ -163,44 +539,70 @@ pub fn get_clip_preferred_transition_beats(
     window: WebviewWindow,
     playback_state: State<states::playback::PlaybackState>,
     global_app_state: State<states::GlobalAppState>,
-    id: usize,
+    id: ClipId,
     beat: usize,
     count: usize,
-) -> Option<HashMap<u64, f32>> {
-    let mut tracks = global_app_state.tracks.lock();
+) -> Result<HashMap<u64, f32>, AllomereError> {
+    let clip_ref = find_clip(&global_app_state.tracks.lock(), id)?;
+    let clip = clip_ref.0.lock();
+
+    let map = clip
+        .get_preferred_transition_beats(beat, count)?
+        .into_iter()
+        .map(|(beat, distance)| (beat as u64, distance))
+        .collect();
+    Ok(map)
+}
 
-    let track_and_clip = (*tracks).iter().find_map(|track| {
-        track.clips.iter().find_map(|clip| {
-            if ((*clip).0.lock()).id == id {
-                Some((track, (*clip).clone()))
-            } else {
-                None
-            }
-        })
-    });
+// Jump points from the clip's `beat` into other songs, or within the same one,
+// closest match first. Scope defaults to other songs
+#[tauri::command]
+pub fn find_transitions(
+    global_app_state: State<states::GlobalAppState>,
+    from_clip: ClipId,
+    beat: usize,
+    k: usize,
+    scope: Option<TransitionScope>,
+) -> Result<Vec<TransitionCandidate>, AllomereError> {
+    let (clip_ref, project) = {
+        let tracks = global_app_state.tracks.lock();
+        let project: HashSet<String> = tracks
+            .iter()
+            .flat_map(|track| track.clips.iter())
+            .filter_map(|clip| clip.0.lock().audio_hash().ok())
+            .collect();
+        (find_clip(&tracks, from_clip)?, project)
+    };
+    let clip = clip_ref.0.lock();
+
+    clip.find_transitions(
+        beat,
+        k,
+        scope.unwrap_or(TransitionScope::OtherSongs),
+        &project,
+    )
+}
 
-    if let Some((track, clip_ref)) = track_and_clip {
-        let clip = clip_ref.0.lock();
-        if let Some(beat_index) = &track.beat_index {
-            {
-                println!("Beat Index Size: {}", beat_index.clone().lock().size())
-            }
-            let matches = clip.get_preferred_transition_beats(beat_index.clone(), beat, count);
-            let map = matches
-                .keys
-                .iter()
-                .cloned()
-                .zip(matches.distances.iter().cloned())
-                .collect();
-            println!("{:#?}", map);
-            Some(map)
-        } else {
-            None
-        }
-        // Some(clip_ref.0.lock().clone())
-    } else {
-        None
-    }
+// Keeps jumping between similar beats of the clip so it plays on indefinitely,
+// the clip's own loop is put back once the mix stops
+#[tauri::command]
+pub fn start_endless_mix(
+    global_app_state: State<states::GlobalAppState>,
+    id: ClipId,
+    settings: Option<EndlessSettings>,
+) -> Result<(), AllomereError> {
+    let tracks = global_app_state.tracks.lock();
+
+    let clip_ref = find_clip(&tracks, id)?;
+
+    endless::start(clip_ref, settings.unwrap_or_default());
+
+    Ok(())
+}
+
+#[tauri::command]
+pub fn stop_endless_mix(id: ClipId) -> Result<bool, AllomereError> {
+    Ok(endless::stop(id))
 }
 
 #[tauri::command]
@@ -208,32 +610,56 @@ pub fn set_clip_loop(
     window: WebviewWindow,
     playback_state: State<states::playback::PlaybackState>,
     global_app_state: State<states::GlobalAppState>,
-    id: usize,
+    id: ClipId,
     start_pos: f64,
     end_pos: f64,
-) -> Option<()> {
-    let mut tracks = global_app_state.tracks.lock();
-
-    let clip = (*tracks).iter().find_map(|track| {
-        track.clips.iter().find_map(|clip| {
-            if ((*clip).0.lock()).id == id {
-                Some((*clip).clone())
-            } else {
-                None
-            }
-        })
-    });
-
-    if let Some(clip_ref) = clip {
-        let mut clip = clip_ref.0.lock();
+) -> Result<(), AllomereError> {
+    edit_clip_loop(&global_app_state, &window, id, "Set loop", |clip| {
         clip.set_loop(
             std::time::Duration::from_secs_f64(start_pos),
             std::time::Duration::from_secs_f64(end_pos),
-        );
-        Some(())
-    } else {
-        None
-    }
+        )
+    })?
+}
+
+// Loop points on the timeline, mapped into the clip's source frames
+#[tauri::command]
+pub fn set_clip_loop_at(
+    window: WebviewWindow,
+    playback_state: State<states::playback::PlaybackState>,
+    global_app_state: State<states::GlobalAppState>,
+    id: ClipId,
+    start: Position,
+    end: Position,
+) -> Result<(), AllomereError> {
+    let sample_rate = playback_state.config.sample_rate().0;
+    let (start_frame, end_frame) = {
+        let transport = playback_state.transport.read();
+        (
+            transport.resolve(start, sample_rate),
+            transport.resolve(end, sample_rate),
+        )
+    };
+
+    let (start_frame, end_frame) = global_app_state
+        .tracks
+        .lock()
+        .iter()
+        .find_map(|track| {
+            track
+                .source_frame(id, TimelineFrame(start_frame))
+                .zip(track.source_frame(id, TimelineFrame(end_frame)))
+        })
+        .ok_or(AllomereError::ClipNotFound(id))?;
+    // Loop points are u32 source frames, later frames of very long files can't be looped
+    let to_u32 = |frame: SourceFrame| {
+        u32::try_from(frame.0).map_err(|_| AllomereError::FrameOutOfRange { id, frame: frame.0 })
+    };
+    let (start_frame, end_frame) = (to_u32(start_frame)?, to_u32(end_frame)?);
+
+    edit_clip_loop(&global_app_state, &window, id, "Set loop", |clip| {
+        clip.set_loop_frames(start_frame, end_frame)
+    })?
 }
 
 #[tauri::command]
@@ -241,29 +667,58 @@ pub fn set_clip_loop_frames(
     window: WebviewWindow,
     playback_state: State<states::playback::PlaybackState>,
     global_app_state: State<states::GlobalAppState>,
-    id: usize,
+    id: ClipId,
     start_frame: u32,
     end_frame: u32,
-) -> Option<()> {
-    let mut tracks = global_app_state.tracks.lock();
+) -> Result<(), AllomereError> {
+    edit_clip_loop(&global_app_state, &window, id, "Set loop", |clip| {
+        clip.set_loop_frames(start_frame, end_frame)
+    })?
+}
 
-    let clip = (*tracks).iter().find_map(|track| {
-        track.clips.iter().find_map(|clip| {
-            if ((*clip).0.lock()).id == id {
-                Some((*clip).clone())
-            } else {
-                None
-            }
-        })
-    });
+#[tauri::command]
+pub fn set_clip_loop_count(
+    window: WebviewWindow,
+    playback_state: State<states::playback::PlaybackState>,
+    global_app_state: State<states::GlobalAppState>,
+    id: ClipId,
+    loop_count: Option<u16>,
+) -> Result<(), AllomereError> {
+    edit_clip_loop(&global_app_state, &window, id, "Loop count", |clip| {
+        clip.set_loop_count(loop_count)
+    })
+}
 
-    if let Some(clip_ref) = clip {
-        let mut clip = clip_ref.0.lock();
-        clip.set_loop_frames((start_frame), (end_frame));
-        Some(())
-    } else {
-        None
-    }
+#[tauri::command]
+pub fn set_clip_loop_crossfade(
+    window: WebviewWindow,
+    playback_state: State<states::playback::PlaybackState>,
+    global_app_state: State<states::GlobalAppState>,
+    id: ClipId,
+    length: CrossfadeLength,
+) -> Result<u32, AllomereError> {
+    edit_clip_loop(&global_app_state, &window, id, "Loop crossfade", |clip| {
+        // Beat lengths need the beat track, which isn't there until analysis finishes
+        clip.set_loop_crossfade(length)
+            .ok_or_else(|| AllomereError::AnalysisPending(clip.name.clone()))
+    })?
+}
+
+#[tauri::command]
+pub fn set_clip_loop_zero_crossing(
+    window: WebviewWindow,
+    playback_state: State<states::playback::PlaybackState>,
+    global_app_state: State<states::GlobalAppState>,
+    id: ClipId,
+    enabled: bool,
+) -> Result<(), AllomereError> {
+    edit_clip_loop(
+        &global_app_state,
+        &window,
+        id,
+        "Loop zero crossing",
+        |clip| clip.set_loop_zero_crossing(enabled),
+    )
 }
 
 #[tauri::command]
@@ -271,27 +726,152 @@ pub fn clear_clip_loop(
     window: WebviewWindow,
     playback_state: State<states::playback::PlaybackState>,
     global_app_state: State<states::GlobalAppState>,
-    id: usize,
-) -> Option<()> {
+    id: ClipId,
+) -> Result<(), AllomereError> {
+    edit_clip_loop(&global_app_state, &window, id, "Clear loop", |clip| {
+        clip.clear_loop()
+    })
+}
+
+// Stops any endless mix on the clip too
+#[tauri::command]
+pub fn remove_clip(
+    window: WebviewWindow,
+    global_app_state: State<states::GlobalAppState>,
+    id: ClipId,
+) -> Result<(), AllomereError> {
     let mut tracks = global_app_state.tracks.lock();
 
-    let clip = (*tracks).iter().find_map(|track| {
-        track.clips.iter().find_map(|clip| {
-            if ((*clip).0.lock()).id == id {
-                Some((*clip).clone())
-            } else {
-                None
-            }
-        })
-    });
+    let track = find_clip_track(&mut tracks, id)?;
+    let track_id = track.id;
+    let clip = track.take_clip(id).ok_or(AllomereError::ClipNotFound(id))?;
+    endless::stop(id);
 
-    if let Some(clip_ref) = clip {
-        let mut clip = clip_ref.0.lock();
-        clip.clear_loop();
-        Some(())
-    } else {
-        None
-    }
+    record_edit(
+        &global_app_state,
+        &window,
+        "Remove clip",
+        Edit::RemoveClip { track_id, clip },
+    );
+
+    let _ = states::emit_state_sync("tracks", &*tracks, &window);
+    Ok(())
+}
+
+// Moves the clip to `start_at` on the timeline of track `track_id`, which can be
+// the track it's on already
+#[tauri::command]
+pub fn move_clip(
+    window: WebviewWindow,
+    global_app_state: State<states::GlobalAppState>,
+    id: ClipId,
+    track_id: TrackId,
+    start_at: u64,
+) -> Result<(), AllomereError> {
+    let mut tracks = global_app_state.tracks.lock();
+    find_track(&mut tracks, track_id)?;
+
+    let from_track = find_clip_track(&mut tracks, id)?;
+    let from_track_id = from_track.id;
+    let clip_ref = from_track
+        .take_clip(id)
+        .ok_or(AllomereError::ClipNotFound(id))?;
+    let from_start_at = clip_ref.0.lock().start_at.replace(start_at).unwrap_or(0);
+    find_track(&mut tracks, track_id)?.schedule_clip(clip_ref);
+
+    record_edit(
+        &global_app_state,
+        &window,
+        "Move clip",
+        Edit::MoveClip {
+            id,
+            before: (from_track_id, from_start_at),
+            after: (track_id, start_at),
+        },
+    );
+
+    let _ = states::emit_state_sync("tracks", &*tracks, &window);
+    Ok(())
+}
+
+// Cuts the clip at `frame` on the timeline, returns the id of the clip after the cut
+#[tauri::command]
+pub fn split_clip(
+    window: WebviewWindow,
+    global_app_state: State<states::GlobalAppState>,
+    id: ClipId,
+    frame: u64,
+) -> Result<ClipId, AllomereError> {
+    let mut tracks = global_app_state.tracks.lock();
+
+    let track = find_clip_track(&mut tracks, id)?;
+    let before = find_clip(std::slice::from_ref(track), id)?
+        .0
+        .lock()
+        .edits()
+        .unwrap_or_default();
+    let tail_id = track.split_clip(id, TimelineFrame(frame))?;
+
+    let track_id = track.id;
+    let head = find_clip(&tracks, id)?;
+    let after = head.0.lock().edits().unwrap_or_default();
+    let tail = find_clip(&tracks, tail_id)?;
+
+    record_edit(
+        &global_app_state,
+        &window,
+        "Split clip",
+        Edit::Group(vec![
+            Edit::ClipEdits { id, before, after },
+            Edit::AddClip {
+                track_id,
+                clip: tail,
+            },
+        ]),
+    );
+
+    let _ = states::emit_state_sync("tracks", &*tracks, &window);
+    Ok(tail_id)
+}
+
+// Frames are source frames, an end of None plays to the end of the file
+#[tauri::command]
+pub fn set_clip_trim(
+    window: WebviewWindow,
+    global_app_state: State<states::GlobalAppState>,
+    id: ClipId,
+    start_frame: u32,
+    end_frame: Option<u32>,
+) -> Result<(), AllomereError> {
+    edit_clip_edits(&global_app_state, &window, id, "Trim clip", |clip| {
+        clip.set_trim(start_frame, end_frame)
+    })
+}
+
+#[tauri::command]
+pub fn set_clip_fades(
+    window: WebviewWindow,
+    global_app_state: State<states::GlobalAppState>,
+    id: ClipId,
+    fade_in_frames: u32,
+    fade_out_frames: u32,
+) -> Result<(), AllomereError> {
+    edit_clip_edits(&global_app_state, &window, id, "Clip fades", |clip| {
+        clip.set_fades(fade_in_frames, fade_out_frames)
+    })
+}
+
+// An empty envelope leaves the clip at unity gain
+#[tauri::command]
+pub fn set_clip_gain_envelope(
+    window: WebviewWindow,
+    global_app_state: State<states::GlobalAppState>,
+    id: ClipId,
+    points: Vec<GainPoint>,
+) -> Result<(), AllomereError> {
+    edit_clip_edits(&global_app_state, &window, id, "Gain envelope", |clip| {
+        clip.set_gain_envelope(points)
+    })
 }
 
 #[tauri::command]
@@ -300,18 +880,53 @@ pub fn get_audio_data(
     playback_state: State<states::playback::PlaybackState>,
     global_app_state: State<states::GlobalAppState>,
     path: String,
-) -> Option<AudioData> {
+) -> Result<AudioData, AllomereError> {
     let audio_data_ref = {
         let audio_data_map_lock = AUDIO_DATA_MAP.lock();
         audio_data_map_lock.get(&path).cloned()
         // (AUDIO_DATA_MAP.lock().get(&path))
     };
 
-    if let Some(data) = audio_data_ref {
-        let audio_data_ref = data.clone();
-        let audio_data = audio_data_ref.lock();
-        Some(audio_data.clone())
-    } else {
-        None
+    let audio_data_ref = audio_data_ref.ok_or(AllomereError::AudioDataNotFound(path))?;
+    let audio_data = audio_data_ref.lock().clone();
+    Ok(audio_data)
+}
+
+// Peaks for drawing the waveform of `path`, one per `samples_per_pixel` frames
+// `channel` picks a channel, None mixes them all. The layout of the payload is
+// described on `WaveformPeaks::to_bytes`. Computing the peaks reads the whole
+// file, so it runs on the blocking pool
+#[tauri::command]
+pub async fn get_waveform_peaks(
+    path: String,
+    samples_per_pixel: u32,
+    channel: Option<u16>,
+) -> Result<tauri::ipc::Response, AllomereError> {
+    tauri::async_runtime::spawn_blocking(move || waveform_peaks(path, samples_per_pixel, channel))
+        .await
+        .map_err(|e| AllomereError::Other(e.into()))?
+}
+
+fn waveform_peaks(
+    path: String,
+    samples_per_pixel: u32,
+    channel: Option<u16>,
+) -> Result<tauri::ipc::Response, AllomereError> {
+    let audio_data_ref = AUDIO_DATA_MAP
+        .lock()
+        .get(&path)
+        .cloned()
+        .ok_or(AllomereError::AudioDataNotFound(path))?;
+
+    let waveform = AudioData::waveform(&audio_data_ref);
+    if let Some(channel) = channel.filter(|channel| *channel >= waveform.channels()) {
+        return Err(AllomereError::ChannelOutOfRange {
+            channel,
+            channels: waveform.channels(),
+        });
     }
+
+    Ok(tauri::ipc::Response::new(
+        waveform.to_bytes(channel, samples_per_pixel),
+    ))
 }
@@ -0,0 +1,77 @@
+use crate::error::AllomereError;
+use crate::handlers::playback::find_clip;
+use crate::states::{
+    self,
+    preview::{PreviewOutput, PreviewState},
+    registry::ClipId,
+};
+
+use tauri::{AppHandle, Manager, State, WebviewWindow};
+
+// None while the preview bus isn't on any output
+#[tauri::command]
+pub fn get_preview_output(preview_state: State<PreviewState>) -> Option<PreviewOutput> {
+    preview_state.output()
+}
+
+// A device of its own, or spare channels of the main output's device
+#[tauri::command]
+pub fn set_preview_output(
+    window: WebviewWindow,
+    playback_state: State<states::playback::PlaybackState>,
+    preview_state: State<PreviewState>,
+    output: Option<PreviewOutput>,
+) -> Result<(), AllomereError> {
+    preview_state.set_output(output, &playback_state)?;
+
+    let _ = states::emit_state_sync("previewOutput", &preview_state.output(), &window);
+    Ok(())
+}
+
+// Plays the jump from `from_beat` to `to_beat` of the clip on the preview bus, with
+// `pre_roll_beats` either side of it, like a pair from `get_preferred_transition_beats`.
+// The main mix and the clip's own loop are left alone. Returns the length in seconds.
+// Snapping to zero crossings reads the file, so it runs on the blocking pool
+#[tauri::command]
+pub async fn preview_transition(
+    app_handle: AppHandle,
+    clip_id: ClipId,
+    from_beat: usize,
+    to_beat: usize,
+    pre_roll_beats: usize,
+) -> Result<f64, AllomereError> {
+    tauri::async_runtime::spawn_blocking(move || {
+        play_transition(&app_handle, clip_id, from_beat, to_beat, pre_roll_beats)
+    })
+    .await
+    .map_err(|e| AllomereError::Other(e.into()))?
+}
+
+fn play_transition(
+    app_handle: &AppHandle,
+    clip_id: ClipId,
+    from_beat: usize,
+    to_beat: usize,
+    pre_roll_beats: usize,
+) -> Result<f64, AllomereError> {
+    let global_app_state = app_handle.state::<states::GlobalAppState>();
+    let playback_state = app_handle.state::<states::playback::PlaybackState>();
+    let preview_state = app_handle.state::<PreviewState>();
+
+    let clip_ref = find_clip(&global_app_state.tracks.lock(), clip_id)?;
+    let (source, duration) = clip_ref.0.lock().preview_source(
+        preview_state.sample_rate(),
+        from_beat,
+        to_beat,
+        pre_roll_beats,
+    )?;
+
+    preview_state.play(source, duration, &playback_state)?;
+    Ok(duration.as_secs_f64())
+}
+
+// False if nothing was being previewed
+#[tauri::command]
+pub fn stop_preview(preview_state: State<PreviewState>) -> bool {
+    preview_state.stop()
+}
@@ -0,0 +1,79 @@
+use crate::error::AllomereError;
+use crate::states::{self, project::ProjectDocument};
+
+use std::path::PathBuf;
+use tauri::{State, WebviewWindow};
+
+#[tauri::command]
+pub fn save_project(
+    window: WebviewWindow,
+    playback_state: State<states::playback::PlaybackState>,
+    global_app_state: State<states::GlobalAppState>,
+    path: PathBuf,
+) -> Result<(), AllomereError> {
+    let document = {
+        let tracks = global_app_state.tracks.lock();
+        ProjectDocument::from_tracks(
+            &tracks,
+            playback_state.config.sample_rate().0,
+            *playback_state.transport.read(),
+        )
+    };
+
+    document.save(&path)?;
+
+    println!("Saved project to {:?}", path);
+    let _ = states::emit_state_sync("project", &path, &window);
+
+    Ok(())
+}
+
+#[tauri::command]
+pub fn open_project(
+    window: WebviewWindow,
+    playback_state: State<states::playback::PlaybackState>,
+    global_app_state: State<states::GlobalAppState>,
+    path: PathBuf,
+) -> Result<(), AllomereError> {
+    let document = ProjectDocument::load(&path)?;
+
+    let was_paused = *playback_state.is_paused.read();
+    playback_state.backend.lock().pause()?;
+    *(playback_state.is_paused.write()) = true;
+
+    let mut tracks = global_app_state.tracks.lock();
+
+    // New tracks start at the playhead, so it's rewound before they're built
+    let previous_frames = std::mem::replace(&mut *playback_state.total_frames.write(), 0);
+
+    // Built before anything is torn down, so a missing file leaves the open session as it was
+    let restored = match document.restore(playback_state.inner()) {
+        Ok(restored) => restored,
+        Err(e) => {
+            *(playback_state.total_frames.write()) = previous_frames;
+            if !was_paused {
+                match playback_state.backend.lock().play() {
+                    Ok(()) => *(playback_state.is_paused.write()) = false,
+                    Err(e) => eprintln!("Failed to resume playback: {}", e),
+                }
+            }
+            return Err(e);
+        }
+    };
+
+    // Dropping the old tracks ends their timelines, which takes them out of the mixer
+    drop(std::mem::replace(&mut *tracks, restored));
+
+    // Undone tracks are dropped with the history
+    let mut history = global_app_state.history.lock();
+    history.clear();
+
+    println!("Opened project {:?}", path);
+
+    let _ = states::emit_state_sync("project", &path, &window);
+    let _ = states::emit_state_sync("tracks", &*tracks, &window);
+    let _ = states::emit_state_sync("playback", playback_state.inner(), &window);
+    let _ = states::emit_state_sync("history", &history.summary(), &window);
+
+    Ok(())
+}
@@ -1,10 +1,11 @@
-use crate::states;
+use crate::error::AllomereError;
+use crate::states::{self, history::Edit};
 use tauri::{Manager, State, WebviewWindow, Window};
 
 use super::audio;
 
 #[tauri::command]
-pub fn refresh(window: WebviewWindow) {
+pub fn refresh(window: WebviewWindow) -> Result<(), AllomereError> {
     let handle = window.app_handle();
 
     let global_app_state: State<states::GlobalAppState> = handle.state();
@@ -16,10 +17,15 @@ pub fn refresh(window: WebviewWindow) {
 
     let _ = states::emit_state_sync("tracks", &*tracks, &window);
     let _ = states::emit_state_sync("playback", playback_state.inner(), &window);
+
+    Ok(())
 }
 
 #[tauri::command]
-pub fn open_file(window: WebviewWindow, payload: states::window::OpenFilePayload) {
+pub fn open_file(
+    window: WebviewWindow,
+    payload: states::window::OpenFilePayload,
+) -> Result<(), AllomereError> {
     let handle = window.app_handle();
 
     let global_app_state: State<states::GlobalAppState> = handle.state();
@@ -33,8 +39,18 @@ pub fn open_file(window: WebviewWindow, payload: states::window::OpenFilePayload
     match payload.path.to_str() {
         Some(path) => match tracks.last_mut() {
             Some(track) => {
-                let clip = states::playback::Clip::new(path);
+                let clip = states::playback::Clip::new(path)?;
                 track.add_clip(clip);
+
+                let track_id = track.id;
+                if let Some(clip) = track.clips.last().cloned() {
+                    super::playback::record_edit(
+                        &global_app_state,
+                        &window,
+                        "Add clip",
+                        Edit::AddClip { track_id, clip },
+                    );
+                }
                 let _ = states::emit_state_sync("tracks", &*tracks, &window);
             }
             _ => {}
@@ -43,4 +59,6 @@ pub fn open_file(window: WebviewWindow, payload: states::window::OpenFilePayload
     }
 
     println!("got window openFile with payload {:?}", payload);
+
+    Ok(())
 }
@@ -5,6 +5,7 @@ mod autogen {
     pub mod constants;
 }
 
+mod error;
 mod handlers;
 mod states;
 
@@ -21,6 +22,8 @@ use std::env;
 // Learn more about Tauri commands at https://tauri.app/v1/guides/features/command
 fn main() -> anyhow::Result<()> {
     let window = Arc::new(Mutex::new(None));
+    let playback_state = states::playback::set_default_state();
+    let preview_state = states::preview::PreviewState::new(&playback_state.config);
 
     // tauri::async_runtime::set(tokio::runtime::Handle::current());
 
@@ -28,7 +31,8 @@ fn main() -> anyhow::Result<()> {
         .plugin(tauri_plugin_shell::init())
         .plugin(tauri_plugin_dialog::init())
         .manage(states::set_default_state(window))
-        .manage(states::playback::set_default_state())
+        .manage(playback_state)
+        .manage(preview_state)
         .setup(|app| {
             let main_window = app.get_webview_window("main").unwrap();
             let handle = app.app_handle();
@@ -41,7 +45,23 @@ fn main() -> anyhow::Result<()> {
                 .id("openFile".to_string())
                 .build(app)?;
             let file_menu = SubmenuBuilder::new(app, "Media").item(&open).build()?;
-            let mut menu_builder = MenuBuilder::new(app).item(&file_menu).separator().quit();
+
+            let open_project = MenuItemBuilder::new("Open Project")
+                .id("openProject".to_string())
+                .build(app)?;
+            let save_project = MenuItemBuilder::new("Save Project")
+                .id("saveProject".to_string())
+                .build(app)?;
+            let project_menu = SubmenuBuilder::new(app, "Project")
+                .item(&open_project)
+                .item(&save_project)
+                .build()?;
+
+            let mut menu_builder = MenuBuilder::new(app)
+                .item(&file_menu)
+                .item(&project_menu)
+                .separator()
+                .quit();
 
             // #[cfg(debug_assertions)]
             // {
@@ -58,7 +78,7 @@ fn main() -> anyhow::Result<()> {
 
             global_app_state.window.lock().replace(main_window.clone());
 
-            handlers::playback::add_track(main_window.clone(), playback_state, global_app_state);
+            handlers::playback::add_track(main_window.clone(), playback_state, global_app_state)?;
 
             // let open_file_window = main_window.clone();
             // let _id = main_window.listen("openFile", move |event| {
@@ -81,21 +101,61 @@ fn main() -> anyhow::Result<()> {
                         "openFile" => {
                             app.dialog()
                                 .file()
-                                .add_filter("Music", &["wav", "mp3", "flac"])
+                                .add_filter("Music", states::playback::SUPPORTED_EXTENSIONS)
                                 .pick_file(move |path_buf| match path_buf {
                                     Some(p) => {
-                                        handlers::window::open_file(
-                                            webview_window,
+                                        if let Err(e) = handlers::window::open_file(
+                                            webview_window.clone(),
                                             states::window::OpenFilePayload {
                                                 path: p.into_path().expect("Should be PathBuf"),
                                             },
-                                        );
+                                        ) {
+                                            states::emit_error(&e, &webview_window);
+                                        }
+                                    }
+                                    _ => {}
+                                });
+                        }
+                        "openProject" => {
+                            app.dialog()
+                                .file()
+                                .add_filter("Project", &[states::project::PROJECT_EXTENSION])
+                                .pick_file(move |path_buf| match path_buf {
+                                    Some(p) => {
+                                        if let Err(e) = handlers::project::open_project(
+                                            webview_window.clone(),
+                                            webview_window.state(),
+                                            webview_window.state(),
+                                            p.into_path().expect("Should be PathBuf"),
+                                        ) {
+                                            states::emit_error(&e, &webview_window);
+                                        }
+                                    }
+                                    _ => {}
+                                });
+                        }
+                        "saveProject" => {
+                            app.dialog()
+                                .file()
+                                .add_filter("Project", &[states::project::PROJECT_EXTENSION])
+                                .save_file(move |path_buf| match path_buf {
+                                    Some(p) => {
+                                        if let Err(e) = handlers::project::save_project(
+                                            webview_window.clone(),
+                                            webview_window.state(),
+                                            webview_window.state(),
+                                            p.into_path().expect("Should be PathBuf"),
+                                        ) {
+                                            states::emit_error(&e, &webview_window);
+                                        }
                                     }
                                     _ => {}
                                 });
                         }
                         "refresh" => {
-                            handlers::window::refresh(webview_window);
+                            if let Err(e) = handlers::window::refresh(webview_window.clone()) {
+                                states::emit_error(&e, &webview_window);
+                            }
                         }
                         _ => {}
                     }
@@ -109,14 +169,58 @@ fn main() -> anyhow::Result<()> {
             handlers::playback::pause,
             handlers::playback::toggle_playback,
             handlers::playback::try_seek,
+            handlers::playback::try_seek_frame,
             handlers::playback::add_track,
+            handlers::playback::set_track_gain,
+            handlers::playback::set_track_pan,
+            handlers::playback::mute_track,
+            handlers::playback::solo_track,
+            handlers::playback::set_master_tempo,
+            handlers::playback::set_time_signature,
+            handlers::playback::set_grid_origin,
+            handlers::playback::seek,
             handlers::playback::get_clip,
             handlers::playback::get_audio_data,
+            handlers::playback::get_waveform_peaks,
             handlers::playback::clear_clip_loop,
+            handlers::playback::remove_clip,
+            handlers::playback::move_clip,
+            handlers::playback::split_clip,
+            handlers::playback::set_clip_trim,
+            handlers::playback::set_clip_fades,
+            handlers::playback::set_clip_gain_envelope,
+            handlers::history::undo,
+            handlers::history::redo,
+            handlers::history::get_history,
+            handlers::output::list_output_devices,
+            handlers::output::get_output_device,
+            handlers::output::set_output_device,
+            handlers::preview::get_preview_output,
+            handlers::preview::set_preview_output,
+            handlers::preview::preview_transition,
+            handlers::preview::stop_preview,
             handlers::playback::set_clip_loop,
             handlers::playback::set_clip_loop_frames,
+            handlers::playback::set_clip_loop_at,
+            handlers::playback::set_clip_loop_count,
+            handlers::playback::set_clip_loop_crossfade,
+            handlers::playback::set_clip_loop_zero_crossing,
+            handlers::playback::get_clip_beat_track,
             handlers::playback::get_clip_preferred_transition_beats,
+            handlers::playback::get_clip_preferred_transition_beats_at,
+            handlers::playback::find_transitions,
+            handlers::playback::start_endless_mix,
+            handlers::playback::stop_endless_mix,
             handlers::audio::get_beats,
+            handlers::audio::get_beat_tracker,
+            handlers::audio::set_beat_tracker,
+            handlers::audio::get_embedding_extractor,
+            handlers::audio::set_embedding_extractor,
+            handlers::export::export_mix,
+            handlers::cache::get_analysis_cache,
+            handlers::cache::clear_analysis_cache,
+            handlers::project::save_project,
+            handlers::project::open_project,
             // get_beats,
         ])
         // .on_page_load(|window, event| {
@@ -3,11 +3,31 @@ use parking_lot::{Mutex, RwLock};
 use rodio::cpal::Stream;
 use rodio::dynamic_mixer::DynamicMixerController;
 use std::sync::Arc;
-use tauri::{AppHandle, WebviewWindow, Emitter};
+use tauri::{AppHandle, Emitter, WebviewWindow};
 
 use crate::autogen::constants::STATE_SYNC_EVENT;
+use crate::error::AllomereError;
 
+pub mod beats;
+pub mod cache;
+pub mod dsp;
+pub mod endless;
+pub mod export;
+pub mod features;
+pub mod history;
+pub mod library;
+pub mod opus;
+pub mod output;
+pub mod pcm;
 pub mod playback;
+pub mod preview;
+pub mod project;
+pub mod python;
+pub mod registry;
+pub mod resample;
+pub mod stretch;
+pub mod transport;
+pub mod waveform;
 pub mod window;
 
 lazy_static! {
@@ -24,6 +44,8 @@ pub struct Payload {
 // #[derive(Clone, serde::Serialize, serde::Deserialize, Debug)]
 pub struct GlobalAppState {
     pub tracks: Mutex<Vec<playback::Track>>,
+    // Lock after `tracks`
+    pub history: Mutex<history::History>,
     // pub stream_handle: Mutex<OutputStreamHandle>,
     pub window: Arc<Mutex<Option<WebviewWindow>>>,
 }
@@ -31,6 +53,7 @@ pub struct GlobalAppState {
 pub fn set_default_state(window: Arc<Mutex<Option<WebviewWindow>>>) -> GlobalAppState {
     GlobalAppState {
         tracks: Mutex::new(Vec::new()),
+        history: Mutex::new(history::History::default()),
         // stream_handle: Mutex::new(stream_handle),
         window,
     }
@@ -53,6 +76,12 @@ where
     Ok(())
 }
 
+// Menu actions have no caller to return an error to, so it goes out on the "error" key
+pub fn emit_error(error: &AllomereError, window: &WebviewWindow) {
+    eprintln!("{}", error);
+    let _ = emit_state_sync("error", error, window);
+}
+
 pub fn emit_state_sync_handle<T>(
     key: &str,
     value: &T,
@@ -0,0 +1,366 @@
+use anyhow::{anyhow, Result};
+
+use parking_lot::RwLock;
+
+use pyo3::prelude::*;
+
+use serde::{Deserialize, Serialize};
+
+use lazy_static::lazy_static;
+
+use std::sync::Arc;
+
+use crate::states::dsp;
+use crate::states::playback::Sound;
+use crate::states::python;
+
+// Turns a decoded file into `(tempo, beat_samples)`, the same shape beats.py returns,
+// beat positions are sample frames at the file's own rate
+pub trait BeatTracker: Send + Sync {
+    fn kind(&self) -> BeatTrackerKind;
+
+    // Stored with cached analysis, bump when the output for the same file changes
+    fn version(&self) -> &'static str;
+
+    fn track(&self, path: &str, sound: &Sound) -> Result<(Vec<f32>, Vec<u32>)>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum BeatTrackerKind {
+    Native,
+    Librosa,
+}
+
+impl BeatTrackerKind {
+    pub fn tracker(self) -> Arc<dyn BeatTracker> {
+        match self {
+            BeatTrackerKind::Native => Arc::new(NativeBeatTracker::default()),
+            BeatTrackerKind::Librosa => Arc::new(LibrosaBeatTracker),
+        }
+    }
+}
+
+lazy_static! {
+    static ref BEAT_TRACKER: RwLock<Arc<dyn BeatTracker>> = RwLock::new(default_tracker());
+}
+
+// ALLOMERE_BEAT_TRACKER=librosa keeps the Python analysis as the default
+fn default_tracker() -> Arc<dyn BeatTracker> {
+    match std::env::var("ALLOMERE_BEAT_TRACKER").as_deref() {
+        Ok("librosa") => BeatTrackerKind::Librosa.tracker(),
+        _ => BeatTrackerKind::Native.tracker(),
+    }
+}
+
+pub fn beat_tracker() -> Arc<dyn BeatTracker> {
+    BEAT_TRACKER.read().clone()
+}
+
+pub fn set_beat_tracker(kind: BeatTrackerKind) {
+    *BEAT_TRACKER.write() = kind.tracker();
+}
+
+// librosa.beat.beat_track through pyo3, needs a Python environment with librosa installed
+pub struct LibrosaBeatTracker;
+
+impl BeatTracker for LibrosaBeatTracker {
+    fn kind(&self) -> BeatTrackerKind {
+        BeatTrackerKind::Librosa
+    }
+
+    fn version(&self) -> &'static str {
+        "librosa-beats-1"
+    }
+
+    fn track(&self, path: &str, sound: &Sound) -> Result<(Vec<f32>, Vec<u32>)> {
+        let sample_rate = sound.sample_rate();
+
+        Python::with_gil(|py| {
+            let get_beats: Py<PyAny> = python::beats_module(py)?
+                .bind(py)
+                .getattr("get_beats")?
+                .into();
+
+            let (tempo, beat_track): (Vec<f32>, Vec<u32>) =
+                get_beats.call1(py, (path, sample_rate))?.extract(py)?;
+
+            Ok((tempo, beat_track))
+        })
+    }
+}
+
+// Onset envelope, autocorrelation tempo estimate and dynamic-programming beat picking,
+// following Ellis (2007) and the defaults of librosa.beat.beat_track
+pub struct NativeBeatTracker {
+    pub n_fft: usize,
+    pub hop_length: usize,
+    pub n_mels: usize,
+    // Tempo the estimate is biased towards, and the width of that bias in octaves
+    pub start_bpm: f32,
+    pub std_bpm: f32,
+    pub min_bpm: f32,
+    pub max_bpm: f32,
+    // How strongly beats are held to the estimated period
+    pub tightness: f32,
+}
+
+impl Default for NativeBeatTracker {
+    fn default() -> Self {
+        NativeBeatTracker {
+            n_fft: 2048,
+            hop_length: 512,
+            n_mels: 128,
+            start_bpm: 120.0,
+            std_bpm: 1.0,
+            min_bpm: 30.0,
+            max_bpm: 300.0,
+            tightness: 100.0,
+        }
+    }
+}
+
+impl BeatTracker for NativeBeatTracker {
+    fn kind(&self) -> BeatTrackerKind {
+        BeatTrackerKind::Native
+    }
+
+    fn version(&self) -> &'static str {
+        "native-beats-1"
+    }
+
+    fn track(&self, _path: &str, sound: &Sound) -> Result<(Vec<f32>, Vec<u32>)> {
+        let sample_rate = sound.sample_rate();
+        let mut samples = 0;
+        let onset_envelope = self.onset_envelope(
+            dsp::mono_samples(sound).inspect(|_| samples += 1),
+            sample_rate,
+        );
+        if samples < self.n_fft {
+            return Err(anyhow!("Audio too short to track beats"));
+        }
+
+        let frame_rate = sample_rate as f32 / self.hop_length as f32;
+
+        let tempo = self.estimate_tempo(&onset_envelope, frame_rate);
+        let beat_frames = self.pick_beats(&onset_envelope, tempo, frame_rate);
+
+        let beat_track = beat_frames
+            .into_iter()
+            .map(|frame| (frame * self.hop_length) as u32)
+            .collect();
+
+        Ok((vec![tempo], beat_track))
+    }
+}
+
+impl NativeBeatTracker {
+    // Mean positive change in log-mel power between frames, one value per hop.
+    // Frames are centred so envelope index t lines up with sample t * hop_length
+    pub fn onset_envelope(&self, samples: impl Iterator<Item = f32>, sample_rate: u32) -> Vec<f32> {
+        let filterbank = dsp::mel_filterbank(self.n_mels, self.n_fft, sample_rate);
+
+        let mut mel_db: Vec<Vec<f32>> = Vec::new();
+        dsp::stft_stream(samples, self.n_fft, self.hop_length, |spectrum| {
+            mel_db.push(
+                filterbank
+                    .iter()
+                    .map(|filter| 10.0 * dsp::filter_power(filter, spectrum).max(1e-10).log10())
+                    .collect(),
+            );
+        });
+        let n_frames = mel_db.len();
+
+        // Same 80 dB floor below the loudest band as librosa's power_to_db
+        let max_db = mel_db
+            .iter()
+            .flatten()
+            .cloned()
+            .fold(f32::NEG_INFINITY, f32::max);
+        let floor_db = max_db - 80.0;
+
+        let mut envelope = vec![0f32; n_frames];
+        for t in 1..n_frames {
+            let flux: f32 = mel_db[t]
+                .iter()
+                .zip(&mel_db[t - 1])
+                .map(|(current, previous)| {
+                    (current.max(floor_db) - previous.max(floor_db)).max(0.0)
+                })
+                .sum();
+            envelope[t] = flux / self.n_mels as f32;
+        }
+
+        envelope
+    }
+
+    // Autocorrelation of the onset envelope weighted by a log-normal prior around start_bpm
+    pub fn estimate_tempo(&self, onset_envelope: &[f32], frame_rate: f32) -> f32 {
+        let min_lag = ((60.0 * frame_rate / self.max_bpm).floor() as usize).max(1);
+        let max_lag =
+            ((60.0 * frame_rate / self.min_bpm).ceil() as usize).min(onset_envelope.len() - 1);
+
+        let mean = onset_envelope.iter().sum::<f32>() / onset_envelope.len() as f32;
+        let centered: Vec<f32> = onset_envelope.iter().map(|value| value - mean).collect();
+
+        let best_lag = (min_lag..=max_lag)
+            .map(|lag| {
+                let autocorrelation: f32 = centered[lag..]
+                    .iter()
+                    .zip(&centered)
+                    .map(|(a, b)| a * b)
+                    .sum::<f32>()
+                    / (centered.len() - lag) as f32;
+                let bpm = 60.0 * frame_rate / lag as f32;
+                let prior = (-0.5 * ((bpm / self.start_bpm).log2() / self.std_bpm).powi(2)).exp();
+                (lag, autocorrelation.max(0.0) * prior)
+            })
+            .fold((0, f32::NEG_INFINITY), |best, candidate| {
+                if candidate.1 > best.1 {
+                    candidate
+                } else {
+                    best
+                }
+            })
+            .0;
+
+        if best_lag == 0 {
+            return self.start_bpm;
+        }
+        60.0 * frame_rate / best_lag as f32
+    }
+
+    // Best-scoring chain of onsets spaced near one period apart, returns envelope frames
+    pub fn pick_beats(&self, onset_envelope: &[f32], bpm: f32, frame_rate: f32) -> Vec<usize> {
+        let n = onset_envelope.len();
+        let period = 60.0 * frame_rate / bpm;
+
+        let mean = onset_envelope.iter().sum::<f32>() / n as f32;
+        let std = (onset_envelope
+            .iter()
+            .map(|value| (value - mean).powi(2))
+            .sum::<f32>()
+            / (n.max(2) - 1) as f32)
+            .sqrt();
+        if std <= 0.0 {
+            return Vec::new();
+        }
+
+        // Onsets smoothed with a Gaussian a period wide so near-misses still count
+        let radius = period.round() as isize;
+        let kernel: Vec<f32> = (-radius..=radius)
+            .map(|k| (-0.5 * (k as f32 * 32.0 / period).powi(2)).exp())
+            .collect();
+        let local_score: Vec<f32> = (0..n as isize)
+            .map(|t| {
+                kernel
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(k, weight)| {
+                        let index = t + k as isize - radius;
+                        (index >= 0 && (index as usize) < n)
+                            .then(|| onset_envelope[index as usize] / std * weight)
+                    })
+                    .sum()
+            })
+            .collect();
+
+        let min_offset = (period / 2.0).round().max(1.0) as usize;
+        let max_offset = (2.0 * period).round() as usize;
+        let max_local_score = local_score.iter().cloned().fold(0.0, f32::max);
+
+        let mut cumulative_score = vec![0f32; n];
+        let mut backlink: Vec<Option<usize>> = vec![None; n];
+        let mut first_beat = true;
+
+        for t in 0..n {
+            let mut best: Option<(usize, f32)> = None;
+            for offset in min_offset..=max_offset.min(t) {
+                let previous = t - offset;
+                let transition = -self.tightness * (offset as f32 / period).ln().powi(2);
+                let score = cumulative_score[previous] + transition;
+                if best.map_or(true, |(_, best_score)| score > best_score) {
+                    best = Some((previous, score));
+                }
+            }
+
+            // Leading silence starts no chain of its own
+            match best {
+                Some((previous, score))
+                    if !(first_beat && local_score[t] < 0.01 * max_local_score) =>
+                {
+                    cumulative_score[t] = local_score[t] + score;
+                    backlink[t] = Some(previous);
+                }
+                _ => cumulative_score[t] = local_score[t],
+            }
+
+            if first_beat && local_score[t] >= 0.01 * max_local_score {
+                first_beat = false;
+            }
+        }
+
+        // The chain ends on the last local maximum that scores at least half the median peak
+        let mut peaks: Vec<(usize, f32)> = (1..n.saturating_sub(1))
+            .filter(|&t| {
+                cumulative_score[t] > cumulative_score[t - 1]
+                    && cumulative_score[t] >= cumulative_score[t + 1]
+            })
+            .map(|t| (t, cumulative_score[t]))
+            .collect();
+        if peaks.is_empty() {
+            return Vec::new();
+        }
+        let mut peak_scores: Vec<f32> = peaks.iter().map(|&(_, score)| score).collect();
+        peak_scores.sort_by(|a, b| a.total_cmp(b));
+        let median = peak_scores[peak_scores.len() / 2];
+        peaks.retain(|&(_, score)| score >= 0.5 * median);
+
+        let mut beats = Vec::new();
+        let mut current = peaks.last().map(|&(t, _)| t);
+        while let Some(t) = current {
+            beats.push(t);
+            current = backlink[t];
+        }
+        beats.reverse();
+
+        self.trim_beats(&local_score, beats)
+    }
+
+    // Drops weak beats at either end, which are usually fades or silence
+    fn trim_beats(&self, local_score: &[f32], beats: Vec<usize>) -> Vec<usize> {
+        let hann = [0.25, 0.75, 1.0, 0.75, 0.25];
+        let smoothed: Vec<f32> = beats
+            .iter()
+            .enumerate()
+            .map(|(i, _)| {
+                hann.iter()
+                    .enumerate()
+                    .filter_map(|(k, weight)| {
+                        let index = (i + k).checked_sub(2)?;
+                        beats.get(index).map(|&beat| local_score[beat] * weight)
+                    })
+                    .sum()
+            })
+            .collect();
+
+        if smoothed.is_empty() {
+            return beats;
+        }
+
+        let threshold = 0.5
+            * (smoothed.iter().map(|value| value * value).sum::<f32>() / smoothed.len() as f32)
+                .sqrt();
+
+        let start = smoothed
+            .iter()
+            .position(|&value| value > threshold)
+            .unwrap_or(0);
+        let end = smoothed
+            .iter()
+            .rposition(|&value| value > threshold)
+            .map_or(beats.len(), |index| index + 1);
+
+        beats[start..end.max(start)].to_vec()
+    }
+}
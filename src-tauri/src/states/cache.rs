@@ -0,0 +1,146 @@
+use anyhow::{anyhow, Result};
+
+use serde::{Deserialize, Serialize};
+use tauri::Manager;
+
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+use crate::states;
+use crate::states::beats;
+use crate::states::features;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AnalysisCacheEntry {
+    pub version: String,
+    pub tempo: f32,
+    pub beat_track: Vec<u32>,
+    pub beat_features: Vec<Vec<f32>>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AnalysisCacheInfo {
+    pub path: PathBuf,
+    pub version: String,
+    pub entries: Vec<AnalysisCacheFile>,
+    pub total_bytes: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AnalysisCacheFile {
+    pub key: String,
+    pub bytes: u64,
+}
+
+// Beat windows used to start on the whole second before the beat, they're
+// centred on it since they're sliced from the decoded audio
+const BEAT_WINDOW_VERSION: &str = "windows-2";
+
+// The beat tracker and embedding extractor each version their own output,
+// entries written by another combination are never read back
+pub fn analysis_version() -> String {
+    format!(
+        "{}.{}.{}",
+        beats::beat_tracker().version(),
+        features::embedding_extractor().version(),
+        BEAT_WINDOW_VERSION
+    )
+}
+
+fn app_cache_dir() -> Result<PathBuf> {
+    Ok(match states::APP_HANDLE.lock().as_ref() {
+        Some(app_handle) => app_handle.path().app_cache_dir()?,
+        None => std::env::temp_dir().join("allomere"),
+    })
+}
+
+pub fn cache_dir() -> Result<PathBuf> {
+    Ok(app_cache_dir()?.join("analysis"))
+}
+
+// Decoded audio of the files in use, what earlier runs left is removed on the first open
+pub fn pcm_dir() -> Result<PathBuf> {
+    Ok(app_cache_dir()?.join("pcm"))
+}
+
+// Content hash of the file bytes, so renamed or moved files still hit the cache
+// The file is hashed as it's read rather than loaded whole
+pub fn audio_hash(path: &Path) -> std::io::Result<String> {
+    let mut hasher = blake3::Hasher::new();
+    std::io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+pub fn cache_key(audio_hash: &str) -> String {
+    format!("{}-{}", audio_hash, analysis_version())
+}
+
+fn entry_path(key: &str) -> Result<PathBuf> {
+    Ok(cache_dir()?.join(format!("{}.bin", key)))
+}
+
+pub fn load(key: &str) -> Option<AnalysisCacheEntry> {
+    let path = entry_path(key).ok()?;
+    let bytes = fs::read(&path).ok()?;
+
+    match bincode::deserialize::<AnalysisCacheEntry>(&bytes) {
+        Ok(entry) if entry.version == analysis_version() => Some(entry),
+        Ok(_) => None,
+        Err(e) => {
+            eprintln!("Failed to read analysis cache entry {:?}: {}", path, e);
+            None
+        }
+    }
+}
+
+pub fn store(key: &str, entry: &AnalysisCacheEntry) -> Result<()> {
+    let path = entry_path(key)?;
+    fs::create_dir_all(path.parent().ok_or(anyhow!("Cache path has no parent"))?)?;
+
+    // Written aside then renamed so a crash never leaves a truncated entry
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, bincode::serialize(entry)?)?;
+    fs::rename(&tmp_path, &path)?;
+
+    Ok(())
+}
+
+pub fn info() -> Result<AnalysisCacheInfo> {
+    let path = cache_dir()?;
+    let mut entries = Vec::new();
+
+    if path.exists() {
+        for dir_entry in fs::read_dir(&path)? {
+            let dir_entry = dir_entry?;
+            let file_path = dir_entry.path();
+            if file_path.extension().is_some_and(|ext| ext == "bin") {
+                entries.push(AnalysisCacheFile {
+                    key: file_path
+                        .file_stem()
+                        .unwrap_or_default()
+                        .to_string_lossy()
+                        .to_string(),
+                    bytes: dir_entry.metadata()?.len(),
+                });
+            }
+        }
+    }
+
+    Ok(AnalysisCacheInfo {
+        path,
+        version: analysis_version(),
+        total_bytes: entries.iter().map(|entry| entry.bytes).sum(),
+        entries,
+    })
+}
+
+// Returns the number of entries removed
+pub fn clear() -> Result<usize> {
+    let info = info()?;
+    for entry in &info.entries {
+        fs::remove_file(info.path.join(format!("{}.bin", entry.key)))?;
+    }
+    Ok(info.entries.len())
+}
@@ -0,0 +1,128 @@
+use realfft::num_complex::Complex;
+use realfft::RealFftPlanner;
+
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+
+use crate::states::playback::Sound;
+
+// Whole file averaged down to one channel, at the file's own rate. Read as it's
+// consumed so the file is never in memory at once
+pub fn mono_samples(sound: &Sound) -> impl Iterator<Item = f32> {
+    let channels = sound.channels() as usize;
+    let mut reader = sound.reader();
+    std::iter::from_fn(move || {
+        let mut sum = 0.0;
+        for _ in 0..channels {
+            sum += reader.next()?;
+        }
+        Some(sum / channels as f32)
+    })
+}
+
+pub fn downmix(samples: &[f32], channels: usize) -> Vec<f32> {
+    samples
+        .chunks(channels)
+        .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+        .collect()
+}
+
+pub fn hz_to_mel(hz: f32) -> f32 {
+    2595.0 * (1.0 + hz / 700.0).log10()
+}
+
+pub fn mel_to_hz(mel: f32) -> f32 {
+    700.0 * (10f32.powf(mel / 2595.0) - 1.0)
+}
+
+// Triangular filters over the rfft bins, evenly spaced on the mel scale up to Nyquist
+pub fn mel_filterbank(n_mels: usize, n_fft: usize, sample_rate: u32) -> Vec<Vec<(usize, f32)>> {
+    let n_bins = n_fft / 2 + 1;
+    let max_mel = hz_to_mel(sample_rate as f32 / 2.0);
+    let edges: Vec<f32> = (0..n_mels + 2)
+        .map(|i| mel_to_hz(max_mel * i as f32 / (n_mels + 1) as f32))
+        .collect();
+    let bin_hz = sample_rate as f32 / n_fft as f32;
+
+    (0..n_mels)
+        .map(|m| {
+            let (lower, center, upper) = (edges[m], edges[m + 1], edges[m + 2]);
+            (0..n_bins)
+                .filter_map(|bin| {
+                    let hz = bin as f32 * bin_hz;
+                    let weight = if hz <= center {
+                        (hz - lower) / (center - lower)
+                    } else {
+                        (upper - hz) / (upper - center)
+                    };
+                    (weight > 0.0).then_some((bin, weight))
+                })
+                .collect()
+        })
+        .collect()
+}
+
+pub fn filter_power(filter: &[(usize, f32)], spectrum: &[Complex<f32>]) -> f32 {
+    filter
+        .iter()
+        .map(|&(bin, weight)| spectrum[bin].norm_sqr() * weight)
+        .sum()
+}
+
+// Hann-windowed short-time spectra, one call per hop. Frames are centred with zero
+// padding so frame t covers the audio around sample t * hop_length
+pub fn stft(samples: &[f32], n_fft: usize, hop_length: usize, f: impl FnMut(&[Complex<f32>])) {
+    stft_stream(samples.iter().copied(), n_fft, hop_length, f);
+}
+
+// `stft` over samples as they're read, keeping only one frame of them
+pub fn stft_stream(
+    samples: impl Iterator<Item = f32>,
+    n_fft: usize,
+    hop_length: usize,
+    mut f: impl FnMut(&[Complex<f32>]),
+) {
+    let pad = n_fft / 2;
+    let mut samples = samples.fuse();
+    // Samples under the current frame, zero before the first and after the last
+    let mut buffer: VecDeque<f32> = VecDeque::with_capacity(n_fft);
+    buffer.extend(std::iter::repeat(0.0).take(pad));
+    let mut read = 0;
+    // Returns how many samples it read, 0 once they've run out
+    let mut advance = |buffer: &mut VecDeque<f32>| {
+        let sample = samples.next();
+        buffer.push_back(sample.unwrap_or(0.0));
+        sample.is_some() as usize
+    };
+    while buffer.len() < n_fft {
+        read += advance(&mut buffer);
+    }
+
+    let window: Vec<f32> = (0..n_fft)
+        .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / n_fft as f32).cos())
+        .collect();
+
+    let mut planner = RealFftPlanner::<f32>::new();
+    let fft = planner.plan_fft_forward(n_fft);
+    let mut frame = fft.make_input_vec();
+    let mut spectrum = fft.make_output_vec();
+
+    // Frames run up to the one centred on the last sample, read runs at least
+    // that far ahead of the frame
+    let mut t = 0;
+    while t * hop_length <= read {
+        for ((value, sample), weight) in frame.iter_mut().zip(&buffer).zip(&window) {
+            *value = sample * weight;
+        }
+        // Buffer lengths come from the plan so this cannot fail
+        fft.process(&mut frame, &mut spectrum).unwrap();
+
+        f(&spectrum);
+
+        t += 1;
+        for _ in 0..hop_length {
+            buffer.pop_front();
+            read += advance(&mut buffer);
+        }
+    }
+}
@@ -0,0 +1,288 @@
+use parking_lot::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use lazy_static::lazy_static;
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering::SeqCst};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::states;
+use crate::states::playback::{AllomereMutex, Clip};
+use crate::states::registry::ClipId;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct EndlessSettings {
+    // Beats that play after landing before the next jump can leave
+    pub min_segment_beats: usize,
+    // Jumps shorter than this sound like a stutter rather than a new passage
+    pub min_jump_beats: usize,
+    // Closest matches the next jump is picked from at random
+    pub top_k: usize,
+    // Landing beats near one of the last `history` jumps aren't used again
+    pub history: usize,
+    // Same seed, same sequence of jumps. None seeds from the clock
+    pub seed: Option<u64>,
+}
+
+impl Default for EndlessSettings {
+    fn default() -> Self {
+        EndlessSettings {
+            min_segment_beats: 16,
+            min_jump_beats: 8,
+            top_k: 4,
+            history: 8,
+            seed: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Jump {
+    pub from_beat: usize,
+    pub to_beat: usize,
+}
+
+// Synced under `endless."<clip id>"` whenever a jump is planned or the mix stops
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EndlessStatus {
+    pub active: bool,
+    pub next_jump: Option<Jump>,
+    pub recent_jumps: Vec<Jump>,
+}
+
+struct RunningMix {
+    stopped: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+lazy_static! {
+    // The running mixes by clip id. Their threads never lock this, so they can be
+    // joined while it's held
+    static ref ENDLESS_MIXES: Mutex<HashMap<ClipId, RunningMix>> = Mutex::new(HashMap::new());
+}
+
+// SplitMix64, small and stable across releases so seeds stay reproducible
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % (n as u64)) as usize
+    }
+}
+
+// Watches the clip's playback position and keeps one jump armed ahead of it.
+// Jumps use the clip's loop with a count of 2, so the seam crossfade and zero
+// crossing settings apply and a played jump shows up as no loops remaining
+pub struct EndlessMix {
+    clip: Arc<AllomereMutex<Clip>>,
+    clip_id: ClipId,
+    settings: EndlessSettings,
+    rng: Rng,
+    recent_jumps: VecDeque<Jump>,
+    next_jump: Option<Jump>,
+    // Beat the last plan started from, so a plan that found nothing isn't retried every poll
+    planned_from: Option<usize>,
+}
+
+const POLL_PERIOD: Duration = Duration::from_millis(20);
+
+// Replaces any mix already running on the clip
+pub fn start(clip: Arc<AllomereMutex<Clip>>, settings: EndlessSettings) {
+    let clip_id = clip.0.lock().id;
+    let mut mixes = ENDLESS_MIXES.lock();
+    // The old mix has put the clip's own loop back by the time it's copied here
+    end(&mut mixes, clip_id);
+    let controller = clip.0.lock().controller();
+
+    let seed = settings.seed.unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_nanos() as u64)
+    });
+    let stopped = Arc::new(AtomicBool::new(false));
+    let thread_stopped = stopped.clone();
+
+    let mut mix = EndlessMix {
+        clip,
+        clip_id,
+        settings,
+        rng: Rng(seed),
+        recent_jumps: VecDeque::new(),
+        next_jump: None,
+        planned_from: None,
+    };
+
+    let thread = thread::spawn(move || {
+        // Also ends once the clip is dropped, e.g. when another project is opened
+        while !thread_stopped.load(SeqCst) && Arc::strong_count(&mix.clip) > 1 {
+            mix.poll();
+            thread::sleep(POLL_PERIOD);
+        }
+
+        // Whatever loop the clip had before comes back
+        let mut clip = mix.clip.0.lock();
+        match controller {
+            Some(controller) => clip.set_controller(controller),
+            None => clip.clear_loop(),
+        }
+        drop(clip);
+        mix.next_jump = None;
+        mix.emit(false);
+    });
+    mixes.insert(clip_id, RunningMix { stopped, thread });
+}
+
+// Returns whether a mix was running on the clip, once it has put the clip's loop back
+pub fn stop(clip_id: ClipId) -> bool {
+    end(&mut ENDLESS_MIXES.lock(), clip_id)
+}
+
+fn end(mixes: &mut HashMap<ClipId, RunningMix>, clip_id: ClipId) -> bool {
+    match mixes.remove(&clip_id) {
+        Some(mix) => {
+            mix.stopped.store(true, SeqCst);
+            if mix.thread.join().is_err() {
+                eprintln!("Endless mix on clip {} panicked", clip_id);
+            }
+            true
+        }
+        None => false,
+    }
+}
+
+impl EndlessMix {
+    fn poll(&mut self) {
+        let (frame, beat_track, jump_played) = {
+            let clip = self.clip.0.lock();
+            let Some(beat_track) = clip
+                .audio_data()
+                .and_then(|audio_data| audio_data.lock().beat_track().map(<[u32]>::to_vec))
+            else {
+                // Still being analysed
+                return;
+            };
+            let frame = clip
+                .audio
+                .as_ref()
+                .map_or(0, |audio| audio.source.current_frame());
+            let jump_played = clip
+                .controller()
+                .is_some_and(|controller| controller.loops_remaining() == Some(0));
+            (frame, beat_track, jump_played)
+        };
+        if beat_track.is_empty() {
+            return;
+        }
+
+        let current_beat = beat_track
+            .partition_point(|&beat| (beat as u64) <= frame)
+            .saturating_sub(1);
+
+        match self.next_jump {
+            Some(jump) if jump_played => {
+                self.recent_jumps.push_back(jump);
+                while self.recent_jumps.len() > self.settings.history {
+                    self.recent_jumps.pop_front();
+                }
+                self.plan(jump.to_beat, &beat_track);
+            }
+            // Playback was moved past the jump, plan again from wherever it is now
+            Some(jump) if current_beat > jump.from_beat => self.plan(current_beat, &beat_track),
+            Some(_) => {}
+            None if self.planned_from != Some(current_beat) => self.plan(current_beat, &beat_track),
+            None => {}
+        }
+    }
+
+    // Arms the next jump at least min_segment_beats after `landed_beat`
+    fn plan(&mut self, landed_beat: usize, beat_track: &[u32]) {
+        self.planned_from = Some(landed_beat);
+        let beats = beat_track.len();
+        let first_source = (landed_beat + self.settings.min_segment_beats).min(beats - 1);
+        let last_source = (first_source + self.settings.min_segment_beats.max(1)).min(beats - 1);
+
+        let mut candidates = Vec::new();
+        for from_beat in first_source..=last_source {
+            let transitions = self.clip.0.lock().get_preferred_transition_beats(
+                from_beat,
+                self.settings.top_k + self.settings.history,
+            );
+            let Ok(transitions) = transitions else {
+                continue;
+            };
+            for (to_beat, distance) in transitions {
+                // Landing somewhere that leaves room for a whole segment before the end
+                if to_beat + self.settings.min_segment_beats < beats
+                    && to_beat.abs_diff(from_beat) >= self.settings.min_jump_beats
+                {
+                    candidates.push((Jump { from_beat, to_beat }, distance));
+                }
+            }
+        }
+        candidates.sort_by(|a, b| a.1.total_cmp(&b.1));
+
+        let fresh: Vec<Jump> = candidates
+            .iter()
+            .map(|&(jump, _)| jump)
+            .filter(|jump| {
+                !self.recent_jumps.iter().any(|recent| {
+                    recent.to_beat.abs_diff(jump.to_beat) < self.settings.min_jump_beats
+                })
+            })
+            .take(self.settings.top_k.max(1))
+            .collect();
+        // Better to repeat a jump than to let the song run out
+        let choices: Vec<Jump> = if fresh.is_empty() {
+            candidates
+                .iter()
+                .map(|&(jump, _)| jump)
+                .take(self.settings.top_k.max(1))
+                .collect()
+        } else {
+            fresh
+        };
+
+        self.next_jump = if choices.is_empty() {
+            None
+        } else {
+            Some(choices[self.rng.below(choices.len())])
+        };
+
+        if let Some(jump) = self.next_jump {
+            self.clip
+                .0
+                .lock()
+                .set_jump(beat_track[jump.from_beat], beat_track[jump.to_beat]);
+        }
+        self.emit(true);
+    }
+
+    fn emit(&self, active: bool) {
+        if let Some(app_handle) = states::APP_HANDLE.lock().as_ref() {
+            let _ = states::emit_state_sync_handle(
+                format!("endless.\"{}\"", self.clip_id).as_str(),
+                &EndlessStatus {
+                    active,
+                    next_jump: self.next_jump,
+                    recent_jumps: self.recent_jumps.iter().cloned().collect(),
+                },
+                app_handle,
+            );
+        }
+    }
+}
@@ -0,0 +1,273 @@
+use anyhow::{anyhow, Result};
+
+use flacenc::bitsink::ByteSink;
+use flacenc::component::{BitRepr, Stream, StreamInfo};
+use flacenc::error::Verified;
+use flacenc::source::{Fill, FrameBuf};
+use rodio::dynamic_mixer::{self, DynamicMixer};
+use serde::{Deserialize, Serialize};
+
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::states::playback::Track;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ExportFormat {
+    Wav,
+    Flac,
+}
+
+impl ExportFormat {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path
+            .extension()?
+            .to_string_lossy()
+            .to_ascii_lowercase()
+            .as_str()
+        {
+            "wav" => Some(ExportFormat::Wav),
+            "flac" => Some(ExportFormat::Flac),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportProgress {
+    pub path: PathBuf,
+    pub state: String,
+    pub rendered_frames: u64,
+    pub total_frames: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl ExportProgress {
+    pub fn new(path: PathBuf, total_frames: u64) -> Self {
+        ExportProgress {
+            path,
+            state: "rendering".to_string(),
+            rendered_frames: 0,
+            total_frames,
+            error: None,
+        }
+    }
+}
+
+enum ExportWriter {
+    Wav(hound::WavWriter<BufWriter<File>>),
+    Flac(FlacWriter),
+}
+
+// Encodes a FLAC frame each time a block of the mix has been rendered and writes
+// it out, so only one block is ever held. STREAMINFO goes in first as a
+// placeholder and is rewritten once the frames are all known
+struct FlacWriter {
+    file: BufWriter<File>,
+    config: Verified<flacenc::config::Encoder>,
+    stream_info: StreamInfo,
+    framebuf: FrameBuf,
+    channels: usize,
+    // Interleaved samples of the block being rendered
+    block: Vec<i32>,
+    block_samples: usize,
+    frame_number: usize,
+}
+
+impl FlacWriter {
+    fn create(path: &Path, channels: u16, sample_rate: u32, bits_per_sample: u16) -> Result<Self> {
+        let config = flacenc::config::Encoder::default()
+            .into_verified()
+            .map_err(|(_, e)| anyhow!("Invalid FLAC encoder config: {:?}", e))?;
+        let channels = channels as usize;
+        let block_size = config.block_size;
+        let stream_info = StreamInfo::new(sample_rate as usize, channels, bits_per_sample as usize)
+            .map_err(|e| anyhow!("Invalid FLAC stream: {:?}", e))?;
+        let framebuf = FrameBuf::with_size(channels, block_size)
+            .map_err(|e| anyhow!("Invalid FLAC block size: {:?}", e))?;
+
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(&flac_header(&stream_info)?)?;
+
+        Ok(FlacWriter {
+            file,
+            config,
+            stream_info,
+            framebuf,
+            channels,
+            block: Vec::with_capacity(block_size * channels),
+            block_samples: block_size * channels,
+            frame_number: 0,
+        })
+    }
+
+    fn write_sample(&mut self, sample: i32) -> Result<()> {
+        self.block.push(sample);
+        if self.block.len() == self.block_samples {
+            self.encode_block()?;
+        }
+        Ok(())
+    }
+
+    fn encode_block(&mut self) -> Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        // Only the last block can be short
+        if self.block.len() < self.block_samples {
+            self.framebuf = FrameBuf::with_size(self.channels, self.block.len() / self.channels)
+                .map_err(|e| anyhow!("Invalid FLAC block size: {:?}", e))?;
+        }
+        self.framebuf
+            .fill_interleaved(&self.block)
+            .map_err(|e| anyhow!("Failed to fill FLAC block: {:?}", e))?;
+
+        let frame = flacenc::encode_fixed_size_frame(
+            &self.config,
+            &self.framebuf,
+            self.frame_number,
+            &self.stream_info,
+        )
+        .map_err(|e| anyhow!("Failed to encode FLAC: {:?}", e))?;
+        self.stream_info.update_frame_info(&frame);
+
+        let mut sink = ByteSink::new();
+        frame
+            .write(&mut sink)
+            .map_err(|e| anyhow!("Failed to write FLAC: {:?}", e))?;
+        self.file.write_all(sink.as_slice())?;
+
+        self.frame_number += 1;
+        self.block.clear();
+        Ok(())
+    }
+
+    fn finalize(mut self) -> Result<()> {
+        self.encode_block()?;
+        let header = flac_header(&self.stream_info)?;
+        let mut file = self.file.into_inner().map_err(|e| e.into_error())?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&header)?;
+        Ok(())
+    }
+}
+
+// "fLaC" and the STREAMINFO block, the same length whatever the totals in it
+fn flac_header(stream_info: &StreamInfo) -> Result<Vec<u8>> {
+    let mut sink = ByteSink::new();
+    Stream::with_stream_info(stream_info.clone())
+        .write(&mut sink)
+        .map_err(|e| anyhow!("Failed to write FLAC: {:?}", e))?;
+    Ok(sink.as_slice().to_vec())
+}
+
+pub struct MixExport {
+    pub path: PathBuf,
+    pub format: ExportFormat,
+    pub channels: u16,
+    pub sample_rate: u32,
+    pub bits_per_sample: u16,
+    pub total_frames: u64,
+    mixer: DynamicMixer<f32>,
+}
+
+impl MixExport {
+    // Builds a mixer equivalent to the playback one, each track's clips
+    // scheduled at their start_at
+    pub fn new(
+        tracks: &[Track],
+        path: PathBuf,
+        format: ExportFormat,
+        channels: u16,
+        sample_rate: u32,
+        bits_per_sample: u16,
+        end_frame: Option<u64>,
+    ) -> Result<Self> {
+        if bits_per_sample != 16 && bits_per_sample != 24 {
+            return Err(anyhow!("Unsupported bits per sample: {}", bits_per_sample));
+        }
+
+        let (mixer_tx, mixer_rx) = dynamic_mixer::mixer::<f32>(channels, sample_rate);
+
+        let mut total_frames = 0;
+        for track in tracks {
+            if let Some(source) = track.render_source(sample_rate) {
+                mixer_tx.add(source);
+            }
+            if let Some(track_end_frame) = track.end_frame(sample_rate) {
+                total_frames = total_frames.max(track_end_frame.0);
+            }
+        }
+
+        Ok(MixExport {
+            path,
+            format,
+            channels,
+            sample_rate,
+            bits_per_sample,
+            total_frames: end_frame.unwrap_or(total_frames),
+            mixer: mixer_rx,
+        })
+    }
+
+    // Pulls samples as fast as the graph produces them, calling `on_progress`
+    // with the number of frames rendered so far
+    pub fn render<F>(mut self, mut on_progress: F) -> Result<u64>
+    where
+        F: FnMut(u64),
+    {
+        let mut writer = match self.format {
+            ExportFormat::Wav => ExportWriter::Wav(hound::WavWriter::create(
+                &self.path,
+                hound::WavSpec {
+                    channels: self.channels,
+                    sample_rate: self.sample_rate,
+                    bits_per_sample: self.bits_per_sample,
+                    sample_format: hound::SampleFormat::Int,
+                },
+            )?),
+            ExportFormat::Flac => ExportWriter::Flac(FlacWriter::create(
+                &self.path,
+                self.channels,
+                self.sample_rate,
+                self.bits_per_sample,
+            )?),
+        };
+
+        let scale = ((1i64 << (self.bits_per_sample - 1)) - 1) as f32;
+        let channels = self.channels as u64;
+        // Report roughly once per second of audio
+        let progress_interval = self.sample_rate as u64;
+
+        let mut rendered_frames = 0;
+        while rendered_frames < self.total_frames {
+            for _ in 0..channels {
+                // The mixer ends once every source is exhausted, the rest is silence
+                let sample = self.mixer.next().unwrap_or(0f32);
+                let sample = (sample.clamp(-1.0, 1.0) * scale) as i32;
+                match writer {
+                    ExportWriter::Wav(ref mut wav_writer) => wav_writer.write_sample(sample)?,
+                    ExportWriter::Flac(ref mut flac_writer) => flac_writer.write_sample(sample)?,
+                }
+            }
+            rendered_frames += 1;
+
+            if rendered_frames % progress_interval == 0 {
+                on_progress(rendered_frames);
+            }
+        }
+
+        match writer {
+            ExportWriter::Wav(wav_writer) => wav_writer.finalize()?,
+            ExportWriter::Flac(flac_writer) => flac_writer.finalize()?,
+        }
+
+        on_progress(rendered_frames);
+
+        Ok(rendered_frames)
+    }
+}
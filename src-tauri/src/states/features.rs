@@ -0,0 +1,280 @@
+use anyhow::Result;
+
+use numpy::PyArray2;
+
+use parking_lot::RwLock;
+
+use pyo3::prelude::*;
+
+use serde::{Deserialize, Serialize};
+
+use usearch::{IndexOptions, MetricKind, ScalarKind};
+
+use lazy_static::lazy_static;
+
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+use crate::states::dsp;
+use crate::states::python;
+
+// Turns beat windows into fixed-size vectors for the transition index,
+// windows are interleaved samples at the file's own rate and channel count
+pub trait EmbeddingExtractor: Send + Sync {
+    fn kind(&self) -> EmbeddingExtractorKind;
+
+    // Stored with cached analysis, bump when the output for the same window changes
+    fn version(&self) -> &'static str;
+
+    fn dimensions(&self) -> usize;
+
+    fn metric(&self) -> MetricKind;
+
+    fn extract(
+        &self,
+        windows: &[Vec<f32>],
+        channels: u16,
+        sample_rate: u32,
+    ) -> Result<Vec<Vec<f32>>>;
+
+    fn index_options(&self) -> IndexOptions {
+        let mut index_options = IndexOptions::default();
+        index_options.dimensions = self.dimensions();
+        index_options.metric = self.metric();
+        index_options.quantization = ScalarKind::F64;
+        index_options
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum EmbeddingExtractorKind {
+    Native,
+    Clap,
+}
+
+impl EmbeddingExtractorKind {
+    pub fn extractor(self) -> Arc<dyn EmbeddingExtractor> {
+        match self {
+            EmbeddingExtractorKind::Native => Arc::new(NativeEmbeddingExtractor::default()),
+            EmbeddingExtractorKind::Clap => Arc::new(ClapEmbeddingExtractor),
+        }
+    }
+}
+
+lazy_static! {
+    static ref EMBEDDING_EXTRACTOR: RwLock<Arc<dyn EmbeddingExtractor>> =
+        RwLock::new(default_extractor());
+}
+
+// ALLOMERE_EMBEDDING_EXTRACTOR=clap keeps the CLAP model as the default
+fn default_extractor() -> Arc<dyn EmbeddingExtractor> {
+    match std::env::var("ALLOMERE_EMBEDDING_EXTRACTOR").as_deref() {
+        Ok("clap") => EmbeddingExtractorKind::Clap.extractor(),
+        _ => EmbeddingExtractorKind::Native.extractor(),
+    }
+}
+
+pub fn embedding_extractor() -> Arc<dyn EmbeddingExtractor> {
+    EMBEDDING_EXTRACTOR.read().clone()
+}
+
+pub fn set_embedding_extractor(kind: EmbeddingExtractorKind) {
+    *EMBEDDING_EXTRACTOR.write() = kind.extractor();
+}
+
+// laion/larger_clap_music through features.py, downloads the model on first use
+pub struct ClapEmbeddingExtractor;
+
+impl EmbeddingExtractor for ClapEmbeddingExtractor {
+    fn kind(&self) -> EmbeddingExtractorKind {
+        EmbeddingExtractorKind::Clap
+    }
+
+    fn version(&self) -> &'static str {
+        "clap-1"
+    }
+
+    fn dimensions(&self) -> usize {
+        512
+    }
+
+    fn metric(&self) -> MetricKind {
+        MetricKind::Cos
+    }
+
+    fn extract(
+        &self,
+        windows: &[Vec<f32>],
+        _channels: u16,
+        sample_rate: u32,
+    ) -> Result<Vec<Vec<f32>>> {
+        Python::with_gil(|py| {
+            let get_audio_features: Py<PyAny> = python::features_module(py)?
+                .bind(py)
+                .getattr("get_audio_features")?
+                .into();
+
+            let mut features = Vec::with_capacity(windows.len());
+            for chunked_windows in windows.chunks(10) {
+                let audio_array = PyArray2::from_vec2(py, chunked_windows)?;
+                features.extend(
+                    get_audio_features
+                        .call1(py, (audio_array, sample_rate))?
+                        .extract::<Vec<Vec<f32>>>(py)?,
+                );
+            }
+
+            Ok(features)
+        })
+    }
+}
+
+// MFCC mean and spread plus an averaged chroma profile per window, computed locally
+// and deterministic so transition search works offline
+pub struct NativeEmbeddingExtractor {
+    pub n_fft: usize,
+    pub hop_length: usize,
+    pub n_mels: usize,
+    // Cepstral coefficients kept after dropping the loudness term c0
+    pub n_mfcc: usize,
+}
+
+impl Default for NativeEmbeddingExtractor {
+    fn default() -> Self {
+        NativeEmbeddingExtractor {
+            n_fft: 2048,
+            hop_length: 512,
+            n_mels: 40,
+            n_mfcc: 12,
+        }
+    }
+}
+
+impl EmbeddingExtractor for NativeEmbeddingExtractor {
+    fn kind(&self) -> EmbeddingExtractorKind {
+        EmbeddingExtractorKind::Native
+    }
+
+    fn version(&self) -> &'static str {
+        "native-mfcc-chroma-1"
+    }
+
+    fn dimensions(&self) -> usize {
+        2 * self.n_mfcc + 12
+    }
+
+    fn metric(&self) -> MetricKind {
+        MetricKind::Cos
+    }
+
+    fn extract(
+        &self,
+        windows: &[Vec<f32>],
+        channels: u16,
+        sample_rate: u32,
+    ) -> Result<Vec<Vec<f32>>> {
+        let filterbank = dsp::mel_filterbank(self.n_mels, self.n_fft, sample_rate);
+        let pitch_classes = self.pitch_classes(sample_rate);
+
+        Ok(windows
+            .iter()
+            .map(|window| {
+                let samples = dsp::downmix(window, channels as usize);
+                self.embed(&samples, &filterbank, &pitch_classes)
+            })
+            .collect())
+    }
+}
+
+// Scales a block to unit length so each block weighs the same under cosine distance
+fn normalize(block: &mut [f32]) {
+    let norm = block.iter().map(|value| value * value).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        block.iter_mut().for_each(|value| *value /= norm);
+    }
+}
+
+impl NativeEmbeddingExtractor {
+    // Pitch class of every rfft bin between A0 and 5 kHz, None outside that range
+    fn pitch_classes(&self, sample_rate: u32) -> Vec<Option<usize>> {
+        let bin_hz = sample_rate as f32 / self.n_fft as f32;
+        (0..self.n_fft / 2 + 1)
+            .map(|bin| {
+                let hz = bin as f32 * bin_hz;
+                (27.5..=5000.0).contains(&hz).then(|| {
+                    let midi = 69.0 + 12.0 * (hz / 440.0).log2();
+                    (midi.round() as i32).rem_euclid(12) as usize
+                })
+            })
+            .collect()
+    }
+
+    fn embed(
+        &self,
+        samples: &[f32],
+        filterbank: &[Vec<(usize, f32)>],
+        pitch_classes: &[Option<usize>],
+    ) -> Vec<f32> {
+        let mut mfcc_frames: Vec<Vec<f32>> = Vec::new();
+        let mut chroma = [0f32; 12];
+
+        dsp::stft(samples, self.n_fft, self.hop_length, |spectrum| {
+            let log_mel: Vec<f32> = filterbank
+                .iter()
+                .map(|filter| dsp::filter_power(filter, spectrum).max(1e-10).ln())
+                .collect();
+
+            mfcc_frames.push(
+                (1..=self.n_mfcc)
+                    .map(|k| {
+                        log_mel
+                            .iter()
+                            .enumerate()
+                            .map(|(m, value)| {
+                                value
+                                    * (PI * k as f32 * (m as f32 + 0.5) / self.n_mels as f32).cos()
+                            })
+                            .sum::<f32>()
+                    })
+                    .collect(),
+            );
+
+            let mut frame_chroma = [0f32; 12];
+            for (bin, pitch_class) in pitch_classes.iter().enumerate() {
+                if let Some(pitch_class) = pitch_class {
+                    frame_chroma[*pitch_class] += spectrum[bin].norm_sqr();
+                }
+            }
+            let peak = frame_chroma.iter().cloned().fold(0.0, f32::max);
+            if peak > 0.0 {
+                chroma
+                    .iter_mut()
+                    .zip(frame_chroma)
+                    .for_each(|(total, value)| *total += value / peak);
+            }
+        });
+
+        let n_frames = mfcc_frames.len().max(1) as f32;
+        let mut mean = vec![0f32; self.n_mfcc];
+        for frame in &mfcc_frames {
+            mean.iter_mut()
+                .zip(frame)
+                .for_each(|(total, value)| *total += value / n_frames);
+        }
+        let mut spread = vec![0f32; self.n_mfcc];
+        for frame in &mfcc_frames {
+            spread
+                .iter_mut()
+                .zip(frame.iter().zip(&mean))
+                .for_each(|(total, (value, mean))| *total += (value - mean).powi(2) / n_frames);
+        }
+        spread.iter_mut().for_each(|value| *value = value.sqrt());
+
+        normalize(&mut mean);
+        normalize(&mut spread);
+        normalize(&mut chroma);
+
+        mean.into_iter().chain(spread).chain(chroma).collect()
+    }
+}
@@ -0,0 +1,343 @@
+use serde::Serialize;
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::error::AllomereError;
+use crate::handlers::playback::{find_clip, find_clip_track, find_track};
+use crate::states::endless;
+use crate::states::playback::{
+    AllomereMutex, Clip, ClipEdits, CustomSourceController, PlaybackState, TimelineFrame, Track,
+};
+use crate::states::registry::{ClipId, TrackId};
+use crate::states::transport::Transport;
+
+// Oldest edits are forgotten past this
+pub const HISTORY_LIMIT: usize = 100;
+// Changes closer together than this are one gesture, like a fader drag
+const MERGE_WINDOW: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TrackMix {
+    pub gain: f32,
+    pub pan: f32,
+    pub muted: bool,
+    pub soloed: bool,
+}
+
+impl TrackMix {
+    pub fn of(track: &Track) -> Self {
+        TrackMix {
+            gain: track.gain,
+            pan: track.pan,
+            muted: track.muted,
+            soloed: track.soloed,
+        }
+    }
+}
+
+// One reversible change to the project. Settings are kept as they were before
+// and after the change, clips and tracks by the same Arc or value so putting
+// them back brings back everything about them
+pub enum Edit {
+    ClipLoop {
+        id: ClipId,
+        before: CustomSourceController,
+        after: CustomSourceController,
+    },
+    ClipEdits {
+        id: ClipId,
+        before: ClipEdits,
+        after: ClipEdits,
+    },
+    AddClip {
+        track_id: TrackId,
+        clip: Arc<AllomereMutex<Clip>>,
+    },
+    RemoveClip {
+        track_id: TrackId,
+        clip: Arc<AllomereMutex<Clip>>,
+    },
+    // (track id, start_at) on either side of the move
+    MoveClip {
+        id: ClipId,
+        before: (TrackId, u64),
+        after: (TrackId, u64),
+    },
+    // The track is kept here while it's undone
+    AddTrack {
+        track_id: TrackId,
+        index: usize,
+        track: Option<Track>,
+    },
+    TrackMix {
+        track_id: TrackId,
+        before: TrackMix,
+        after: TrackMix,
+    },
+    Transport {
+        before: Transport,
+        after: Transport,
+    },
+    // Applied in order, undone in reverse
+    Group(Vec<Edit>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Direction {
+    Undo,
+    Redo,
+}
+
+impl Direction {
+    fn pick<T>(self, before: T, after: T) -> T {
+        match self {
+            Direction::Undo => before,
+            Direction::Redo => after,
+        }
+    }
+}
+
+impl Edit {
+    fn apply(
+        &mut self,
+        direction: Direction,
+        tracks: &mut Vec<Track>,
+        playback_state: &PlaybackState,
+    ) -> Result<(), AllomereError> {
+        match self {
+            Edit::ClipLoop { id, before, after } => {
+                let controller = direction.pick(&*before, &*after).clone();
+                find_clip(tracks, *id)?.0.lock().set_controller(controller);
+            }
+            Edit::ClipEdits { id, before, after } => {
+                let edits = direction.pick(&*before, &*after).clone();
+                find_clip(tracks, *id)?.0.lock().set_edits(edits);
+            }
+            Edit::AddClip { track_id, clip } => {
+                put_clip(direction == Direction::Redo, *track_id, clip, tracks)?
+            }
+            Edit::RemoveClip { track_id, clip } => {
+                put_clip(direction == Direction::Undo, *track_id, clip, tracks)?
+            }
+            Edit::MoveClip { id, before, after } => {
+                let (track_id, start_at) = *direction.pick(&*before, &*after);
+                // Check first so a missing track doesn't leave the clip nowhere
+                find_track(tracks, track_id)?;
+                let clip = find_clip_track(tracks, *id)?
+                    .take_clip(*id)
+                    .ok_or(AllomereError::ClipNotFound(*id))?;
+                clip.0.lock().start_at = Some(start_at);
+                find_track(tracks, track_id)?.schedule_clip(clip);
+            }
+            Edit::AddTrack {
+                track_id,
+                index,
+                track,
+            } => match direction {
+                Direction::Undo => {
+                    let position = tracks
+                        .iter()
+                        .position(|track| track.id == *track_id)
+                        .ok_or(AllomereError::TrackNotFound(*track_id))?;
+                    *index = position;
+                    let removed = tracks.remove(position);
+                    removed.silence();
+                    track.replace(removed);
+                    Track::update_audible(tracks);
+                }
+                Direction::Redo => {
+                    let mut track = track
+                        .take()
+                        .ok_or(AllomereError::TrackNotFound(*track_id))?;
+                    // Its timeline kept running while it was out, catch it up
+                    track.try_seek(TimelineFrame(*playback_state.total_frames.read()))?;
+                    tracks.insert((*index).min(tracks.len()), track);
+                    Track::update_audible(tracks);
+                }
+            },
+            Edit::TrackMix {
+                track_id,
+                before,
+                after,
+            } => {
+                let mix = *direction.pick(&*before, &*after);
+                let track = find_track(tracks, *track_id)?;
+                track.set_gain(mix.gain);
+                track.set_pan(mix.pan);
+                track.set_muted(mix.muted);
+                track.set_soloed(mix.soloed);
+                Track::update_audible(tracks);
+            }
+            Edit::Transport { before, after } => {
+                *playback_state.transport.write() = *direction.pick(&*before, &*after);
+                for track in tracks.iter() {
+                    track.sync_tempo();
+                }
+            }
+            Edit::Group(edits) => match direction {
+                Direction::Undo => {
+                    for edit in edits.iter_mut().rev() {
+                        edit.apply(direction, tracks, playback_state)?;
+                    }
+                }
+                Direction::Redo => {
+                    for edit in edits.iter_mut() {
+                        edit.apply(direction, tracks, playback_state)?;
+                    }
+                }
+            },
+        }
+        Ok(())
+    }
+
+    // Fader drags come in as many small changes, they're undone as one
+    fn merge(&mut self, next: &Edit) -> bool {
+        match (self, next) {
+            (
+                Edit::TrackMix {
+                    track_id, after, ..
+                },
+                Edit::TrackMix {
+                    track_id: next_track_id,
+                    after: next_after,
+                    ..
+                },
+            ) if track_id == next_track_id => {
+                *after = *next_after;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // A gesture that ended where it started, like a fader dragged back
+    fn is_noop(&self) -> bool {
+        match self {
+            Edit::TrackMix { before, after, .. } => before == after,
+            _ => false,
+        }
+    }
+}
+
+// Puts the clip on track `track_id` or takes it off again
+fn put_clip(
+    on: bool,
+    track_id: TrackId,
+    clip: &Arc<AllomereMutex<Clip>>,
+    tracks: &mut [Track],
+) -> Result<(), AllomereError> {
+    let track = find_track(tracks, track_id)?;
+    if on {
+        track.schedule_clip(clip.clone());
+    } else {
+        let id = clip.0.lock().id;
+        track.take_clip(id).ok_or(AllomereError::ClipNotFound(id))?;
+        endless::stop(id);
+    }
+    Ok(())
+}
+
+struct Entry {
+    label: String,
+    edit: Edit,
+    // When the edit, or the last change merged into it, was made
+    changed_at: Instant,
+}
+
+// Synced under "history", the next edit to undo or redo comes first in each list
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistorySummary {
+    pub undo: Vec<String>,
+    pub redo: Vec<String>,
+}
+
+#[derive(Default)]
+pub struct History {
+    undo: VecDeque<Entry>,
+    redo: Vec<Entry>,
+}
+
+impl History {
+    // Records an edit that has already been made, anything undone is dropped
+    pub fn record(&mut self, label: &str, edit: Edit) {
+        self.redo.clear();
+
+        if let Some(last) = self.undo.back_mut() {
+            if last.label == label
+                && last.changed_at.elapsed() < MERGE_WINDOW
+                && last.edit.merge(&edit)
+            {
+                last.changed_at = Instant::now();
+                if last.edit.is_noop() {
+                    self.undo.pop_back();
+                }
+                return;
+            }
+        }
+
+        self.undo.push_back(Entry {
+            label: label.to_string(),
+            edit,
+            changed_at: Instant::now(),
+        });
+        while self.undo.len() > HISTORY_LIMIT {
+            self.undo.pop_front();
+        }
+    }
+
+    // A new document starts with nothing to undo
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+    }
+
+    // Returns the label of the edit undone, None when there's nothing to undo
+    // An edit that can't be undone any more is dropped
+    pub fn undo(
+        &mut self,
+        tracks: &mut Vec<Track>,
+        playback_state: &PlaybackState,
+    ) -> Result<Option<String>, AllomereError> {
+        let Some(mut entry) = self.undo.pop_back() else {
+            return Ok(None);
+        };
+        entry.edit.apply(Direction::Undo, tracks, playback_state)?;
+        let label = entry.label.clone();
+        self.redo.push(entry);
+        Ok(Some(label))
+    }
+
+    pub fn redo(
+        &mut self,
+        tracks: &mut Vec<Track>,
+        playback_state: &PlaybackState,
+    ) -> Result<Option<String>, AllomereError> {
+        let Some(mut entry) = self.redo.pop() else {
+            return Ok(None);
+        };
+        entry.edit.apply(Direction::Redo, tracks, playback_state)?;
+        let label = entry.label.clone();
+        self.undo.push_back(entry);
+        Ok(Some(label))
+    }
+
+    pub fn summary(&self) -> HistorySummary {
+        HistorySummary {
+            undo: self
+                .undo
+                .iter()
+                .rev()
+                .map(|entry| entry.label.clone())
+                .collect(),
+            redo: self
+                .redo
+                .iter()
+                .rev()
+                .map(|entry| entry.label.clone())
+                .collect(),
+        }
+    }
+}
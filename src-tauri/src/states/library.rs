@@ -0,0 +1,292 @@
+use parking_lot::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use usearch::{Index, IndexOptions};
+
+use lazy_static::lazy_static;
+
+use std::collections::{HashMap, HashSet};
+
+use crate::error::AllomereError;
+use crate::states::features;
+
+// Which songs a transition may land in
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum TransitionScope {
+    // Another beat of the same song
+    Song,
+    // Any other song analysed this session
+    OtherSongs,
+    // Any song with a clip on one of the tracks, including the same one
+    Project,
+    // Any song analysed this session, including the same one
+    Library,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TransitionCandidate {
+    pub audio_hash: String,
+    pub path: String,
+    pub beat: usize,
+    // Source frame of the beat in that song
+    pub frame: u32,
+    pub distance: f32,
+}
+
+struct LibrarySong {
+    path: String,
+    beat_track: Vec<u32>,
+    beat_features: Vec<Vec<f32>>,
+    // The same beats keyed by beat number, for transitions within the song
+    index: Index,
+}
+
+// Every analysed song's beat features in one index keyed by (audio hash, beat), so
+// transitions can be found across songs. Songs are keyed by the hash of their
+// bytes, so two clips of the same file share their entries
+pub struct Library {
+    options: IndexOptions,
+    index: Index,
+    songs: HashMap<String, LibrarySong>,
+    // Audio hash owning each key range, ordered by first key
+    key_ranges: Vec<(u64, String)>,
+    next_key: u64,
+}
+
+lazy_static! {
+    static ref LIBRARY: Mutex<Library> = Mutex::new(Library::new(
+        features::embedding_extractor().index_options()
+    ));
+}
+
+fn new_index(options: &IndexOptions) -> Index {
+    Index::new(options).expect("Index options come from the embedding extractor")
+}
+
+// usearch only adds within reserved capacity
+fn reserve(index: &Index, additional: usize) -> Result<(), AllomereError> {
+    let needed = index.size() + additional;
+    if needed > index.capacity() {
+        index
+            .reserve(needed.next_power_of_two())
+            .map_err(|e| AllomereError::Index(e.to_string()))?;
+    }
+    Ok(())
+}
+
+impl Library {
+    fn new(options: IndexOptions) -> Self {
+        Library {
+            index: new_index(&options),
+            options,
+            songs: HashMap::new(),
+            key_ranges: Vec::new(),
+            next_key: 0,
+        }
+    }
+
+    fn add(
+        &mut self,
+        audio_hash: &str,
+        path: &str,
+        beat_track: &[u32],
+        beat_features: &[Vec<f32>],
+    ) -> Result<(), AllomereError> {
+        if self.songs.contains_key(audio_hash) {
+            return Ok(());
+        }
+        let Some(dimensions) = beat_features.first().map(Vec::len) else {
+            return Ok(());
+        };
+
+        // Features from another extractor can't share an index with the old ones,
+        // like a song analysed while the extractor was being switched
+        if dimensions != self.options.dimensions {
+            return Err(AllomereError::Index(format!(
+                "Beat features have {} dimensions, the library index expects {}",
+                dimensions, self.options.dimensions
+            )));
+        }
+
+        let song_index = new_index(&self.options);
+        reserve(&song_index, beat_features.len())?;
+        reserve(&self.index, beat_features.len())?;
+
+        // Keys are never handed out twice, even those of a song that failed to go in
+        let first_key = self.next_key;
+        self.next_key += beat_features.len() as u64;
+        let added = beat_features
+            .iter()
+            .enumerate()
+            .try_for_each(|(beat, feature)| {
+                song_index
+                    .add(beat as u64, feature)
+                    .and_then(|_| self.index.add(first_key + beat as u64, feature))
+            });
+        if let Err(e) = added {
+            // Keys left behind would be found under the song before this one
+            for beat in 0..beat_features.len() {
+                let _ = self.index.remove(first_key + beat as u64);
+            }
+            return Err(AllomereError::Index(e.to_string()));
+        }
+        self.key_ranges.push((first_key, audio_hash.to_string()));
+
+        self.songs.insert(
+            audio_hash.to_string(),
+            LibrarySong {
+                path: path.to_string(),
+                beat_track: beat_track.to_vec(),
+                beat_features: beat_features.to_vec(),
+                index: song_index,
+            },
+        );
+        Ok(())
+    }
+
+    // The song and beat behind a key of the shared index
+    fn locate(&self, key: u64) -> Option<(&str, usize)> {
+        let range = self
+            .key_ranges
+            .partition_point(|(first_key, _)| *first_key <= key)
+            .checked_sub(1)?;
+        let (first_key, audio_hash) = &self.key_ranges[range];
+        Some((audio_hash.as_str(), (key - first_key) as usize))
+    }
+
+    fn candidate(
+        &self,
+        audio_hash: &str,
+        beat: usize,
+        distance: f32,
+    ) -> Option<TransitionCandidate> {
+        let song = self.songs.get(audio_hash)?;
+        Some(TransitionCandidate {
+            audio_hash: audio_hash.to_string(),
+            path: song.path.clone(),
+            beat,
+            frame: *song.beat_track.get(beat)?,
+            distance,
+        })
+    }
+
+    fn find_transitions(
+        &self,
+        audio_hash: &str,
+        beat: usize,
+        count: usize,
+        keep: impl Fn(&str) -> bool,
+        song_only: bool,
+    ) -> Result<Vec<TransitionCandidate>, AllomereError> {
+        let song = self
+            .songs
+            .get(audio_hash)
+            .ok_or_else(|| AllomereError::AnalysisPending(audio_hash.to_string()))?;
+        let feature = song
+            .beat_features
+            .get(beat)
+            .ok_or(AllomereError::BeatOutOfRange {
+                beat,
+                beats: song.beat_features.len(),
+            })?;
+        let is_origin = |candidate_hash: &str, candidate_beat: usize| {
+            candidate_hash == audio_hash && candidate_beat == beat
+        };
+
+        if song_only {
+            // One extra for the beat itself, which is always its own closest match
+            let matches = song
+                .index
+                .search(feature, count + 1)
+                .map_err(|e| AllomereError::Index(e.to_string()))?;
+            return Ok(matches
+                .keys
+                .iter()
+                .zip(matches.distances.iter())
+                .filter(|(&key, _)| !is_origin(audio_hash, key as usize))
+                .filter_map(|(&key, &distance)| self.candidate(audio_hash, key as usize, distance))
+                .take(count)
+                .collect());
+        }
+
+        // Matches outside the scope are dropped, so fetch more until there are enough
+        let size = self.index.size();
+        let mut fetch = (count * 4).max(32);
+        loop {
+            let matches = self
+                .index
+                .search(feature, fetch.min(size))
+                .map_err(|e| AllomereError::Index(e.to_string()))?;
+            let candidates: Vec<TransitionCandidate> = matches
+                .keys
+                .iter()
+                .zip(matches.distances.iter())
+                .filter_map(|(&key, &distance)| {
+                    let (candidate_hash, candidate_beat) = self.locate(key)?;
+                    (keep(candidate_hash) && !is_origin(candidate_hash, candidate_beat))
+                        .then(|| self.candidate(candidate_hash, candidate_beat, distance))
+                        .flatten()
+                })
+                .take(count)
+                .collect();
+
+            if candidates.len() >= count || fetch >= size {
+                return Ok(candidates);
+            }
+            fetch *= 4;
+        }
+    }
+}
+
+// Empties the library into an index for the current embedding extractor, songs
+// analysed from then on fill it again
+pub fn reset() {
+    *LIBRARY.lock() = Library::new(features::embedding_extractor().index_options());
+}
+
+// Adding a song that's already in the library does nothing
+pub fn add(
+    audio_hash: &str,
+    path: &str,
+    beat_track: &[u32],
+    beat_features: &[Vec<f32>],
+) -> Result<(), AllomereError> {
+    LIBRARY
+        .lock()
+        .add(audio_hash, path, beat_track, beat_features)
+}
+
+// Closest beats to `beat` of the song with `audio_hash`, nearest first. The beat
+// itself is left out. `project` holds the audio hashes of the clips on the tracks
+pub fn find_transitions(
+    audio_hash: &str,
+    beat: usize,
+    count: usize,
+    scope: TransitionScope,
+    project: &HashSet<String>,
+) -> Result<Vec<TransitionCandidate>, AllomereError> {
+    let library = LIBRARY.lock();
+    match scope {
+        TransitionScope::Song => library.find_transitions(audio_hash, beat, count, |_| true, true),
+        TransitionScope::OtherSongs => library.find_transitions(
+            audio_hash,
+            beat,
+            count,
+            |candidate_hash| candidate_hash != audio_hash,
+            false,
+        ),
+        TransitionScope::Project => library.find_transitions(
+            audio_hash,
+            beat,
+            count,
+            |candidate_hash| project.contains(candidate_hash),
+            false,
+        ),
+        TransitionScope::Library => {
+            library.find_transitions(audio_hash, beat, count, |_| true, false)
+        }
+    }
+}
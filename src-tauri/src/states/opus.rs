@@ -0,0 +1,172 @@
+use ogg::PacketReader;
+
+use opus::{Channels, Decoder as PacketDecoder};
+
+use rodio::decoder::DecoderError;
+use rodio::Source;
+
+use std::io::{Read, Seek};
+use std::path::Path;
+use std::time::Duration;
+
+// Opus always decodes at 48 kHz, whatever rate the file was made from
+const SAMPLE_RATE: u32 = 48000;
+// Samples per channel in the longest Opus packet, 120 ms
+const MAX_PACKET_FRAMES: usize = 5760;
+
+pub fn is_opus(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("opus"))
+}
+
+// Ogg Opus through libopus, which symphonia can't decode. Only mono and stereo
+// streams, the channel mapping family used by nearly every .opus file
+pub struct OpusDecoder<R: Read + Seek> {
+    packets: PacketReader<R>,
+    decoder: PacketDecoder,
+    channels: u16,
+    // Output gain from the header, 1 if there's none
+    gain: f32,
+    // Frames at the start that are only there to prime the decoder
+    pre_skip: u64,
+    // Frames decoded so far, pre-skip included as granule positions count it
+    decoded: u64,
+    buffer: Vec<i16>,
+    position: usize,
+    len: usize,
+    finished: bool,
+}
+
+impl<R: Read + Seek> OpusDecoder<R> {
+    pub fn new(reader: R) -> Result<Self, DecoderError> {
+        let mut packets = PacketReader::new(reader);
+
+        let head = read_packet(&mut packets)?.ok_or(DecoderError::UnrecognizedFormat)?;
+        if head.len() < 19 || &head[..8] != b"OpusHead" {
+            return Err(DecoderError::UnrecognizedFormat);
+        }
+        let channels = match (head[9], head[18]) {
+            (1, 0) => Channels::Mono,
+            (2, 0) => Channels::Stereo,
+            _ => {
+                return Err(DecoderError::DecodeError(
+                    "Only mono and stereo Opus streams are supported",
+                ))
+            }
+        };
+        let pre_skip = u16::from_le_bytes([head[10], head[11]]) as u64;
+        // Q7.8 dB
+        let gain_db = i16::from_le_bytes([head[16], head[17]]) as f32 / 256.0;
+
+        // Comment header, nothing in it is needed
+        read_packet(&mut packets)?.ok_or(DecoderError::NoStreams)?;
+
+        let decoder = PacketDecoder::new(SAMPLE_RATE, channels)
+            .map_err(|_| DecoderError::DecodeError("Failed to create the Opus decoder"))?;
+
+        Ok(OpusDecoder {
+            packets,
+            decoder,
+            channels: head[9] as u16,
+            gain: 10f32.powf(gain_db / 20.0),
+            pre_skip,
+            decoded: 0,
+            buffer: vec![0; MAX_PACKET_FRAMES * head[9] as usize],
+            position: 0,
+            len: 0,
+            finished: false,
+        })
+    }
+
+    // Decodes packets until one leaves samples to play, false at the end of the stream
+    fn refill(&mut self) -> bool {
+        while !self.finished {
+            let packet = match self.packets.read_packet() {
+                Ok(Some(packet)) => packet,
+                Ok(None) => break,
+                Err(e) => {
+                    eprintln!("Failed to read Opus packet: {}", e);
+                    break;
+                }
+            };
+
+            let frames = match self.decoder.decode(&packet.data, &mut self.buffer, false) {
+                Ok(frames) => frames as u64,
+                Err(e) => {
+                    eprintln!("Failed to decode Opus packet: {}", e);
+                    continue;
+                }
+            };
+            let start = self.decoded;
+            self.decoded += frames;
+
+            // The last granule position marks where the audio really ends
+            let mut end = frames;
+            if packet.last_in_stream() {
+                end = end.min(packet.absgp_page().saturating_sub(start));
+                self.finished = true;
+            }
+            let skip = self.pre_skip.min(end);
+            self.pre_skip -= skip;
+
+            let channels = self.channels as usize;
+            self.position = skip as usize * channels;
+            self.len = end as usize * channels;
+            if self.gain != 1.0 {
+                for sample in &mut self.buffer[self.position..self.len] {
+                    *sample = (*sample as f32 * self.gain)
+                        .round()
+                        .clamp(i16::MIN as f32, i16::MAX as f32)
+                        as i16;
+                }
+            }
+            if self.position < self.len {
+                return true;
+            }
+        }
+
+        self.finished = true;
+        false
+    }
+}
+
+fn read_packet<R: Read + Seek>(
+    packets: &mut PacketReader<R>,
+) -> Result<Option<Vec<u8>>, DecoderError> {
+    packets
+        .read_packet()
+        .map(|packet| packet.map(|packet| packet.data))
+        .map_err(|e| DecoderError::IoError(e.to_string()))
+}
+
+impl<R: Read + Seek> Iterator for OpusDecoder<R> {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        if self.position == self.len && !self.refill() {
+            return None;
+        }
+        let sample = self.buffer[self.position];
+        self.position += 1;
+        Some(sample)
+    }
+}
+
+impl<R: Read + Seek> Source for OpusDecoder<R> {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        SAMPLE_RATE
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
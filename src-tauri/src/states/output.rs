@@ -0,0 +1,520 @@
+use anyhow::{anyhow, Result};
+
+use parking_lot::{Mutex, RwLock};
+
+use rodio::cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use rodio::cpal::{
+    self, BufferSize, Device, SampleFormat, SampleRate, Stream, StreamConfig, SupportedBufferSize,
+    SupportedStreamConfig,
+};
+use rodio::dynamic_mixer::DynamicMixer;
+use rodio::source::UniformSourceIterator;
+use rodio::{Source, StreamError};
+
+use serde::Serialize;
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering::SeqCst};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::error::AllomereError;
+
+pub type MixerOutput = Arc<Mutex<DynamicMixer<f32>>>;
+
+// Pulls interleaved samples from the mixer into `data`, advancing the playback clock
+pub fn fill_from_mixer(
+    data: &mut [f32],
+    mixer_rx: &MixerOutput,
+    total_frames: &Arc<RwLock<u64>>,
+    channels: usize,
+) {
+    *(total_frames.write()) += (data.len() / channels) as u64;
+
+    let mut mixer_rx = mixer_rx.lock();
+    data.iter_mut()
+        .for_each(|d| *d = mixer_rx.next().unwrap_or(0f32))
+}
+
+// The mixer read as a source of its own, so it can be converted for devices that
+// don't run at the project's format. Counts the samples taken for the playback clock
+struct MixerSource {
+    mixer_rx: MixerOutput,
+    channels: u16,
+    sample_rate: u32,
+    samples: Arc<AtomicU64>,
+}
+
+impl Iterator for MixerSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        self.samples.fetch_add(1, SeqCst);
+        Some(self.mixer_rx.lock().next().unwrap_or(0f32))
+    }
+}
+
+impl Source for MixerSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+// A second mixer played on channels of another mixer's stream, like the preview
+// bus on channels 3/4 of the main output. It replaces what the main mix had there
+pub struct ChannelRoute {
+    mixer_rx: MixerOutput,
+    mixer_channels: u16,
+    mixer_sample_rate: u32,
+    first_channel: usize,
+    // Converted for the stream playing it, rebuilt if that runs at another rate
+    converted: Option<(u32, UniformSourceIterator<MixerSource, f32>)>,
+}
+
+// Shared with the stream so the route can change while it plays
+pub type SharedChannelRoute = Arc<Mutex<Option<ChannelRoute>>>;
+
+impl ChannelRoute {
+    // `first_channel` counts from 0, so 2 is channels 3/4
+    pub fn new(
+        mixer_rx: MixerOutput,
+        mixer_config: &SupportedStreamConfig,
+        first_channel: u16,
+    ) -> Self {
+        ChannelRoute {
+            mixer_rx,
+            mixer_channels: mixer_config.channels(),
+            mixer_sample_rate: mixer_config.sample_rate().0,
+            first_channel: first_channel as usize,
+            converted: None,
+        }
+    }
+
+    // Highest channel the route plays on, counting from 1
+    pub fn last_channel(&self) -> u16 {
+        (self.first_channel + self.mixer_channels as usize) as u16
+    }
+
+    fn fill(&mut self, data: &mut [f32], channels: usize, sample_rate: u32) {
+        let last_channel = self.last_channel() as usize;
+        if last_channel > channels {
+            return;
+        }
+
+        if self.converted.as_ref().map(|(rate, _)| *rate) != Some(sample_rate) {
+            let source = MixerSource {
+                mixer_rx: self.mixer_rx.clone(),
+                channels: self.mixer_channels,
+                sample_rate: self.mixer_sample_rate,
+                samples: Arc::new(AtomicU64::new(0)),
+            };
+            self.converted = Some((
+                sample_rate,
+                UniformSourceIterator::new(source, self.mixer_channels, sample_rate),
+            ));
+        }
+        let Some((_, source)) = self.converted.as_mut() else {
+            return;
+        };
+
+        for frame in data.chunks_mut(channels) {
+            for sample in &mut frame[self.first_channel..last_channel] {
+                *sample = source.next().unwrap_or(0f32);
+            }
+        }
+    }
+}
+
+// Feeds a device stream from the mixer, converting when the device's format
+// isn't the project's. The playback clock always counts frames of the mixer
+struct MixerStream {
+    mixer_rx: MixerOutput,
+    total_frames: Arc<RwLock<u64>>,
+    mixer_channels: u16,
+    converted: Option<(UniformSourceIterator<MixerSource, f32>, Arc<AtomicU64>)>,
+    counted_frames: u64,
+    sample_rate: u32,
+    channel_route: SharedChannelRoute,
+}
+
+impl MixerStream {
+    fn new(
+        mixer_rx: MixerOutput,
+        mixer_config: &SupportedStreamConfig,
+        config: &StreamConfig,
+        total_frames: Arc<RwLock<u64>>,
+        channel_route: SharedChannelRoute,
+    ) -> Self {
+        let converted = (config.channels != mixer_config.channels()
+            || config.sample_rate != mixer_config.sample_rate())
+        .then(|| {
+            let samples = Arc::new(AtomicU64::new(0));
+            let source = MixerSource {
+                mixer_rx: mixer_rx.clone(),
+                channels: mixer_config.channels(),
+                sample_rate: mixer_config.sample_rate().0,
+                samples: samples.clone(),
+            };
+            (
+                UniformSourceIterator::new(source, config.channels, config.sample_rate.0),
+                samples,
+            )
+        });
+
+        MixerStream {
+            mixer_rx,
+            total_frames,
+            mixer_channels: mixer_config.channels(),
+            converted,
+            counted_frames: 0,
+            sample_rate: config.sample_rate.0,
+            channel_route,
+        }
+    }
+
+    fn fill(&mut self, data: &mut [f32], channels: usize) {
+        match self.converted.as_mut() {
+            Some((source, samples)) => {
+                data.iter_mut()
+                    .for_each(|d| *d = source.next().unwrap_or(0f32));
+
+                let frames = samples.load(SeqCst) / self.mixer_channels as u64;
+                *(self.total_frames.write()) += frames - self.counted_frames;
+                self.counted_frames = frames;
+            }
+            None => fill_from_mixer(data, &self.mixer_rx, &self.total_frames, channels),
+        }
+
+        if let Some(route) = self.channel_route.lock().as_mut() {
+            route.fill(data, channels, self.sample_rate);
+        }
+    }
+}
+
+// The device a stream plays on and the format it runs at
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OutputDevice {
+    pub name: String,
+    pub sample_rate: u32,
+    pub channels: u16,
+    // In frames, None leaves it to the device
+    pub buffer_size: Option<u32>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OutputDeviceInfo {
+    pub name: String,
+    pub is_default: bool,
+    pub channels: u16,
+    pub default_sample_rate: u32,
+    // Over every config the device supports
+    pub min_sample_rate: u32,
+    pub max_sample_rate: u32,
+    // In frames, None when the device doesn't say
+    pub min_buffer_size: Option<u32>,
+    pub max_buffer_size: Option<u32>,
+}
+
+pub trait OutputBackend {
+    fn name(&self) -> &'static str;
+
+    // None for backends without a device
+    fn device(&self) -> Option<OutputDevice> {
+        None
+    }
+
+    fn play(&self) -> Result<()>;
+
+    fn pause(&self) -> Result<()>;
+
+    // Renders `frames` frames right away and returns them interleaved, only
+    // supported by backends without a device clock
+    fn advance(&self, _frames: u64) -> Result<Vec<f32>> {
+        Err(anyhow!("{} output is driven by its device", self.name()))
+    }
+}
+
+pub struct CpalBackend {
+    pub device: Device,
+    output: OutputDevice,
+    stream: Stream,
+}
+
+impl CpalBackend {
+    // Plays the mixer, which runs at `mixer_config`, on `device` at `config`, with
+    // whatever `channel_route` holds on top. `on_device_lost` runs once, on the
+    // stream's thread, if the device goes away
+    pub fn new(
+        device: Device,
+        config: StreamConfig,
+        mixer_rx: MixerOutput,
+        mixer_config: &SupportedStreamConfig,
+        total_frames: Arc<RwLock<u64>>,
+        channel_route: SharedChannelRoute,
+        on_device_lost: impl Fn() + Send + 'static,
+    ) -> Result<Self> {
+        let lost = AtomicBool::new(false);
+        let error_callback = move |err| match err {
+            cpal::StreamError::DeviceNotAvailable => {
+                if !lost.swap(true, SeqCst) {
+                    eprintln!("Output device lost");
+                    on_device_lost();
+                }
+            }
+            err => eprintln!("an error occurred on output stream: {}", err),
+        };
+
+        let channels = config.channels as usize;
+        let mut mixer_stream =
+            MixerStream::new(mixer_rx, mixer_config, &config, total_frames, channel_route);
+
+        let stream = device.build_output_stream::<f32, _, _>(
+            &config,
+            move |data, _| mixer_stream.fill(data, channels),
+            error_callback,
+            None,
+        )?;
+
+        let output = OutputDevice {
+            name: device_name(&device),
+            sample_rate: config.sample_rate.0,
+            channels: config.channels,
+            buffer_size: match config.buffer_size {
+                BufferSize::Fixed(frames) => Some(frames),
+                BufferSize::Default => None,
+            },
+        };
+
+        Ok(CpalBackend {
+            device,
+            output,
+            stream,
+        })
+    }
+}
+
+impl OutputBackend for CpalBackend {
+    fn name(&self) -> &'static str {
+        "cpal"
+    }
+
+    fn device(&self) -> Option<OutputDevice> {
+        Some(self.output.clone())
+    }
+
+    fn play(&self) -> Result<()> {
+        Ok(self.stream.play()?)
+    }
+
+    fn pause(&self) -> Result<()> {
+        Ok(self.stream.pause()?)
+    }
+}
+
+// Output without a sound card, frames are pulled on a timer or on demand
+pub struct NullBackend {
+    mixer_rx: MixerOutput,
+    total_frames: Arc<RwLock<u64>>,
+    channels: usize,
+    is_playing: Arc<AtomicBool>,
+    is_closed: Arc<AtomicBool>,
+}
+
+impl NullBackend {
+    pub const SAMPLE_RATE: u32 = 48_000;
+    pub const CHANNELS: u16 = 2;
+
+    pub fn config() -> SupportedStreamConfig {
+        SupportedStreamConfig::new(
+            Self::CHANNELS,
+            SampleRate(Self::SAMPLE_RATE),
+            SupportedBufferSize::Unknown,
+            SampleFormat::F32,
+        )
+    }
+
+    // With a `period` the mixer is drained in real time on a background thread,
+    // without one it only moves when `advance` is called
+    pub fn new(
+        config: &SupportedStreamConfig,
+        mixer_rx: MixerOutput,
+        total_frames: Arc<RwLock<u64>>,
+        period: Option<Duration>,
+    ) -> Self {
+        let backend = NullBackend {
+            mixer_rx,
+            total_frames,
+            channels: config.channels() as usize,
+            is_playing: Arc::new(AtomicBool::new(false)),
+            is_closed: Arc::new(AtomicBool::new(false)),
+        };
+
+        if let Some(period) = period {
+            let mixer_rx = backend.mixer_rx.clone();
+            let total_frames = backend.total_frames.clone();
+            let is_playing = backend.is_playing.clone();
+            let is_closed = backend.is_closed.clone();
+            let channels = backend.channels;
+            let period_frames =
+                ((config.sample_rate().0 as f64) * period.as_secs_f64()).max(1.0) as usize;
+
+            thread::spawn(move || {
+                let mut data = vec![0f32; period_frames * channels];
+                while !is_closed.load(SeqCst) {
+                    thread::sleep(period);
+                    if is_playing.load(SeqCst) {
+                        fill_from_mixer(&mut data, &mixer_rx, &total_frames, channels);
+                    }
+                }
+            });
+        }
+
+        backend
+    }
+}
+
+impl OutputBackend for NullBackend {
+    fn name(&self) -> &'static str {
+        "null"
+    }
+
+    fn play(&self) -> Result<()> {
+        self.is_playing.store(true, SeqCst);
+        Ok(())
+    }
+
+    fn pause(&self) -> Result<()> {
+        self.is_playing.store(false, SeqCst);
+        Ok(())
+    }
+
+    fn advance(&self, frames: u64) -> Result<Vec<f32>> {
+        let mut data = vec![0f32; frames as usize * self.channels];
+        fill_from_mixer(&mut data, &self.mixer_rx, &self.total_frames, self.channels);
+        Ok(data)
+    }
+}
+
+impl Drop for NullBackend {
+    fn drop(&mut self) {
+        self.is_closed.store(true, SeqCst);
+    }
+}
+
+pub fn default_device() -> Result<(Device, SupportedStreamConfig)> {
+    let device = cpal::default_host()
+        .default_output_device()
+        .ok_or(StreamError::NoDevice)?;
+    let config = device.default_output_config()?;
+    Ok((device, config))
+}
+
+fn device_name(device: &Device) -> String {
+    device
+        .name()
+        .unwrap_or_else(|_| "Unknown device".to_string())
+}
+
+// Output devices of the default host, devices that can't report a config are left out
+pub fn list_devices() -> Result<Vec<OutputDeviceInfo>> {
+    let host = cpal::default_host();
+    let default_name = host
+        .default_output_device()
+        .and_then(|device| device.name().ok());
+
+    Ok(host
+        .output_devices()?
+        .filter_map(|device| {
+            let name = device.name().ok()?;
+            let default_config = device.default_output_config().ok()?;
+            let configs: Vec<_> = device.supported_output_configs().ok()?.collect();
+            let buffer_sizes = configs
+                .iter()
+                .filter_map(|config| match config.buffer_size() {
+                    SupportedBufferSize::Range { min, max } => Some((*min, *max)),
+                    SupportedBufferSize::Unknown => None,
+                });
+
+            Some(OutputDeviceInfo {
+                is_default: default_name.as_ref() == Some(&name),
+                channels: default_config.channels(),
+                default_sample_rate: default_config.sample_rate().0,
+                min_sample_rate: configs
+                    .iter()
+                    .map(|config| config.min_sample_rate().0)
+                    .min()
+                    .unwrap_or(default_config.sample_rate().0),
+                max_sample_rate: configs
+                    .iter()
+                    .map(|config| config.max_sample_rate().0)
+                    .max()
+                    .unwrap_or(default_config.sample_rate().0),
+                min_buffer_size: buffer_sizes.clone().map(|(min, _)| min).min(),
+                max_buffer_size: buffer_sizes.map(|(_, max)| max).max(),
+                name,
+            })
+        })
+        .collect())
+}
+
+// The device called `name`, the default device for None
+pub fn find_device(name: Option<&str>) -> Result<Device, AllomereError> {
+    let host = cpal::default_host();
+    let device = match name {
+        Some(name) => host
+            .output_devices()
+            .map_err(anyhow::Error::from)?
+            .find(|device| device.name().is_ok_and(|device_name| device_name == name)),
+        None => host.default_output_device(),
+    };
+    device.ok_or_else(|| AllomereError::OutputDeviceNotFound(name.unwrap_or("default").to_string()))
+}
+
+// The device's default config, moved to `sample_rate` and with a fixed buffer of
+// `buffer_size` frames when they're given. Buffer sizes are clamped to what the
+// device allows
+pub fn stream_config(
+    device: &Device,
+    sample_rate: Option<u32>,
+    buffer_size: Option<u32>,
+) -> Result<StreamConfig> {
+    let default_config = device.default_output_config()?;
+    let supported_config = match sample_rate {
+        Some(sample_rate) if sample_rate != default_config.sample_rate().0 => device
+            .supported_output_configs()?
+            .filter(|config| config.channels() == default_config.channels())
+            .find_map(|config| config.try_with_sample_rate(SampleRate(sample_rate)))
+            .ok_or_else(|| {
+                anyhow!(
+                    "{} can't play {} channels at {} Hz",
+                    device_name(device),
+                    default_config.channels(),
+                    sample_rate
+                )
+            })?,
+        _ => default_config,
+    };
+
+    let mut config = supported_config.config();
+    if let Some(buffer_size) = buffer_size {
+        config.buffer_size = BufferSize::Fixed(match supported_config.buffer_size() {
+            SupportedBufferSize::Range { min, max } => buffer_size.clamp(*min, *max),
+            SupportedBufferSize::Unknown => buffer_size,
+        });
+    }
+    Ok(config)
+}
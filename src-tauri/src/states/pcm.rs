@@ -0,0 +1,618 @@
+use crossbeam_channel::{Receiver, Sender, TrySendError};
+
+use parking_lot::{Condvar, Mutex};
+
+use rodio::cpal::Sample;
+use rodio::source::SeekError;
+use rodio::{Decoder, Source};
+
+use lazy_static::lazy_static;
+
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, TryLockError};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering::SeqCst};
+use std::sync::{Arc, Once, OnceLock, Weak};
+use std::thread::{self, Thread};
+use std::time::Duration;
+
+use crate::error::AllomereError;
+use crate::states::cache;
+use crate::states::opus::{self, OpusDecoder};
+
+// Frames per chunk, the unit decoded audio is written and read back in
+pub const CHUNK_FRAMES: u64 = 1 << 16;
+// Chunks kept in memory across every reader of a file, about 16 MB of stereo
+const CACHED_CHUNKS: usize = 32;
+// Chunks a stream's prefetch thread keeps ready past the one playing
+const PREFETCH_CHUNKS: usize = 2;
+// How long the prefetch thread sleeps when nobody wakes it
+const PREFETCH_IDLE: Duration = Duration::from_millis(100);
+// `Pcm::length` until decoding finishes
+const DECODING: u64 = u64::MAX;
+
+lazy_static! {
+    // Keyed by audio hash so copies of a file are only decoded once
+    static ref PCM_MAP: Mutex<HashMap<String, Weak<Pcm>>> = Mutex::new(HashMap::new());
+}
+
+static REMOVE_STALE: Once = Once::new();
+
+// Every running instance holds a lock on `<pid>.lock` in the decoded audio
+// directory until it exits, this one's is kept here
+static OWNER_LOCK: OnceLock<File> = OnceLock::new();
+
+// Takes this instance's lock, then removes what instances that have exited left
+fn claim(pcm_dir: &Path) {
+    let path = pcm_dir.join(format!("{}.lock", std::process::id()));
+    match File::create(&path).and_then(|file| file.lock().map(|_| file)) {
+        Ok(file) => {
+            let _ = OWNER_LOCK.set(file);
+        }
+        Err(e) => eprintln!("Failed to lock {:?}: {}", path, e),
+    }
+    remove_stale(pcm_dir);
+}
+
+// Process id in `<hash>-<pid>.pcm` and `<pid>.lock`
+fn owner(path: &Path) -> Option<&str> {
+    let stem = path.file_stem()?.to_str()?;
+    match path.extension()?.to_str()? {
+        "pcm" => stem.rsplit_once('-').map(|(_, pid)| pid),
+        "lock" => Some(stem),
+        _ => None,
+    }
+}
+
+// An instance that's still running has its lock file locked
+fn is_running(pcm_dir: &Path, pid: &str) -> bool {
+    File::open(pcm_dir.join(format!("{}.lock", pid)))
+        .is_ok_and(|file| matches!(file.try_lock(), Err(TryLockError::WouldBlock)))
+}
+
+// Decoded audio left behind by instances that have exited. Files of other
+// instances still running are their live cache and are left alone
+fn remove_stale(pcm_dir: &Path) {
+    let pid = std::process::id().to_string();
+    let Ok(entries) = fs::read_dir(pcm_dir) else {
+        return;
+    };
+    let mut running: HashMap<String, bool> = HashMap::new();
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let Some(owner) = owner(&path) else {
+            continue;
+        };
+        if owner == pid {
+            continue;
+        }
+        let owner_running = *running
+            .entry(owner.to_string())
+            .or_insert_with(|| is_running(pcm_dir, owner));
+        if !owner_running {
+            if let Err(e) = fs::remove_file(&path) {
+                eprintln!("Failed to remove stale decoded audio {:?}: {}", path, e);
+            }
+        }
+    }
+}
+
+struct Progress {
+    frames: u64,
+    finished: bool,
+}
+
+// Decoded audio of one file, shared by playback and analysis. The file is decoded
+// once on a background thread, streaming it from disk, into 16 bit PCM in the
+// cache directory. Readers get it back a chunk at a time and only the chunks
+// used last stay in memory
+pub struct Pcm {
+    hash: String,
+    path: PathBuf,
+    channels: u16,
+    sample_rate: u32,
+    // What the decoder reported up front, the length until decoding finishes
+    estimated_frames: Option<u64>,
+    // Frames in the file, readable without locking once decoding has finished
+    length: AtomicU64,
+    progress: Mutex<Progress>,
+    decoded: Condvar,
+    file: Mutex<File>,
+    // Least recently used first. Only held for lookups, never across a read
+    cached: Mutex<VecDeque<(u64, Arc<Vec<f32>>)>>,
+}
+
+impl Pcm {
+    // Starts decoding `source_path`, or shares the decode of a file with the same content
+    pub fn open(source_path: &Path) -> Result<Arc<Pcm>, AllomereError> {
+        let io_error = |path: &Path| {
+            let path = path.to_path_buf();
+            move |source| AllomereError::Io { path, source }
+        };
+        let decode_error = |path: &Path| {
+            let path = path.to_path_buf();
+            move |source| AllomereError::Decode { path, source }
+        };
+
+        let hash = cache::audio_hash(source_path).map_err(io_error(source_path))?;
+
+        let mut pcm_map = PCM_MAP.lock();
+        if let Some(pcm) = pcm_map.get(&hash).and_then(Weak::upgrade) {
+            return Ok(pcm);
+        }
+
+        let file = File::open(source_path).map_err(io_error(source_path))?;
+        let reader = BufReader::new(file);
+        let decoder: Box<dyn Source<Item = i16> + Send> = if opus::is_opus(source_path) {
+            Box::new(OpusDecoder::new(reader).map_err(decode_error(source_path))?)
+        } else {
+            Box::new(Decoder::new(reader).map_err(decode_error(source_path))?)
+        };
+
+        let pcm_dir = cache::pcm_dir()?;
+        fs::create_dir_all(&pcm_dir).map_err(io_error(&pcm_dir))?;
+        REMOVE_STALE.call_once(|| claim(&pcm_dir));
+        let path = pcm_dir.join(format!("{}-{}.pcm", hash, std::process::id()));
+        let writer = File::create(&path).map_err(io_error(&path))?;
+        let reader = File::open(&path).map_err(io_error(&path))?;
+
+        let pcm = Arc::new(Pcm {
+            hash: hash.clone(),
+            path,
+            channels: decoder.channels(),
+            sample_rate: decoder.sample_rate(),
+            estimated_frames: decoder.total_duration().map(|total_duration| {
+                (total_duration.as_secs_f64() * decoder.sample_rate() as f64).round() as u64
+            }),
+            length: AtomicU64::new(DECODING),
+            progress: Mutex::new(Progress {
+                frames: 0,
+                finished: false,
+            }),
+            decoded: Condvar::new(),
+            file: Mutex::new(reader),
+            cached: Mutex::new(VecDeque::new()),
+        });
+        pcm_map.insert(hash, Arc::downgrade(&pcm));
+
+        let decoding = pcm.clone();
+        thread::spawn(move || decoding.decode(decoder, writer));
+
+        Ok(pcm)
+    }
+
+    fn decode(&self, mut decoder: Box<dyn Source<Item = i16> + Send>, file: File) {
+        let chunk_samples = (CHUNK_FRAMES * self.channels as u64) as usize;
+        let mut writer = BufWriter::new(file);
+        let mut bytes = Vec::with_capacity(chunk_samples * 2);
+
+        loop {
+            bytes.clear();
+            bytes.extend(
+                decoder
+                    .by_ref()
+                    .take(chunk_samples)
+                    .flat_map(|sample| sample.to_le_bytes()),
+            );
+            let frames = (bytes.len() / 2 / self.channels as usize) as u64;
+            if frames == 0 {
+                break;
+            }
+
+            // Flushed so the chunk can be read back as soon as it's counted
+            if let Err(e) = writer.write_all(&bytes).and_then(|_| writer.flush()) {
+                eprintln!("Failed to write decoded audio to {:?}: {}", self.path, e);
+                break;
+            }
+
+            self.progress.lock().frames += frames;
+            self.decoded.notify_all();
+
+            if frames < CHUNK_FRAMES {
+                break;
+            }
+        }
+
+        let mut progress = self.progress.lock();
+        progress.finished = true;
+        self.length.store(progress.frames, SeqCst);
+        drop(progress);
+        self.decoded.notify_all();
+    }
+
+    pub fn hash(&self) -> &str {
+        &self.hash
+    }
+
+    pub fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // Exact once decoding has finished, the decoder's estimate before that
+    pub fn frames(&self) -> Option<u64> {
+        match self.length.load(SeqCst) {
+            DECODING => self.estimated_frames,
+            frames => Some(frames),
+        }
+    }
+
+    // Blocks until every frame before `frame` is decoded or the file ends first,
+    // returns the frames decoded by then
+    fn wait_for(&self, frame: u64) -> u64 {
+        let mut progress = self.progress.lock();
+        while progress.frames < frame && !progress.finished {
+            self.decoded.wait(&mut progress);
+        }
+        progress.frames
+    }
+
+    // Chunk `index` as interleaved samples, None past the end of the file
+    pub fn chunk(&self, index: u64) -> Option<Arc<Vec<f32>>> {
+        let start_frame = index * CHUNK_FRAMES;
+        let frames = self
+            .wait_for(start_frame + CHUNK_FRAMES)
+            .min(start_frame + CHUNK_FRAMES)
+            .checked_sub(start_frame)
+            .filter(|frames| *frames > 0)?;
+
+        if let Some(chunk) = touch(&mut self.cached.lock(), index) {
+            return Some(chunk);
+        }
+
+        let channels = self.channels as u64;
+        let mut bytes = vec![0u8; (frames * channels * 2) as usize];
+        let mut file = self.file.lock();
+        if let Err(e) = file
+            .seek(SeekFrom::Start(start_frame * channels * 2))
+            .and_then(|_| file.read_exact(&mut bytes))
+        {
+            eprintln!("Failed to read decoded audio from {:?}: {}", self.path, e);
+            return None;
+        }
+        drop(file);
+
+        let chunk = Arc::new(
+            bytes
+                .chunks_exact(2)
+                .map(|sample| i16::from_le_bytes([sample[0], sample[1]]).to_sample::<f32>())
+                .collect::<Vec<f32>>(),
+        );
+        let mut cached = self.cached.lock();
+        // Another reader may have read it in the meantime
+        if let Some(chunk) = touch(&mut cached, index) {
+            return Some(chunk);
+        }
+        cached.push_back((index, chunk.clone()));
+        if cached.len() > CACHED_CHUNKS {
+            cached.pop_front();
+        }
+        Some(chunk)
+    }
+
+    // Chunk `index` if it's in memory and the cache isn't busy, never waits
+    fn try_cached_chunk(&self, index: u64) -> Option<Arc<Vec<f32>>> {
+        touch(&mut *self.cached.try_lock()?, index)
+    }
+
+    // `samples` interleaved samples from `start_frame` on, silence outside the file
+    pub fn slice(self: &Arc<Self>, start_frame: i64, samples: usize) -> Vec<f32> {
+        let channels = self.channels as usize;
+        let mut slice = vec![0f32; samples];
+
+        let skipped = (start_frame.min(0).unsigned_abs() as usize * channels).min(samples);
+        let mut reader = self.reader();
+        reader.seek(start_frame.max(0) as u64);
+        for (sample, decoded) in slice[skipped..].iter_mut().zip(reader) {
+            *sample = decoded;
+        }
+        slice
+    }
+
+    pub fn reader(self: &Arc<Self>) -> PcmReader {
+        PcmReader {
+            pcm: self.clone(),
+            position: 0,
+            chunk: None,
+            prefetch: None,
+        }
+    }
+
+    // A reader for the audio thread. A thread of its own reads ahead of it and
+    // it never waits, audio that isn't ready in time plays as silence
+    pub fn stream(self: &Arc<Self>) -> PcmReader {
+        let request = Arc::new(PrefetchRequest {
+            packed: AtomicU64::new(0),
+            pins: AtomicU64::new(NO_PINS),
+            pinned: Mutex::new(Vec::new()),
+            stopped: AtomicBool::new(false),
+        });
+        let (sender, chunks) = crossbeam_channel::bounded(PREFETCH_CHUNKS);
+        let pcm = self.clone();
+        let prefetch_request = request.clone();
+        let thread = thread::spawn(move || prefetch(pcm, prefetch_request, sender))
+            .thread()
+            .clone();
+
+        PcmReader {
+            pcm: self.clone(),
+            position: 0,
+            chunk: None,
+            prefetch: Some(Prefetch {
+                request,
+                chunks,
+                thread,
+                generation: 0,
+                wanted: 0,
+                pins: NO_PINS,
+            }),
+        }
+    }
+}
+
+// Marks chunk `index` used last, if it's cached
+fn touch(cached: &mut VecDeque<(u64, Arc<Vec<f32>>)>, index: u64) -> Option<Arc<Vec<f32>>> {
+    let position = cached.iter().position(|(i, _)| *i == index)?;
+    let entry = cached.remove(position)?;
+    let chunk = entry.1.clone();
+    cached.push_back(entry);
+    Some(chunk)
+}
+
+impl Drop for Pcm {
+    fn drop(&mut self) {
+        let mut pcm_map = PCM_MAP.lock();
+        // A new decode of the same file may have taken the entry already
+        if pcm_map
+            .get(&self.hash)
+            .is_some_and(|pcm| pcm.strong_count() == 0)
+        {
+            pcm_map.remove(&self.hash);
+        }
+        if let Err(e) = fs::remove_file(&self.path) {
+            eprintln!("Failed to remove decoded audio {:?}: {}", self.path, e);
+        }
+    }
+}
+
+// Chunk `index` and the seek generation it was read for, None past the end
+struct Prefetched {
+    generation: u64,
+    index: u64,
+    chunk: Option<Arc<Vec<f32>>>,
+}
+
+// The chunk a stream is on, packed into one word so it's never read half
+// updated. A new generation means the stream jumped and the thread starts over
+struct PrefetchRequest {
+    packed: AtomicU64,
+    // First and last chunk to keep loaded, packed the same way, NO_PINS for none
+    pins: AtomicU64,
+    // The chunks under `pins` once they're read
+    pinned: Mutex<Vec<(u64, Arc<Vec<f32>>)>>,
+    stopped: AtomicBool,
+}
+
+const INDEX_BITS: u32 = 40;
+const NO_PINS: u64 = u64::MAX;
+
+impl PrefetchRequest {
+    fn set(&self, generation: u64, index: u64) {
+        self.packed
+            .store((generation << INDEX_BITS) | index, SeqCst);
+    }
+
+    fn get(&self) -> (u64, u64) {
+        let packed = self.packed.load(SeqCst);
+        (packed >> INDEX_BITS, packed & ((1 << INDEX_BITS) - 1))
+    }
+}
+
+// Reads chunks from the one the stream is on up to PREFETCH_CHUNKS past it. The
+// channel is only ever polled from the audio thread, so this thread sleeps
+// until the stream takes a chunk or moves
+fn prefetch(pcm: Arc<Pcm>, request: Arc<PrefetchRequest>, chunks: Sender<Prefetched>) {
+    let mut generation = None;
+    let mut next = 0;
+    let mut ready: Option<Prefetched> = None;
+    let mut pins = NO_PINS;
+
+    while !request.stopped.load(SeqCst) {
+        // Jump targets first, playback can land there at any moment
+        let wanted_pins = request.pins.load(SeqCst);
+        if wanted_pins != pins {
+            pins = wanted_pins;
+            let pinned = match pins {
+                NO_PINS => Vec::new(),
+                pins => (pins >> 32..=pins & u32::MAX as u64)
+                    .filter_map(|index| Some((index, pcm.chunk(index)?)))
+                    .collect(),
+            };
+            *request.pinned.lock() = pinned;
+        }
+
+        let (wanted_generation, wanted) = request.get();
+        if generation != Some(wanted_generation) {
+            generation = Some(wanted_generation);
+            next = wanted;
+            ready = None;
+        }
+        if ready.as_ref().is_some_and(|ready| ready.index < wanted) {
+            ready = None;
+        }
+        next = next.max(wanted);
+
+        let prefetched = match ready.take() {
+            Some(prefetched) => prefetched,
+            None if next > wanted + PREFETCH_CHUNKS as u64 => {
+                thread::park_timeout(PREFETCH_IDLE);
+                continue;
+            }
+            None => {
+                next += 1;
+                Prefetched {
+                    generation: wanted_generation,
+                    index: next - 1,
+                    chunk: pcm.chunk(next - 1),
+                }
+            }
+        };
+
+        match chunks.try_send(prefetched) {
+            Ok(()) => {}
+            Err(TrySendError::Full(prefetched)) => {
+                ready = Some(prefetched);
+                thread::park_timeout(PREFETCH_IDLE);
+            }
+            Err(TrySendError::Disconnected(_)) => return,
+        }
+    }
+}
+
+// The audio thread's end of a prefetch thread
+struct Prefetch {
+    request: Arc<PrefetchRequest>,
+    chunks: Receiver<Prefetched>,
+    thread: Thread,
+    generation: u64,
+    wanted: u64,
+    pins: u64,
+}
+
+impl Prefetch {
+    // Moving on to the next chunk keeps what's been read ahead, anything else
+    // starts the thread over
+    fn want(&mut self, index: u64) {
+        if index == self.wanted {
+            return;
+        }
+        if index != self.wanted + 1 {
+            self.generation = (self.generation + 1) % (1 << (64 - INDEX_BITS));
+        }
+        self.wanted = index;
+        self.request.set(self.generation, index);
+        self.thread.unpark();
+    }
+
+    // Keeps chunks `first` to `last` loaded whatever the stream is playing
+    fn pin(&mut self, first: u64, last: u64) {
+        let pins = (first << 32) | last;
+        if pins != self.pins {
+            self.pins = pins;
+            self.request.pins.store(pins, SeqCst);
+            self.thread.unpark();
+        }
+    }
+
+    // Chunk `index` if it's been read, Some(None) past the end of the file.
+    // Stale chunks are cleared out of the channel so the thread can go on
+    fn take(&mut self, index: u64) -> Option<Option<Arc<Vec<f32>>>> {
+        self.want(index);
+        while let Ok(prefetched) = self.chunks.try_recv() {
+            self.thread.unpark();
+            if prefetched.generation == self.generation && prefetched.index == index {
+                return Some(prefetched.chunk);
+            }
+        }
+
+        let pinned = self.request.pinned.try_lock()?;
+        let (_, chunk) = pinned.iter().find(|(i, _)| *i == index)?;
+        Some(Some(chunk.clone()))
+    }
+}
+
+impl Drop for Prefetch {
+    fn drop(&mut self) {
+        self.request.stopped.store(true, SeqCst);
+        self.thread.unpark();
+    }
+}
+
+// Plays a `Pcm` from any frame, seeking is exact and doesn't decode anything
+// Reading ahead of the decoder waits for it, unless the reader is a stream
+pub struct PcmReader {
+    pcm: Arc<Pcm>,
+    // Next sample, counted across channels
+    position: u64,
+    chunk: Option<(u64, Arc<Vec<f32>>)>,
+    prefetch: Option<Prefetch>,
+}
+
+impl PcmReader {
+    pub fn seek(&mut self, frame: u64) {
+        self.position = frame * self.pcm.channels as u64;
+        // Starts reading the new position before playback gets there
+        if let Some(prefetch) = &mut self.prefetch {
+            prefetch.want(frame / CHUNK_FRAMES);
+        }
+    }
+
+    // Keeps the audio from `start_frame` to `end_frame` in memory so a jump there
+    // plays straight away. Only streams read ahead, for other readers it does nothing
+    pub fn pin(&mut self, start_frame: u64, end_frame: u64) {
+        if let Some(prefetch) = &mut self.prefetch {
+            prefetch.pin(start_frame / CHUNK_FRAMES, end_frame / CHUNK_FRAMES);
+        }
+    }
+}
+
+impl Iterator for PcmReader {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let chunk_samples = CHUNK_FRAMES * self.pcm.channels as u64;
+        let index = self.position / chunk_samples;
+        if self.chunk.as_ref().map(|(i, _)| *i) != Some(index) {
+            self.chunk = None;
+            let chunk = match &mut self.prefetch {
+                Some(prefetch) => match prefetch
+                    .take(index)
+                    .or_else(|| self.pcm.try_cached_chunk(index).map(Some))
+                {
+                    Some(chunk) => chunk,
+                    // Not read in time, moving on keeps playback in step
+                    None => {
+                        self.position += 1;
+                        return Some(0.0);
+                    }
+                },
+                None => self.pcm.chunk(index),
+            };
+            self.chunk = Some((index, chunk?));
+        }
+
+        let (_, chunk) = self.chunk.as_ref()?;
+        let sample = *chunk.get((self.position % chunk_samples) as usize)?;
+        self.position += 1;
+        Some(sample)
+    }
+}
+
+impl Source for PcmReader {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.pcm.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.pcm.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        Some(Duration::from_secs_f64(
+            self.pcm.frames()? as f64 / self.pcm.sample_rate as f64,
+        ))
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.seek((pos.as_secs_f64() * self.pcm.sample_rate as f64) as u64);
+        Ok(())
+    }
+}
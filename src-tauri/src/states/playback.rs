@@ -1,37 +1,47 @@
 use derivative::Derivative;
 
-use parking_lot::{Mutex, RwLock};
+use parking_lot::{Condvar, Mutex, RwLock};
 
-use numpy::PyArray2;
-use pyo3::ffi::c_str;
-use pyo3::prelude::*;
+use anyhow::{anyhow, Result};
 
-use anyhow::{anyhow, Error, Result};
-
-use rodio::cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use rodio::cpal::{self, Device, Sample, Stream, SupportedStreamConfig};
+use rodio::cpal::traits::StreamTrait;
+use rodio::cpal::{Sample, SupportedStreamConfig};
 use rodio::dynamic_mixer::{self, DynamicMixer, DynamicMixerController};
-use rodio::queue::SourcesQueueOutput;
-use rodio::source::{self, Buffered, SamplesConverter};
-use rodio::{Decoder, OutputStream, OutputStreamHandle, Sink, Source, StreamError};
+use rodio::source::{self, Buffered};
+use rodio::{OutputStream, OutputStreamHandle, Source};
 
 use tauri::Manager;
-use usearch::{Index, IndexOptions, MetricKind, ScalarKind};
 
 use lazy_static::lazy_static;
 
 use serde::{Deserialize, Serialize, Serializer};
 
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::{self, BufReader, Cursor, Read, Seek};
-use std::path::{Path, PathBuf};
-use std::sync::{atomic::AtomicU64, atomic::Ordering::SeqCst, Arc};
+use std::collections::{HashMap, HashSet};
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::{
+    atomic::AtomicBool, atomic::AtomicU32, atomic::AtomicU64, atomic::Ordering::SeqCst, Arc,
+};
 use std::thread;
 use std::time::{Duration, Instant};
 
+use crate::error::AllomereError;
 use crate::handlers;
 use crate::states;
+use crate::states::beats;
+use crate::states::cache;
+use crate::states::features;
+use crate::states::library::{self, TransitionCandidate, TransitionScope};
+use crate::states::output::{
+    self, CpalBackend, MixerOutput, NullBackend, OutputBackend, SharedChannelRoute,
+};
+use crate::states::pcm::{Pcm, PcmReader};
+use crate::states::preview::{PreviewOutput, PreviewState};
+use crate::states::registry::{self, ClipId, TrackId};
+use crate::states::resample::{Resample, ResampleControls};
+use crate::states::stretch::{self, TimeStretch, TimeStretchControls};
+use crate::states::transport::Transport;
+use crate::states::waveform::WaveformPeaks;
 use std::env;
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -43,6 +53,7 @@ enum Value {
     Int32(i32),
     UInt32(u32),
     UInt16(u16),
+    Float32(f32),
     Boolean(bool),
     String(String),
     Array(Vec<Value>),
@@ -54,12 +65,21 @@ enum Value {
 //     pub source: source::Buffered<Decoder<BufReader<File>>>,
 // }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct CustomSourceController {
     loop_start: Option<bool>,
+    // Number of times the loop region plays, None loops forever
     loop_count: Option<u16>,
+    // Jumps back to loop_start_frame left before playback continues past the loop
+    loops_remaining: Option<u16>,
     loop_start_frame: Option<u32>,
     loop_end_frame: Option<u32>,
+    // Length of the equal-power crossfade at the loop seam, 0 jumps straight back
+    #[serde(default)]
+    loop_crossfade_frames: u32,
+    #[serde(default)]
+    loop_zero_crossing: bool,
 }
 
 impl CustomSourceController {
@@ -67,15 +87,27 @@ impl CustomSourceController {
         CustomSourceController {
             loop_start: None,
             loop_count: None,
+            loops_remaining: None,
             loop_start_frame: None,
             loop_end_frame: None,
+            loop_crossfade_frames: 0,
+            loop_zero_crossing: false,
         }
     }
 
+    pub fn set_loop_crossfade(&mut self, frames: u32) {
+        self.loop_crossfade_frames = frames;
+    }
+
+    pub fn set_loop_zero_crossing(&mut self, enabled: bool) {
+        self.loop_zero_crossing = enabled;
+    }
+
     pub fn set_loop(&mut self, start_frame: u32, end_frame: u32) {
         self.loop_start.replace(true);
         self.loop_start_frame.replace(start_frame);
         self.loop_end_frame.replace(end_frame);
+        self.set_loop_count(None);
     }
 
     pub fn clear_loop(&mut self) {
@@ -86,88 +118,494 @@ impl CustomSourceController {
         self.loop_start.replace(true);
         self.loop_start_frame.replace(start_sample);
         self.loop_end_frame.replace(end_sample);
-        self.loop_count.replace(loop_count);
+        self.set_loop_count(Some(loop_count));
+    }
+
+    pub fn set_loop_count(&mut self, loop_count: Option<u16>) {
+        self.loop_count = loop_count;
+        self.reset_loop_count();
+    }
+
+    // The pass playing when the count is set counts as the first one
+    pub fn reset_loop_count(&mut self) {
+        self.loops_remaining = self
+            .loop_count
+            .map(|loop_count| loop_count.saturating_sub(1));
+    }
+
+    // Source frames the loop plays again after the first pass. 0 unless it's counted
+    // and playback reaches its end before `trim_end_frame`
+    pub fn repeated_frames(&self, trim_end_frame: u32) -> u64 {
+        match (
+            self.loop_start,
+            self.loop_count,
+            self.loop_start_frame,
+            self.loop_end_frame,
+        ) {
+            (Some(true), Some(loop_count), Some(start), Some(end))
+                if start < end && end <= trim_end_frame =>
+            {
+                loop_count.saturating_sub(1) as u64 * (end - start) as u64
+            }
+            _ => 0,
+        }
+    }
+
+    pub fn loops_remaining(&self) -> Option<u16> {
+        self.loops_remaining
+    }
+
+    // Counts down finite loops, returns whether playback should jump back to the loop start
+    pub fn take_loop(&mut self) -> bool {
+        match self.loops_remaining {
+            Some(0) => false,
+            Some(ref mut loops_remaining) => {
+                *loops_remaining -= 1;
+                true
+            }
+            None => true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum CrossfadeLength {
+    Milliseconds(f64),
+    Beats(f64),
+}
+
+// The loop tail read ahead of the seam, faded out while the audio leading
+// into loop_start_frame is faded in, so the loop keeps its exact length
+// The tail buffer is kept from one seam to the next and reserved when the
+// crossfade length is set, so a seam never allocates on the audio thread
+#[derive(Default)]
+pub struct LoopCrossfade {
+    tail: Vec<f32>,
+    position: usize,
+    frames: u32,
+    channels: u32,
+    loop_start_frame: u32,
+    active: bool,
+}
+
+impl LoopCrossfade {
+    fn reserve(&mut self, samples: usize) {
+        self.tail.reserve(samples.saturating_sub(self.tail.len()));
+    }
+
+    // Longest crossfade the reserved tail holds
+    fn capacity_frames(&self, channels: u32) -> u32 {
+        self.tail.capacity() as u32 / channels
+    }
+
+    // Reads the outgoing `frames` from `source`, which is then moved to the incoming audio
+    fn start<I: Iterator<Item = f32>>(
+        &mut self,
+        source: &mut I,
+        frames: u32,
+        channels: u32,
+        loop_start_frame: u32,
+    ) {
+        self.tail.clear();
+        self.tail
+            .extend((0..frames * channels).map_while(|_| source.next()));
+        self.position = 0;
+        self.frames = frames;
+        self.channels = channels;
+        self.loop_start_frame = loop_start_frame;
+        self.active = true;
+    }
+
+    fn next<I: Iterator<Item = f32>>(&mut self, source: &mut I) -> Option<f32> {
+        if !self.active {
+            return None;
+        }
+        let outgoing = *self.tail.get(self.position)?;
+        let incoming = source.next().unwrap_or(0f32);
+        let frame = (self.position as u32 / self.channels) as f32;
+        let t = (frame + 0.5) / (self.frames as f32) * std::f32::consts::FRAC_PI_2;
+        self.position += 1;
+        Some(outgoing * t.cos() + incoming * t.sin())
+    }
+}
+
+// A breakpoint of a clip's gain envelope, the gain is linear and ramps linearly
+// from one point to the next
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GainPoint {
+    pub frame: u32,
+    pub gain: f32,
+}
+
+// The part of the source a clip plays and how loud, all in source frames
+// Fades are linear and run from the trim in point and up to the trim out point
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ClipEdits {
+    pub trim_start_frame: u32,
+    // None plays on to the end of the file
+    pub trim_end_frame: Option<u32>,
+    pub fade_in_frames: u32,
+    pub fade_out_frames: u32,
+    // Ordered by frame, the first and last gains hold before and after them
+    pub gain_envelope: Vec<GainPoint>,
+}
+
+impl ClipEdits {
+    fn envelope_gain(&self, frame: u32) -> f32 {
+        let next = self
+            .gain_envelope
+            .partition_point(|point| point.frame <= frame);
+        match (
+            next.checked_sub(1)
+                .map(|previous| self.gain_envelope[previous]),
+            self.gain_envelope.get(next).copied(),
+        ) {
+            (Some(previous), Some(next)) => {
+                let t = ((frame - previous.frame) as f32) / ((next.frame - previous.frame) as f32);
+                previous.gain + (next.gain - previous.gain) * t
+            }
+            (Some(point), None) | (None, Some(point)) => point.gain,
+            (None, None) => 1.0,
+        }
+    }
+
+    // Gain for the samples of `frame`, `end_frame` being where the clip stops
+    fn gain(&self, frame: u32, end_frame: u32) -> f32 {
+        let mut gain = self.envelope_gain(frame);
+        if self.fade_in_frames > 0 {
+            let faded_in = (frame.saturating_sub(self.trim_start_frame) as f32) + 0.5;
+            gain *= (faded_in / (self.fade_in_frames as f32)).min(1.0);
+        }
+        if self.fade_out_frames > 0 {
+            let remaining = (end_frame.saturating_sub(frame) as f32) - 0.5;
+            gain *= (remaining / (self.fade_out_frames as f32)).clamp(0.0, 1.0);
+        }
+        gain
+    }
+}
+
+pub fn duration_to_frame(duration: Duration, sample_rate: u32) -> u64 {
+    (duration.as_secs_f64() * (sample_rate as f64)).round() as u64
+}
+
+// A frame on the project timeline, at the playback sample rate. Clip starts,
+// the playhead and seeks are counted in these
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TimelineFrame(pub u64);
+
+// A frame of a clip's file, at the file's own sample rate. Trims, fades, loops
+// and beats are counted in these
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SourceFrame(pub u64);
+
+// Converts between a clip's source frames and timeline frames. Source frames are
+// resampled to the timeline rate first, then stretched to the project tempo
+#[derive(Clone, Copy, Debug)]
+pub struct FrameMap {
+    source_rate: u32,
+    timeline_rate: u32,
+    playback_rate: f32,
+}
+
+impl FrameMap {
+    pub fn new(source_rate: u32, timeline_rate: u32, playback_rate: f32) -> Self {
+        FrameMap {
+            source_rate: source_rate.max(1),
+            timeline_rate: timeline_rate.max(1),
+            playback_rate,
+        }
+    }
+
+    // Same threshold TimeStretch passes samples through below, the mapping is
+    // exact integer arithmetic then
+    fn stretched(&self) -> bool {
+        (self.playback_rate - 1.0).abs() >= 1e-3
+    }
+
+    // Timeline frames `frames` of the source take up, to the nearest frame
+    pub fn to_timeline(&self, frames: SourceFrame) -> TimelineFrame {
+        let source_rate = self.source_rate as u128;
+        let resampled = frames.0 as u128 * self.timeline_rate as u128;
+        if self.stretched() {
+            TimelineFrame(
+                ((resampled as f64) / (source_rate as f64) / (self.playback_rate as f64)).round()
+                    as u64,
+            )
+        } else {
+            TimelineFrame(((resampled + source_rate / 2) / source_rate) as u64)
+        }
+    }
+
+    // Where `frames` into the timeline falls in the source: the frame at or
+    // before it and how far past that frame, out of the timeline rate
+    pub fn to_source_position(&self, frames: TimelineFrame) -> (SourceFrame, u32) {
+        let resampled = if self.stretched() {
+            ((frames.0 as f64) * (self.playback_rate as f64)).round() as u64
+        } else {
+            frames.0
+        };
+        let timeline_rate = self.timeline_rate as u128;
+        let source = resampled as u128 * self.source_rate as u128;
+        (
+            SourceFrame((source / timeline_rate) as u64),
+            (source % timeline_rate) as u32,
+        )
+    }
+
+    // Source frame nearest to `frames` into the timeline
+    pub fn to_source(&self, frames: TimelineFrame) -> SourceFrame {
+        let (frame, offset) = self.to_source_position(frames);
+        SourceFrame(frame.0 + ((offset as u64) * 2 >= self.timeline_rate as u64) as u64)
+    }
+}
+
+// A clip's edits, shared between the clip and the sources playing it. Every change
+// swaps in a new snapshot and bumps the version, so the audio thread only locks
+// when the snapshot it holds is stale
+#[derive(Default)]
+pub struct SharedEdits {
+    current: Mutex<Arc<ClipEdits>>,
+    version: AtomicU64,
+}
+
+impl SharedEdits {
+    pub fn get(&self) -> Arc<ClipEdits> {
+        self.current.lock().clone()
+    }
+
+    pub fn set(&self, edits: ClipEdits) {
+        *self.current.lock() = Arc::new(edits);
+        self.version.fetch_add(1, SeqCst);
+    }
+
+    pub fn update(&self, f: impl FnOnce(&mut ClipEdits)) {
+        let mut current = self.current.lock();
+        let mut edits = (**current).clone();
+        f(&mut edits);
+        *current = Arc::new(edits);
+        self.version.fetch_add(1, SeqCst);
     }
 }
 
 #[derive(Clone)]
-pub struct CustomSource<R>
-where
-    R: Read + Seek,
-{
-    pub current_sample: u32,
+pub struct CustomSource {
+    // Shared between clones so the copy in the sink and the one on the clip agree
+    pub current_sample: Arc<AtomicU64>,
+    pub is_finished: Arc<AtomicBool>,
+    pub crossfade: Arc<Mutex<LoopCrossfade>>,
     pub channels: u16,
-    pub raw_source: Arc<Mutex<source::TrackPosition<SamplesConverter<Decoder<R>, f32>>>>,
+    pub raw_source: Arc<Mutex<PcmReader>>,
     pub controller: Arc<Mutex<CustomSourceController>>,
+    pub edits: Arc<SharedEdits>,
+    // This copy's snapshot of `edits` and the version it was taken at
+    edits_snapshot: (u64, Arc<ClipEdits>),
+    sound: Sound,
 }
 
-impl<R: Read + Seek> CustomSource<R> {
+impl CustomSource {
     // type Item = f32;
 
-    pub fn new(raw_source: Decoder<R>) -> (CustomSource<R>, Arc<Mutex<CustomSourceController>>) {
-        // let test = raw_source.track_position().convert_samples::<f32>();
+    // `reader` is the sound's stream when the source plays in real time
+    pub fn new(
+        sound: &Sound,
+        reader: PcmReader,
+    ) -> (CustomSource, Arc<Mutex<CustomSourceController>>) {
         let controller = Arc::new(Mutex::new(CustomSourceController::new()));
         (
             CustomSource {
-                current_sample: 0,
-                channels: raw_source.channels(),
-                raw_source: Arc::new(Mutex::new(
-                    raw_source.convert_samples::<f32>().track_position(),
-                )),
+                current_sample: Arc::new(AtomicU64::new(0)),
+                is_finished: Arc::new(AtomicBool::new(false)),
+                crossfade: Arc::new(Mutex::new(LoopCrossfade::default())),
+                channels: sound.channels(),
+                raw_source: Arc::new(Mutex::new(reader)),
                 controller: controller.clone(),
+                edits: Arc::new(SharedEdits::default()),
+                edits_snapshot: (0, Arc::new(ClipEdits::default())),
+                sound: sound.clone(),
             },
             controller,
         )
     }
+
+    // Length of the whole file, None when the decoder can't tell before decoding it
+    pub fn source_frames(&self) -> Option<u32> {
+        self.sound.frames().map(|frames| frames as u32)
+    }
+
+    // Source frames the clip plays, from the trim in point up to the trim out point
+    pub fn trim_range(&self) -> (u32, u32) {
+        self.trim_range_of(&self.edits.get())
+    }
+
+    fn trim_range_of(&self, edits: &ClipEdits) -> (u32, u32) {
+        let source_frames = self.source_frames();
+        let end_frame = match (edits.trim_end_frame, source_frames) {
+            (Some(trim_end_frame), Some(source_frames)) => trim_end_frame.min(source_frames),
+            (trim_end_frame, source_frames) => trim_end_frame.or(source_frames).unwrap_or(u32::MAX),
+        };
+        (edits.trim_start_frame.min(end_frame), end_frame)
+    }
+
+    pub fn current_frame(&self) -> u64 {
+        self.current_sample.load(SeqCst) / self.channels as u64
+    }
+
+    // Seeks the decoded audio itself, so it works whether or not the stream is running
+    // Returns the frame playback will resume from
+    pub fn seek_frame(&self, frame: u64) -> Result<u64, source::SeekError> {
+        let frame = match self.source_frames() {
+            Some(source_frames) => frame.min(source_frames as u64),
+            None => frame,
+        };
+        self.raw_source.lock().seek(frame);
+
+        self.current_sample
+            .store(frame * self.channels as u64, SeqCst);
+        self.is_finished.store(false, SeqCst);
+        self.crossfade.lock().active = false;
+
+        Ok(frame)
+    }
+
+    // Makes the source end on its next sample, letting the sink move to the next one
+    pub fn finish(&self) {
+        self.is_finished.store(true, SeqCst);
+    }
+
+    // Room for a loop crossfade of `frames`, made before a seam needs it
+    pub fn reserve_crossfade(&self, frames: u32) {
+        self.crossfade
+            .lock()
+            .reserve((frames * self.channels as u32) as usize);
+    }
 }
 
-impl<R: Read + Seek> Iterator for CustomSource<R> {
-    type Item = f32;
+impl CustomSource {
+    // Takes a new snapshot of the edits once they've changed
+    fn refresh_edits(&mut self) {
+        let version = self.edits.version.load(SeqCst);
+        if version != self.edits_snapshot.0 {
+            self.edits_snapshot = (version, self.edits.get());
+        }
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if {
+    // The next sample before the clip's gain is applied
+    fn next_sample(&mut self, trim_start_frame: u32, trim_end_frame: u32) -> Option<f32> {
+        if self.is_finished.load(SeqCst) {
+            return None;
+        }
+
+        // Fresh and rewound sources start from the trim in point
+        if self.current_frame() < trim_start_frame as u64 {
+            if let Err(e) = self.seek_frame(trim_start_frame as u64) {
+                eprintln!("Failed to seek to the trim start: {:?}", e);
+            }
+        }
+
+        let raw_source = self.raw_source.clone();
+        let mut source = raw_source.lock();
+        let channels = self.channels as u32;
+        // Samples are counted in u64, a late frame times the channels doesn't fit a u32
+        let to_sample = |frame: u32| frame as u64 * channels as u64;
+
+        let crossfade_ref = self.crossfade.clone();
+        let mut crossfade = crossfade_ref.lock();
+
+        if crossfade.active {
+            if let Some(sample) = crossfade.next(&mut *source) {
+                self.current_sample.fetch_add(1, SeqCst);
+                return Some(sample);
+            }
+            // The incoming audio has caught up with loop_start_frame
+            self.current_sample
+                .store(to_sample(crossfade.loop_start_frame), SeqCst);
+            crossfade.active = false;
+        }
+
+        let loop_frames = {
             let controller = self.controller.lock();
-            controller.loop_start.unwrap_or(false) && controller.loop_end_frame.is_some()
-        } {
-            let (loop_start_frame, loop_end_frame) = {
-                let controller = self.controller.lock();
-                (
-                    controller.loop_start_frame.unwrap(),
-                    controller.loop_end_frame.unwrap(),
-                )
-            };
-            if self.current_sample % self.channels as u32 == (self.channels - 1) as u32
-                && (self.current_sample / self.channels as u32) + 1 == loop_end_frame
-            {
-                // seek
-                // (source.sample_rate() as f64) * self.controller.lock().loop_end_frame.unwrap()
-                let raw_source = self.raw_source.clone();
-                let mut source = raw_source.lock();
+            if controller.loop_start.unwrap_or(false) {
+                controller
+                    .loop_start_frame
+                    .zip(controller.loop_end_frame)
+                    .map(|(start, end)| (start, end, controller.loop_crossfade_frames))
+            } else {
+                None
+            }
+        };
 
-                let sample_rate = source.sample_rate();
+        if let Some((loop_start_frame, loop_end_frame, crossfade_frames)) = loop_frames {
+            let current_sample = self.current_sample.load(SeqCst);
+            // The fade needs audio before loop_start_frame, can't outlast the loop and
+            // can't grow the tail reserved for it
+            let crossfade_frames = crossfade_frames
+                .min(loop_start_frame)
+                .min(loop_end_frame.saturating_sub(loop_start_frame))
+                .min(crossfade.capacity_frames(channels));
+            // Where the seam jumps to, kept loaded so it never plays as silence
+            source.pin(
+                (loop_start_frame - crossfade_frames) as u64,
+                loop_start_frame as u64,
+            );
 
-                println!("Looped");
+            if crossfade_frames > 0
+                && current_sample == to_sample(loop_end_frame - crossfade_frames)
+                && self.controller.lock().take_loop()
+            {
+                crossfade.start(&mut *source, crossfade_frames, channels, loop_start_frame);
+                source.seek((loop_start_frame - crossfade_frames) as u64);
 
-                if let Err(e) = source.try_seek(Duration::from_secs_f64(
-                    (loop_start_frame as f64) / (sample_rate as f64),
-                )) {
-                    eprintln!("Failed to seek: {:?}", e);
+                if let Some(sample) = crossfade.next(&mut *source) {
+                    self.current_sample.fetch_add(1, SeqCst);
+                    return Some(sample);
                 }
-                println!("{} - current sample", self.current_sample);
-                println!(
-                    "{} - Seeked to sample",
-                    source.get_pos().as_secs_f64() * (sample_rate as f64)
-                );
-                self.current_sample = (loop_start_frame * (self.channels as u32)) - 1
+                crossfade.active = false;
+            }
+            // Every sample of the frames before loop_end_frame has been played
+            else if current_sample == to_sample(loop_end_frame)
+                && self.controller.lock().take_loop()
+            {
+                source.seek(loop_start_frame as u64);
+                self.current_sample
+                    .store(to_sample(loop_start_frame), SeqCst);
             }
         }
-        self.current_sample += 1;
-        self.raw_source.clone().lock().next()
-        // Some(0.0)
+
+        if self.current_sample.load(SeqCst) >= to_sample(trim_end_frame) {
+            self.is_finished.store(true, SeqCst);
+            return None;
+        }
+
+        match source.next() {
+            Some(sample) => {
+                self.current_sample.fetch_add(1, SeqCst);
+                Some(sample)
+            }
+            None => {
+                self.is_finished.store(true, SeqCst);
+                None
+            }
+        }
+    }
+}
+
+impl Iterator for CustomSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.refresh_edits();
+        let (start_frame, end_frame) = self.trim_range_of(&self.edits_snapshot.1);
+        let sample = self.next_sample(start_frame, end_frame)?;
+        let frame =
+            (self.current_sample.load(SeqCst).saturating_sub(1) / self.channels as u64) as u32;
+        Some(sample * self.edits_snapshot.1.gain(frame, end_frame))
     }
 }
 
-impl<R: Read + Seek> Source for CustomSource<R> {
+impl Source for CustomSource {
     fn current_frame_len(&self) -> Option<usize> {
         self.raw_source.clone().lock().current_frame_len()
     }
@@ -180,27 +618,32 @@ impl<R: Read + Seek> Source for CustomSource<R> {
         self.raw_source.clone().lock().sample_rate()
     }
 
+    // Only the trimmed part plays
     fn total_duration(&self) -> Option<Duration> {
-        self.raw_source.clone().lock().total_duration()
+        self.source_frames()?;
+        let (start_frame, end_frame) = self.trim_range();
+        Some(Duration::from_secs_f64(
+            ((end_frame - start_frame) as f64) / (self.sample_rate() as f64),
+        ))
     }
 
+    // Positions count from the trim in point
     fn try_seek(&mut self, pos: Duration) -> Result<(), source::SeekError> {
-        self.current_sample =
-            (pos.as_secs_f64() * (self.sample_rate() as f64) * (self.channels as f64)) as u32;
-        self.raw_source.clone().lock().try_seek(pos)
+        let (start_frame, _) = self.trim_range();
+        self.seek_frame(start_frame as u64 + duration_to_frame(pos, self.sample_rate()))?;
+        Ok(())
     }
 }
 
 #[derive(Clone)]
-pub struct Audio<R>
-where
-    R: Read + Seek,
-{
-    pub source: CustomSource<R>,
+pub struct Audio {
+    pub source: CustomSource,
     pub controller: Option<Arc<Mutex<CustomSourceController>>>,
+    pub stretch: Arc<TimeStretchControls>,
+    pub resample: Arc<ResampleControls>,
 }
 
-impl<R: Read + Seek> Serialize for Audio<R> {
+impl Serialize for Audio {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
@@ -210,14 +653,10 @@ impl<R: Read + Seek> Serialize for Audio<R> {
             data: HashMap<String, Value>,
         }
         let mut helper = HashMap::new();
-        helper.insert(
-            "length",
-            Value::UInt64(
-                (self.source.total_duration().unwrap().as_secs_f64()
-                    * (self.source.sample_rate() as f64)) as u64,
-            ),
-        );
+        helper.insert("length", Value::UInt64(self.total_frames()));
         helper.insert("sampleRate", Value::UInt32(self.source.sample_rate()));
+        helper.insert("currentFrame", Value::UInt64(self.source.current_frame()));
+        helper.insert("playbackRate", Value::Float32(self.stretch.rate()));
 
         let controller = self.source.controller.lock();
         println!("{:#?}", *controller);
@@ -230,67 +669,239 @@ impl<R: Read + Seek> Serialize for Audio<R> {
         if let Some(loop_count) = controller.loop_count {
             helper.insert("loopCount", Value::UInt16(loop_count));
         }
+        if let Some(loops_remaining) = controller.loops_remaining {
+            helper.insert("loopsRemaining", Value::UInt16(loops_remaining));
+        }
         if let Some(loop_start_frame) = controller.loop_start_frame {
             helper.insert("loopStartFrame", Value::UInt32(loop_start_frame));
         }
         if let Some(loop_end_frame) = controller.loop_end_frame {
             helper.insert("loopEndFrame", Value::UInt32(loop_end_frame));
         }
+        helper.insert(
+            "loopCrossfadeFrames",
+            Value::UInt32(controller.loop_crossfade_frames),
+        );
+        helper.insert(
+            "loopZeroCrossing",
+            Value::Boolean(controller.loop_zero_crossing),
+        );
         // }
+        drop(controller);
+
+        let edits = self.source.edits.get();
+        helper.insert("trimStartFrame", Value::UInt32(edits.trim_start_frame));
+        if let Some(trim_end_frame) = edits.trim_end_frame {
+            helper.insert("trimEndFrame", Value::UInt32(trim_end_frame));
+        }
+        helper.insert("fadeInFrames", Value::UInt32(edits.fade_in_frames));
+        helper.insert("fadeOutFrames", Value::UInt32(edits.fade_out_frames));
+        helper.insert(
+            "gainEnvelope",
+            Value::Array(
+                edits
+                    .gain_envelope
+                    .iter()
+                    .map(|point| {
+                        Value::Object(HashMap::from([
+                            ("frame".to_string(), Value::UInt32(point.frame)),
+                            ("gain".to_string(), Value::Float32(point.gain)),
+                        ]))
+                    })
+                    .collect(),
+            ),
+        );
 
         helper.serialize(serializer)
     }
 }
 
-impl<R: Read + Seek> Audio<R> {
+impl Audio {
+    // Frames between the trim points
     pub fn total_frames(&self) -> u64 {
-        (self.source.total_duration().unwrap().as_secs_f64() * (self.source.sample_rate() as f64))
-            as u64
+        let (start_frame, end_frame) = self.source.trim_range();
+        (end_frame - start_frame) as u64
     }
 }
 
-#[derive(Clone)]
-pub struct Sound(Arc<Vec<u8>>);
+// Beat windows sliced and embedded together during analysis, about 11 MB of
+// stereo at 44.1 kHz
+const BEAT_WINDOW_BATCH: usize = 16;
 
-impl AsRef<[u8]> for Sound {
-    fn as_ref(&self) -> &[u8] {
-        &self.0
-    }
+// Extensions the Open File dialog offers and `Sound::load` accepts
+pub const SUPPORTED_EXTENSIONS: &[&str] = &[
+    "wav", "mp3", "flac", "ogg", "oga", "opus", "m4a", "mp4", "aac", "aif", "aiff", "aifc",
+];
+
+pub fn is_supported(path: &Path) -> bool {
+    path.extension()
+        .map(|extension| extension.to_string_lossy().to_ascii_lowercase())
+        .is_some_and(|extension| SUPPORTED_EXTENSIONS.contains(&extension.as_str()))
 }
 
+// A file's decoded audio, clones share the same `Pcm`
+#[derive(Clone)]
+pub struct Sound(Arc<Pcm>);
+
 impl Sound {
-    pub fn load(filename: &str) -> io::Result<Sound> {
-        let mut buf = Vec::new();
-        let mut file = File::open(filename)?;
-        file.read_to_end(&mut buf)?;
-        Ok(Sound(Arc::new(buf)))
+    // Probes the decoder once so files rodio can't read are refused here
+    // instead of failing during playback later
+    pub fn load(filename: &str) -> Result<Sound, AllomereError> {
+        if !is_supported(Path::new(filename)) {
+            return Err(AllomereError::UnsupportedFormat(format!(
+                "{:?}, supported formats are {}",
+                filename,
+                SUPPORTED_EXTENSIONS.join(", ")
+            )));
+        }
+
+        Ok(Sound(Pcm::open(Path::new(filename))?))
+    }
+
+    // Content hash of the file, see `cache::audio_hash`
+    pub fn hash(&self) -> &str {
+        self.0.hash()
+    }
+
+    pub fn channels(&self) -> u16 {
+        self.0.channels()
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.0.sample_rate()
+    }
+
+    pub fn frames(&self) -> Option<u64> {
+        self.0.frames()
+    }
+
+    pub fn reader(&self) -> PcmReader {
+        self.0.reader()
+    }
+
+    pub fn stream(&self) -> PcmReader {
+        self.0.stream()
     }
-    pub fn cursor(self: &Self) -> Cursor<Sound> {
-        Cursor::new(Sound(self.0.clone()))
+
+    pub fn slice(&self, start_frame: i64, samples: usize) -> Vec<f32> {
+        self.0.slice(start_frame, samples)
     }
-    pub fn decoder(self: &Self) -> Decoder<Cursor<Sound>> {
-        Decoder::new(self.cursor()).unwrap()
+
+    // Closest frame to `frame`, within `window_frames` either side, where the
+    // channel-summed signal changes sign
+    pub fn nearest_zero_crossing(self: &Self, frame: u32, window_frames: u32) -> u32 {
+        let channels = self.channels() as usize;
+        let start_frame = frame.saturating_sub(window_frames);
+
+        let mut reader = self.reader();
+        reader.seek(start_frame as u64);
+        let window: Vec<f32> = reader
+            .take(((frame - start_frame + window_frames + 1) as usize) * channels)
+            .collect::<Vec<f32>>()
+            .chunks(channels)
+            .map(|samples| samples.iter().sum())
+            .collect();
+
+        let target = (frame - start_frame) as usize;
+        (1..window.len())
+            .filter(|&i| window[i] == 0.0 || window[i - 1].signum() != window[i].signum())
+            .min_by_key(|&i| i.abs_diff(target))
+            .map(|i| start_frame + i as u32)
+            .unwrap_or(frame)
     }
 }
 
 #[derive(Clone)]
 pub struct AudioData {
     path: String,
+    // Hash of the file bytes, the song's key in the analysis cache and the library
+    audio_hash: String,
+    tempo: Option<f32>,
     beat_track: Option<Vec<u32>>,
     beat_features: Arc<Mutex<Option<Vec<Vec<f32>>>>>,
+    analysis: Arc<AnalysisStatus>,
     sound: Sound,
+    waveform: Option<Arc<WaveformPeaks>>,
+}
+
+// Whether a file's analysis has finished, for anything that needs its results
+// to wait on instead of racing the analysis task for the features lock
+#[derive(Default)]
+pub struct AnalysisStatus {
+    // None while analysis runs, then whether it produced beats and features
+    analysed: Mutex<Option<bool>>,
+    finished: Condvar,
+}
+
+impl AnalysisStatus {
+    fn start(&self) {
+        *self.analysed.lock() = None;
+    }
+
+    fn finish(&self, analysed: bool) {
+        *self.analysed.lock() = Some(analysed);
+        self.finished.notify_all();
+    }
+
+    // Blocks until analysis finishes, true if it succeeded
+    pub fn wait(&self) -> bool {
+        let mut analysed = self.analysed.lock();
+        loop {
+            if let Some(analysed) = *analysed {
+                return analysed;
+            }
+            self.finished.wait(&mut analysed);
+        }
+    }
 }
 
 impl AudioData {
-    pub fn new(path: &str) -> Self {
+    pub fn beat_track(&self) -> Option<&[u32]> {
+        self.beat_track.as_deref()
+    }
+
+    // Median distance between beats, in frames
+    pub fn beat_interval(&self) -> Option<f64> {
+        let beat_track = self.beat_track.as_ref()?;
+        let mut intervals: Vec<u32> = beat_track
+            .windows(2)
+            .map(|beats| beats[1].saturating_sub(beats[0]))
+            .collect();
+        if intervals.is_empty() {
+            return None;
+        }
+        intervals.sort_unstable();
+        Some(intervals[intervals.len() / 2] as f64)
+    }
+
+    pub fn new(path: &str) -> Result<Self, AllomereError> {
         // let get_beats_path = path.clone();
 
-        AudioData {
-            sound: Sound::load(path).unwrap(),
+        let sound = Sound::load(path)?;
+        Ok(AudioData {
+            audio_hash: sound.hash().to_string(),
+            sound,
             path: path.to_string(),
+            tempo: None,
             beat_track: None,
             beat_features: Arc::new(Mutex::new(None)),
-        }
+            analysis: Arc::new(AnalysisStatus::default()),
+            waveform: None,
+        })
+    }
+
+    // Built on first use and kept, without holding the lock while it's built
+    pub fn waveform(audio_data: &Mutex<AudioData>) -> Arc<WaveformPeaks> {
+        let sound = {
+            let audio_data = audio_data.lock();
+            if let Some(waveform) = &audio_data.waveform {
+                return waveform.clone();
+            }
+            audio_data.sound.clone()
+        };
+
+        let waveform = Arc::new(WaveformPeaks::new(&sound));
+        audio_data.lock().waveform.get_or_insert(waveform).clone()
     }
 }
 
@@ -305,6 +916,10 @@ impl Serialize for AudioData {
         }
         let mut helper = HashMap::new();
         helper.insert("path", Value::String(self.path.clone()));
+        helper.insert("audioHash", Value::String(self.audio_hash.clone()));
+        if let Some(tempo) = self.tempo {
+            helper.insert("tempo", Value::Float32(tempo));
+        }
         if let Some(beat_track) = &self.beat_track {
             helper.insert(
                 "beatTrack",
@@ -319,10 +934,6 @@ impl Serialize for AudioData {
 lazy_static! {
     pub static ref AUDIO_DATA_MAP: Mutex<HashMap<String, Arc<Mutex<AudioData>>>> =
         Mutex::new(HashMap::new());
-    static ref BEATS_MODULE: Mutex<Option<Py<PyModule>>> = Mutex::new(None);
-    static ref FEATURES_MODULE: Mutex<Option<Py<PyModule>>> = Mutex::new(None);
-    static ref CLIP_MAP: Mutex<HashMap<u32, Arc<Mutex<Clip>>>> = Mutex::new(HashMap::new());
-    static ref CLIP_ID: Mutex<usize> = Mutex::new(0);
 }
 
 #[derive(Derivative, Serialize, Deserialize)]
@@ -333,14 +944,14 @@ pub struct Clip {
     pub name: String,
     #[serde(skip_deserializing)]
     #[derivative(Debug = "ignore")]
-    pub audio: Option<Audio<Cursor<Sound>>>,
+    pub audio: Option<Audio>,
 
     // #[serde(skip_deserializing)]
     // #[serde(skip_serializing)]
     // #[derivative(Debug = "ignore")]
     // pub beat_track: Option<Vec<u64>>,
     pub start_at: Option<u64>,
-    pub id: usize,
+    pub id: ClipId,
 }
 
 // impl Serialize for Clip {
@@ -362,371 +973,1117 @@ pub struct Clip {
 //     }
 // }
 
-impl Clip {
-    pub fn new(path: &str) -> Self {
-        let audio_data = {
-            let mut audio_data_map = AUDIO_DATA_MAP.lock(); // Lock the mutex here
-            audio_data_map
-                .entry(path.to_string())
-                .or_insert_with(|| Arc::new(Mutex::new(AudioData::new(path))))
-                .clone()
-        };
-        // AudioDataMap.insert(path.clone(), AudioData::new(path.clone()));
-
-        // let get_beats_path = path.clone();
-        let audio_data_ref = audio_data.clone();
+// Analysis runs in the background, so failures reach the frontend as the clip's state
+fn emit_analysis_failed(file_name: &str, error: &AllomereError) {
+    eprintln!("Analysis of {} failed: {}", file_name, error);
+    if let Some(app_handle) = states::APP_HANDLE.lock().as_ref() {
+        let _ = states::emit_state_sync_handle(
+            format!("clip.\"{}\".state", file_name).as_str(),
+            "failed",
+            app_handle,
+        );
+        let _ = states::emit_state_sync_handle(
+            format!("clip.\"{}\".error", file_name).as_str(),
+            error,
+            app_handle,
+        );
+    }
+}
 
-        tauri::async_runtime::spawn(async move {
-            let (path, beat_track_exists) = {
-                let audio_data_guard = audio_data_ref.lock(); // Lock the mutex here
+// The clip still plays, only its transitions are missing, so it goes out on the
+// "error" key rather than failing the analysis
+fn emit_library_error(file_name: &str, error: &AllomereError) {
+    eprintln!("Failed to add {} to the library: {}", file_name, error);
+    if let Some(app_handle) = states::APP_HANDLE.lock().as_ref() {
+        let _ = states::emit_state_sync_handle("error", error, app_handle);
+    }
+}
 
-                (
-                    audio_data_guard.path.clone(),
-                    audio_data_guard.beat_track.is_some(),
-                )
-            };
+// Tracks beats and extracts beat features, from the analysis cache when it has them.
+// `reanalyse` drops the results there are, once an analysis still running is done
+fn analyse(audio_data_ref: &Arc<Mutex<AudioData>>, reanalyse: bool) {
+    let path = audio_data_ref.lock().path.clone();
+
+    let beat_features = {
+        let audio_data_guard = audio_data_ref.lock(); // Lock the mutex again to access beat_features
+        audio_data_guard.beat_features.clone()
+    };
+    let mut beat_features_guard = beat_features.lock();
+
+    let beat_track_exists = {
+        let mut audio_data = audio_data_ref.lock();
+        if reanalyse {
+            audio_data.tempo = None;
+            audio_data.beat_track = None;
+            *beat_features_guard = None;
+        }
+        audio_data.beat_track.is_some()
+    };
+
+    let now = Instant::now();
+    let path_clone = path.clone();
+    let file_name = Path::new(&path_clone)
+        .file_stem()
+        .expect("File name should exist")
+        .to_string_lossy();
+
+    let audio_hash = audio_data_ref.lock().audio_hash.clone();
+    let cache_key = cache::cache_key(&audio_hash);
+
+    if !beat_track_exists {
+        if let Some(entry) = cache::load(&cache_key) {
+            println!("Loaded analysis from cache: {}", cache_key);
+            if let Err(e) =
+                library::add(&audio_hash, &path, &entry.beat_track, &entry.beat_features)
+            {
+                emit_library_error(&file_name, &e);
+            }
+            {
+                let mut audio_data = audio_data_ref.lock();
+                audio_data.tempo.replace(entry.tempo);
+                audio_data.beat_track.replace(entry.beat_track);
+                beat_features_guard.replace(entry.beat_features);
+            }
+            let _ = states::emit_state_sync_handle(
+                format!("clip.\"{}\".state", file_name).as_str(),
+                "processed",
+                states::APP_HANDLE.lock().as_ref().unwrap(),
+            );
+            return;
+        }
+    }
 
-            let beat_features = {
-                let audio_data_guard = audio_data_ref.lock(); // Lock the mutex again to access beat_features
-                audio_data_guard.beat_features.clone()
-            };
-            let mut beat_features_guard = beat_features.lock();
+    if !beat_track_exists {
+        // handlers::audio::notify_processing_audio();
+        {
+            states::emit_state_sync_handle(
+                format!("clip.\"{}\".state", file_name).as_str(),
+                "processing",
+                states::APP_HANDLE.lock().as_ref().unwrap(),
+            );
+        }
 
-            let now = Instant::now();
-            let path_clone = path.clone();
-            let file_name = Path::new(&path_clone)
-                .file_stem()
-                .expect("File name should exist")
-                .to_string_lossy();
-            if !beat_track_exists {
-                // handlers::audio::notify_processing_audio();
-                {
-                    states::emit_state_sync_handle(
-                        format!("clip.\"{}\".state", file_name).as_str(),
-                        "processing",
-                        states::APP_HANDLE.lock().as_ref().unwrap(),
-                    );
-                }
+        let sound = {
+            let audio_data = audio_data_ref.lock();
+            audio_data.sound.clone()
+        };
 
-                let sound = {
-                    let audio_data = audio_data_ref.lock();
-                    audio_data.sound.clone()
-                };
-
-                let sample_rate = { sound.decoder().convert_samples::<f32>().sample_rate() };
-
-                {
-                    let (beats_module, features_module) =
-                        Python::with_gil(|py| -> PyResult<(Py<PyModule>, Py<PyModule>)> {
-                            println!("Downloading and loading models...");
-
-                            let sys = py.import("sys")?;
-                            let path = sys.getattr("path")?;
-
-                            let file_path = Path::new(file!()); // Gets the current file's path
-                            let dir = file_path.parent().expect("Failed to get parent directory");
-
-                            path.call_method1(
-                                "append",
-                                (dir.join("..\\..\\..\\src-python\\venv\\Lib\\site-packages")
-                                    .canonicalize()
-                                    .expect("Failed to get absolute path")
-                                    .to_string_lossy(),),
-                            )?;
-                            path.call_method1(
-                                "append",
-                                (dir.join(
-                                    "..\\..\\..\\src-python\\venv\\Lib\\site-packages\\tokenizers",
-                                )
-                                .canonicalize()
-                                .expect("Failed to get absolute path")
-                                .to_string_lossy(),),
-                            )?;
-
-                            match std::env::var("PATH") {
-                                Ok(val) => {
-                                    let delimiter = if cfg!(windows) { ";" } else { ":" };
-                                    let paths: Vec<PathBuf> =
-                                        val.split(delimiter).map(PathBuf::from).collect();
-
-                                    path.call_method1("extend", (paths,))?;
-                                }
-                                Err(e) => println!("Error {}: {}", "PATH", e),
-                            }
-
-                            let rust_path: Py<PyAny> = path.clone().into();
-                            // println!("{}", rust_path);
-
-                            let beats_module: Py<PyModule> = PyModule::from_code(
-                                py,
-                                c_str!(include_str!("../../../src-python/src/beats.py")),
-                                c_str!("beats.py"),
-                                c_str!("beats"),
-                            )
-                            .unwrap()
-                            .into();
-
-                            let features_module: Py<PyModule> = PyModule::from_code(
-                                py,
-                                c_str!(include_str!("../../../src-python/src/features.py")),
-                                c_str!("features.py"),
-                                c_str!("features"),
-                            )
-                            .unwrap()
-                            .into();
-                            Ok((beats_module, features_module))
-                        })
-                        .expect("Failed to load modules");
-
-                    let mut s_beats_module = BEATS_MODULE.lock();
-                    s_beats_module.replace(beats_module);
-
-                    let mut s_features_module = FEATURES_MODULE.lock();
-                    s_features_module.replace(features_module);
-                }
+        let sample_rate = sound.sample_rate();
 
-                println!("Loaded modules");
-
-                let (beat_track, collected_features) = Python::with_gil(|py| {
-                    let get_audio_features: Py<PyAny> = FEATURES_MODULE
-                        .lock()
-                        .as_ref()
-                        .unwrap()
-                        .bind(py)
-                        .getattr("get_audio_features")?
-                        .into();
-
-                    let get_beats: Py<PyAny> = BEATS_MODULE
-                        .lock()
-                        .as_ref()
-                        .unwrap()
-                        .bind(py)
-                        .getattr("get_beats")?
-                        .into();
-
-                    let (_tempo, beat_track): (Vec<f32>, Vec<u32>) =
-                        get_beats.call1(py, (path, sample_rate))?.extract(py)?;
-
-                    // let mut beat_feature_sources = Vec::new();
-
-                    println!("Sample collection start: {}", now.elapsed().as_secs_f32());
-
-                    let sample_buffers: Vec<Vec<f32>> = beat_track
-                        .iter()
-                        // OPT: Every 4th beat
-                        // .step_by(4)
-                        .enumerate()
-                        .map(|(_i, beat)| {
-                            let mut samples_buffer = Vec::new();
-
-                            // Thinking of having a second buffer around the sample
-                            // This would be |< sample_rate >| 1 |< sample_rate >| = 2 * sample_rate + 1
-
-                            let start: i32 = *beat as i32 - sample_rate as i32;
-                            let seek_duration = if start < 0 {
-                                Duration::from_secs(0)
-                            } else {
-                                match (start as u64).checked_div(sample_rate as u64) {
-                                    Some(duration) => Duration::from_secs(duration),
-                                    None => {
-                                        // println!("{}, {}", start, sample_rate);
-                                        eprintln!(
-                                            "Overflow occurred while calculating seek duration"
-                                        );
-                                        Duration::from_secs(0)
-                                        // continue; // Skip this beat if overflow occurs
-                                    }
-                                }
-                            };
-
-                            if start < 0 {
-                                samples_buffer = vec![0_f32; start.abs().try_into().unwrap()];
-                            }
-
-                            let mut decoder = sound.decoder().convert_samples::<f32>();
-
-                            let total_duration = decoder.total_duration().unwrap();
-                            let result = decoder.try_seek(
-                                seek_duration.clamp(Duration::from_secs(0), total_duration),
-                            );
-
-                            match result {
-                                Ok(_) => {
-                                    // Seek was successful
-                                }
-                                Err(e) => {
-                                    println!("Failed to seek: {:?}", e);
-                                }
-                            }
-
-                            while samples_buffer.len() < ((sample_rate * 2) + 1).try_into().unwrap()
-                            {
-                                // Step 2: Loop until buffer length equals sample_rate
-                                match decoder.next() {
-                                    // Step 3: Attempt to read a sample
-                                    Some(sample) => samples_buffer.push(sample), // Step 4: Add sample to buffer
-                                    None => break, // Step 5: Break if no more samples
-                                }
-                            }
-
-                            // println!(
-                            //     "Sample collection {}/{}",
-                            //     i,
-                            //     beat_track.len(),
-                            // );
-                            samples_buffer
-                        })
-                        .collect();
-
-                    println!(
-                        "Sample collection complete: {}",
-                        now.elapsed().as_secs_f32()
-                    );
+        let beat_tracker = beats::beat_tracker();
+        let (tempo, beat_track) = match beat_tracker.track(&path, &sound) {
+            Ok((_, beat_track)) if beat_track.is_empty() => {
+                emit_analysis_failed(
+                    &file_name,
+                    &AllomereError::Analysis("No beats found".to_string()),
+                );
+                return;
+            }
+            Ok((tempo, beat_track)) => (tempo.first().cloned().unwrap_or(0.0), beat_track),
+            Err(e) => {
+                emit_analysis_failed(
+                    &file_name,
+                    &AllomereError::Analysis(format!(
+                        "Beat tracking with {:?} failed: {}",
+                        beat_tracker.kind(),
+                        e
+                    )),
+                );
+                return;
+            }
+        };
 
-                    let collected_features: Vec<Vec<f32>> = sample_buffers
-                        // .iter()
-                        // .step_by(4)
-                        // .cloned()
-                        // .collect::<Vec<Vec<f32>>>()
-                        .chunks(10)
-                        .enumerate()
-                        .flat_map(|(i, chunked_samples)| {
-                            let audio_array = PyArray2::from_vec2(py, chunked_samples).ok()?;
-                            let features = get_audio_features
-                                .call1(py, (audio_array, sample_rate))
-                                .ok()?
-                                .extract::<Vec<Vec<f32>>>(py)
-                                .ok()?;
-                            println!(
-                                "Feature extraction {}/{}: {:?}",
-                                i,
-                                // OPT: Cause of OPT and chunking
-                                // beat_track.len() / (4 * 10),
-                                beat_track.len() / (10),
-                                now.elapsed().as_secs_f32()
-                            );
-                            Some(features)
-                        })
-                        .flatten()
-                        .collect();
-
-                    Ok::<(Vec<u32>, Vec<Vec<f32>>), Error>((beat_track, collected_features))
-                })
-                .ok()
-                .unwrap();
+        println!(
+            "Beat tracking ({:?}) complete: {}",
+            beat_tracker.kind(),
+            now.elapsed().as_secs_f32()
+        );
 
-                {
-                    audio_data_ref.lock().beat_track.replace(beat_track);
-                    beat_features_guard.replace(collected_features);
-                }
-                {
-                    states::emit_state_sync_handle(
-                        format!("clip.\"{}\".state", file_name).as_str(),
-                        "processed",
-                        states::APP_HANDLE.lock().as_ref().unwrap(),
+        let embedding_extractor = features::embedding_extractor();
+        let channels = sound.channels();
+
+        // |< sample_rate >| beat |< sample_rate >| in frames, sliced from the
+        // decoded audio and padded with silence past either end of the file.
+        // The slice starts at a frame but its length is in interleaved samples.
+        // Only BEAT_WINDOW_BATCH windows are held at a time
+        let window_samples = (sample_rate as usize * 2 + 1) * channels as usize;
+        let mut collected_features = Vec::with_capacity(beat_track.len());
+        for beats in beat_track.chunks(BEAT_WINDOW_BATCH) {
+            let sample_buffers: Vec<Vec<f32>> = beats
+                .iter()
+                .map(|beat| sound.slice(*beat as i64 - sample_rate as i64, window_samples))
+                .collect();
+
+            match embedding_extractor.extract(&sample_buffers, channels, sample_rate) {
+                Ok(features) => collected_features.extend(features),
+                Err(e) => {
+                    emit_analysis_failed(
+                        &file_name,
+                        &AllomereError::Analysis(format!(
+                            "Feature extraction with {:?} failed: {}",
+                            embedding_extractor.kind(),
+                            e
+                        )),
                     );
+                    return;
                 }
-            } else {
-                println!("Beat track already exists")
             }
-            println!(
-                "Beat track generation complete: {:?}",
-                now.elapsed().as_secs_f32()
-            );
-        });
+        }
 
-        let (custom_source, custom_source_controller) =
-            CustomSource::new(audio_data.lock().sound.clone().decoder());
+        println!(
+            "Feature extraction ({:?}) complete: {}",
+            embedding_extractor.kind(),
+            now.elapsed().as_secs_f32()
+        );
 
-        Clip {
-            path: path.to_string(),
-            name: Path::new(&path)
-                .file_stem()
-                .expect("File name should exist")
-                .to_string_lossy()
-                .to_string(),
-            audio: Some(Audio {
-                source: custom_source,
+        if let Err(e) = cache::store(
+            &cache_key,
+            &cache::AnalysisCacheEntry {
+                version: cache::analysis_version(),
+                tempo,
+                beat_track: beat_track.clone(),
+                beat_features: collected_features.clone(),
+            },
+        ) {
+            eprintln!("Failed to store analysis in cache: {}", e);
+        }
+
+        if let Err(e) = library::add(&audio_hash, &path, &beat_track, &collected_features) {
+            emit_library_error(&file_name, &e);
+        }
+
+        {
+            let mut audio_data = audio_data_ref.lock();
+            audio_data.tempo.replace(tempo);
+            audio_data.beat_track.replace(beat_track);
+            beat_features_guard.replace(collected_features);
+        }
+        {
+            states::emit_state_sync_handle(
+                format!("clip.\"{}\".state", file_name).as_str(),
+                "processed",
+                states::APP_HANDLE.lock().as_ref().unwrap(),
+            );
+        }
+    } else {
+        println!("Beat track already exists")
+    }
+    println!(
+        "Beat track generation complete: {:?}",
+        now.elapsed().as_secs_f32()
+    );
+}
+
+fn queue_analysis(audio_data_ref: Arc<Mutex<AudioData>>, reanalyse: bool) {
+    let analysis = {
+        let audio_data = audio_data_ref.lock();
+        if reanalyse || audio_data.beat_track.is_none() {
+            audio_data.analysis.start();
+        }
+        audio_data.analysis.clone()
+    };
+
+    tauri::async_runtime::spawn(async move {
+        analyse(&audio_data_ref, reanalyse);
+        let beat_features = audio_data_ref.lock().beat_features.clone();
+        let analysed = beat_features.lock().is_some();
+        analysis.finish(analysed);
+    });
+}
+
+// Analyses every file loaded this session again, e.g. with a new embedding extractor
+pub fn reanalyse_all() {
+    let audio_data: Vec<_> = AUDIO_DATA_MAP.lock().values().cloned().collect();
+    for audio_data_ref in audio_data {
+        queue_analysis(audio_data_ref, true);
+    }
+}
+
+impl Clip {
+    pub fn new(path: &str) -> Result<Self, AllomereError> {
+        let audio_data = {
+            let mut audio_data_map = AUDIO_DATA_MAP.lock(); // Lock the mutex here
+            match audio_data_map.get(path) {
+                Some(audio_data) => audio_data.clone(),
+                None => {
+                    let audio_data = Arc::new(Mutex::new(AudioData::new(path)?));
+                    audio_data_map.insert(path.to_string(), audio_data.clone());
+                    audio_data
+                }
+            }
+        };
+        // AudioDataMap.insert(path.clone(), AudioData::new(path.clone()));
+
+        // A file whose analysis failed before is analysed again
+        queue_analysis(audio_data.clone(), false);
+
+        let (custom_source, custom_source_controller) = {
+            let sound = audio_data.lock().sound.clone();
+            CustomSource::new(&sound, sound.stream())
+        };
+
+        Ok(Clip {
+            path: path.to_string(),
+            name: Path::new(&path)
+                .file_stem()
+                .expect("File name should exist")
+                .to_string_lossy()
+                .to_string(),
+            audio: Some(Audio {
+                source: custom_source,
                 controller: Some(custom_source_controller),
+                stretch: Arc::new(TimeStretchControls::default()),
+                resample: Arc::new(ResampleControls::default()),
             }),
             start_at: None,
-            id: {
-                let mut id = CLIP_ID.lock();
-                *id += 1;
-                *id
+            id: ClipId::default(),
+        })
+    }
+
+    pub fn try_seek(&mut self, pos: Duration) -> Result<(), source::SeekError> {
+        self.audio.as_mut().unwrap().source.try_seek(pos)
+    }
+
+    // Frames outside the trim points land on the nearest one. The resampler
+    // starts `offset` past `frame`, out of the timeline rate
+    pub fn seek_frame(
+        &self,
+        frame: SourceFrame,
+        offset: u32,
+    ) -> Result<SourceFrame, source::SeekError> {
+        let audio = self.audio.as_ref().unwrap();
+        let (start_frame, end_frame) = audio.source.trim_range();
+        let clamped = frame.0.clamp(start_frame as u64, end_frame as u64);
+        audio.stretch.flush();
+        audio
+            .resample
+            .flush(if clamped == frame.0 { offset } else { 0 });
+        audio.source.seek_frame(clamped).map(SourceFrame)
+    }
+
+    pub fn finish(&self) {
+        let audio = self.audio.as_ref().unwrap();
+        audio.stretch.flush();
+        audio.resample.flush(0);
+        audio.source.finish()
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.audio.as_ref().unwrap().source.sample_rate()
+    }
+
+    // How fast the clip plays relative to its own tempo, 1.0 unless tempo-synced
+    pub fn playback_rate(&self) -> f32 {
+        self.audio
+            .as_ref()
+            .map_or(1.0, |audio| audio.stretch.rate())
+    }
+
+    // Stretches the clip to `master_tempo`, None or an unanalysed clip plays at its own speed
+    pub fn sync_tempo(&self, master_tempo: Option<f32>) {
+        let tempo = self
+            .audio_data()
+            .and_then(|audio_data| audio_data.lock().tempo);
+        let rate = match (tempo, master_tempo) {
+            (Some(tempo), Some(master_tempo)) => stretch::tempo_ratio(tempo, master_tempo),
+            _ => 1.0,
+        };
+        if let Some(audio) = &self.audio {
+            audio.stretch.set_rate(rate);
+        }
+    }
+
+    // Maps the clip's source onto a timeline running at `sample_rate`
+    pub fn frame_map(&self, sample_rate: u32) -> FrameMap {
+        FrameMap::new(self.sample_rate(), sample_rate, self.playback_rate())
+    }
+
+    // Where the clip starts on the timeline
+    pub fn start_frame(&self) -> TimelineFrame {
+        TimelineFrame(self.start_at.unwrap_or(0))
+    }
+
+    // Length of the clip in frames at the playback sample rate, after stretching
+    pub fn timeline_frames(&self, sample_rate: u32) -> TimelineFrame {
+        self.frame_map(sample_rate)
+            .to_timeline(SourceFrame(self.total_frames()))
+    }
+
+    // How long the clip plays for at the playback sample rate, the repeats of a
+    // counted loop included
+    pub fn played_timeline_frames(&self, sample_rate: u32) -> TimelineFrame {
+        let repeated_frames = self.audio.as_ref().map_or(0, |audio| {
+            let (_, trim_end_frame) = audio.source.trim_range();
+            audio
+                .source
+                .controller
+                .lock()
+                .repeated_frames(trim_end_frame)
+        });
+        self.frame_map(sample_rate)
+            .to_timeline(SourceFrame(self.total_frames() + repeated_frames))
+    }
+
+    // Index of the beat closest to `frame` in the source
+    pub fn nearest_beat(&self, frame: SourceFrame) -> Option<usize> {
+        let frame = frame.0;
+        let audio_data = self.audio_data()?;
+        let audio_data = audio_data.lock();
+        let beat_track = audio_data.beat_track.as_ref()?;
+
+        let index = beat_track.partition_point(|&beat| (beat as u64) < frame);
+        [
+            index.checked_sub(1),
+            (index < beat_track.len()).then_some(index),
+        ]
+        .into_iter()
+        .flatten()
+        .min_by_key(|&i| (beat_track[i] as u64).abs_diff(frame))
+    }
+
+    // Source frame the clip starts playing from
+    pub fn trim_start_frame(&self) -> SourceFrame {
+        SourceFrame(
+            self.audio
+                .as_ref()
+                .map_or(0, |audio| audio.source.trim_range().0 as u64),
+        )
+    }
+
+    // Beats on the playback timeline relative to the clip's start, after stretching.
+    // Beats trimmed off the front come out negative. Loop points stay in source
+    // frames, they're applied before the stretch
+    pub fn timeline_beat_track(&self, sample_rate: u32) -> Option<Vec<i64>> {
+        let beat_track = self.audio_data()?.lock().beat_track.clone()?;
+        let frame_map = self.frame_map(sample_rate);
+        let trim_start_frame = self.trim_start_frame().0;
+        Some(
+            beat_track
+                .iter()
+                .map(|&beat| {
+                    let frames = frame_map
+                        .to_timeline(SourceFrame(trim_start_frame.abs_diff(beat as u64)))
+                        .0 as i64;
+                    if (beat as u64) < trim_start_frame {
+                        -frames
+                    } else {
+                        frames
+                    }
+                })
+                .collect(),
+        )
+    }
+
+    pub fn edits(&self) -> Option<ClipEdits> {
+        self.audio
+            .as_ref()
+            .map(|audio| (*audio.source.edits.get()).clone())
+    }
+
+    // Goes through the setters so restored edits are checked like new ones
+    pub fn set_edits(&mut self, edits: ClipEdits) {
+        self.set_trim(edits.trim_start_frame, edits.trim_end_frame);
+        self.set_fades(edits.fade_in_frames, edits.fade_out_frames);
+        self.set_gain_envelope(edits.gain_envelope);
+    }
+
+    // The out point is kept within the file and the in point before it
+    pub fn set_trim(&mut self, start_frame: u32, end_frame: Option<u32>) {
+        if let Some(audio) = &self.audio {
+            let end_frame = match (end_frame, audio.source.source_frames()) {
+                (Some(end_frame), Some(source_frames)) => Some(end_frame.min(source_frames)),
+                (end_frame, _) => end_frame,
+            };
+            let limit = end_frame
+                .or(audio.source.source_frames())
+                .map_or(u32::MAX, |limit| limit.saturating_sub(1));
+
+            audio.source.edits.update(|edits| {
+                edits.trim_start_frame = start_frame.min(limit);
+                edits.trim_end_frame = end_frame;
+            });
+        }
+    }
+
+    pub fn set_fades(&mut self, fade_in_frames: u32, fade_out_frames: u32) {
+        if let Some(audio) = &self.audio {
+            audio.source.edits.update(|edits| {
+                edits.fade_in_frames = fade_in_frames;
+                edits.fade_out_frames = fade_out_frames;
+            });
+        }
+    }
+
+    // Points are put in order, gains that aren't finite are dropped and negative
+    // ones raised to silence
+    pub fn set_gain_envelope(&mut self, mut points: Vec<GainPoint>) {
+        points.retain(|point| point.gain.is_finite());
+        points
+            .iter_mut()
+            .for_each(|point| point.gain = point.gain.max(0.0));
+        points.sort_by_key(|point| point.frame);
+        points.dedup_by_key(|point| point.frame);
+
+        if let Some(audio) = &self.audio {
+            audio
+                .source
+                .edits
+                .update(|edits| edits.gain_envelope = points);
+        }
+    }
+
+    // Positions are times into the file, so they're counted at the file's own
+    // rate whatever the timeline runs at
+    pub fn set_loop(
+        &mut self,
+        start_pos: Duration,
+        end_pos: Duration,
+    ) -> Result<(), AllomereError> {
+        if self.audio.is_none() {
+            return Ok(());
+        }
+        let sample_rate = self.sample_rate();
+        let id = self.id;
+        // Loop points are u32 source frames, later frames of very long files can't be looped
+        let to_u32 = |frame: u64| {
+            u32::try_from(frame).map_err(|_| AllomereError::FrameOutOfRange { id, frame })
+        };
+        let start_frame = to_u32(duration_to_frame(start_pos, sample_rate))?;
+        let end_frame = to_u32(duration_to_frame(end_pos, sample_rate))?;
+        self.set_loop_frames(start_frame, end_frame)
+    }
+
+    pub fn clear_loop(&mut self) {
+        self.audio
+            .as_mut() // Get mutable reference to Option<Audio>
+            .and_then(|audio| {
+                audio.controller.as_mut() // Get mutable reference to Option<Controller>
+            })
+            .map(|controller| {
+                controller
+                    .lock() // Get lock on the Mutex
+                    .clear_loop()
+            });
+    }
+
+    // The loop has to end after it starts and within the file
+    pub fn set_loop_frames(
+        &mut self,
+        start_frame: u32,
+        end_frame: u32,
+    ) -> Result<(), AllomereError> {
+        let Some(audio) = &self.audio else {
+            return Ok(());
+        };
+        let id = self.id;
+        if audio
+            .source
+            .source_frames()
+            .is_some_and(|source_frames| end_frame > source_frames)
+        {
+            return Err(AllomereError::FrameOutOfRange {
+                id,
+                frame: end_frame as u64,
+            });
+        }
+        if start_frame >= end_frame {
+            return Err(AllomereError::FrameOutOfRange {
+                id,
+                frame: start_frame as u64,
+            });
+        }
+
+        let (start_frame, end_frame) = if self.loop_zero_crossing() {
+            self.snap_to_zero_crossings(start_frame, end_frame)
+        } else {
+            (start_frame, end_frame)
+        };
+
+        self.audio
+            .as_mut() // Get mutable reference to Option<Audio>
+            .and_then(|audio| {
+                audio.controller.as_mut() // Get mutable reference to Option<Controller>
+            })
+            .map(|controller| {
+                controller
+                    .lock() // Get lock on the Mutex
+                    .set_loop(start_frame, end_frame)
+            });
+        Ok(())
+    }
+
+    // Plays on to `from_frame` once more, then carries on from `to_frame`
+    pub fn set_jump(&mut self, from_frame: u32, to_frame: u32) {
+        let (to_frame, from_frame) = if self.loop_zero_crossing() {
+            self.snap_to_zero_crossings(to_frame, from_frame)
+        } else {
+            (to_frame, from_frame)
+        };
+
+        self.audio
+            .as_mut()
+            .and_then(|audio| audio.controller.as_mut())
+            .map(|controller| {
+                controller
+                    .lock()
+                    .set_loop_with_count(to_frame, from_frame, 2)
+            });
+    }
+
+    pub fn has_loop(&self) -> bool {
+        self.audio.as_ref().is_some_and(|audio| {
+            let controller = audio.source.controller.lock();
+            controller.loop_start.unwrap_or(false) && controller.loop_end_frame.is_some()
+        })
+    }
+
+    pub fn controller(&self) -> Option<CustomSourceController> {
+        self.audio
+            .as_ref()
+            .map(|audio| audio.source.controller.lock().clone())
+    }
+
+    // Loop counts start over, as if the loop had just been set
+    pub fn set_controller(&mut self, controller: CustomSourceController) {
+        self.audio
+            .as_mut()
+            .and_then(|audio| audio.controller.as_mut())
+            .map(|current_controller| {
+                let mut current_controller = current_controller.lock();
+                *current_controller = controller;
+                current_controller.reset_loop_count();
+            });
+        if let Some(audio) = &self.audio {
+            let frames = audio.source.controller.lock().loop_crossfade_frames;
+            audio.source.reserve_crossfade(frames);
+        }
+    }
+
+    pub fn audio_data(&self) -> Option<Arc<Mutex<AudioData>>> {
+        AUDIO_DATA_MAP.lock().get(&self.path).cloned()
+    }
+
+    fn loop_zero_crossing(&self) -> bool {
+        self.audio
+            .as_ref()
+            .is_some_and(|audio| audio.source.controller.lock().loop_zero_crossing)
+    }
+
+    // Zero crossings are looked for up to 10ms either side of each loop point
+    fn snap_to_zero_crossings(&self, start_frame: u32, end_frame: u32) -> (u32, u32) {
+        let sound = match self.audio_data() {
+            Some(audio_data) => audio_data.lock().sound.clone(),
+            None => return (start_frame, end_frame),
+        };
+        let window_frames = self.sample_rate() / 100;
+
+        (
+            sound.nearest_zero_crossing(start_frame, window_frames),
+            sound.nearest_zero_crossing(end_frame, window_frames),
+        )
+    }
+
+    // Returns the crossfade length in frames, None when it can't be worked out yet
+    pub fn set_loop_crossfade(&mut self, length: CrossfadeLength) -> Option<u32> {
+        let frames = match length {
+            CrossfadeLength::Milliseconds(milliseconds) => {
+                milliseconds * (self.sample_rate() as f64) / 1000.0
+            }
+            CrossfadeLength::Beats(beats) => {
+                let beat_interval = self.audio_data()?.lock().beat_interval()?;
+                beats * beat_interval
+            }
+        };
+        let frames = frames.max(0.0).round() as u32;
+
+        let audio = self.audio.as_mut()?;
+        audio.source.reserve_crossfade(frames);
+        audio
+            .controller
+            .as_mut()
+            .map(|controller| controller.lock().set_loop_crossfade(frames))?;
+
+        Some(frames)
+    }
+
+    // Enabling it also moves the current loop points onto zero crossings
+    pub fn set_loop_zero_crossing(&mut self, enabled: bool) {
+        let loop_frames = self
+            .audio
+            .as_mut()
+            .and_then(|audio| audio.controller.as_mut())
+            .and_then(|controller| {
+                let mut controller = controller.lock();
+                controller.set_loop_zero_crossing(enabled);
+                controller.loop_start_frame.zip(controller.loop_end_frame)
+            });
+
+        if let (true, Some((start_frame, end_frame))) = (enabled, loop_frames) {
+            let (start_frame, end_frame) = self.snap_to_zero_crossings(start_frame, end_frame);
+            self.audio
+                .as_mut()
+                .and_then(|audio| audio.controller.as_mut())
+                .map(|controller| {
+                    let mut controller = controller.lock();
+                    controller.loop_start_frame.replace(start_frame);
+                    controller.loop_end_frame.replace(end_frame);
+                });
+        }
+    }
+
+    pub fn set_loop_count(&mut self, loop_count: Option<u16>) {
+        self.audio
+            .as_mut()
+            .and_then(|audio| audio.controller.as_mut())
+            .map(|controller| controller.lock().set_loop_count(loop_count));
+    }
+
+    // Other beats of the same song closest to `beat`, as (beat, distance) nearest first
+    pub fn get_preferred_transition_beats(
+        &self,
+        beat: usize,
+        count: usize,
+    ) -> Result<Vec<(usize, f32)>, AllomereError> {
+        Ok(self
+            .find_transitions(beat, count, TransitionScope::Song, &HashSet::new())?
+            .into_iter()
+            .map(|candidate| (candidate.beat, candidate.distance))
+            .collect())
+    }
+
+    pub fn audio_hash(&self) -> Result<String, AllomereError> {
+        let audio_data = self
+            .audio_data()
+            .ok_or_else(|| AllomereError::AudioDataNotFound(self.path.clone()))?;
+        let audio_hash = audio_data.lock().audio_hash.clone();
+        Ok(audio_hash)
+    }
+
+    // Beats in the library closest to this clip's `beat`, see `library::find_transitions`
+    pub fn find_transitions(
+        &self,
+        beat: usize,
+        count: usize,
+        scope: TransitionScope,
+        project: &HashSet<String>,
+    ) -> Result<Vec<TransitionCandidate>, AllomereError> {
+        let audio_data = self
+            .audio_data()
+            .ok_or_else(|| AllomereError::AudioDataNotFound(self.path.clone()))?;
+        let (audio_hash, beat_features) = {
+            let audio_data = audio_data.lock();
+            (
+                audio_data.audio_hash.clone(),
+                audio_data.beat_features.clone(),
+            )
+        };
+        if beat_features.lock().is_none() {
+            return Err(AllomereError::AnalysisPending(self.name.clone()));
+        }
+
+        library::find_transitions(&audio_hash, beat, count, scope, project)
+    }
+
+    pub fn total_frames(&self) -> u64 {
+        self.audio.as_ref().unwrap().total_frames()
+    }
+
+    // The clip's own source as a timeline at `sample_rate` plays it
+    fn timeline_source(&self, sample_rate: u32) -> Option<ClipSource> {
+        let audio = self.audio.as_ref()?;
+        Some(TimeStretch::new(
+            Resample::new(audio.source.clone(), sample_rate, audio.resample.clone()),
+            audio.stretch.clone(),
+        ))
+    }
+
+    // Builds an independent source over the same sound for offline rendering
+    pub fn render_source(&self, sample_rate: u32) -> Option<ClipSource> {
+        self.copy_source(sample_rate, Sound::reader)
+    }
+
+    // The loop settings are copied so edits during a render don't affect it
+    fn copy_source(
+        &self,
+        sample_rate: u32,
+        reader: impl FnOnce(&Sound) -> PcmReader,
+    ) -> Option<ClipSource> {
+        let sound = {
+            let audio_data_map = AUDIO_DATA_MAP.lock();
+            let audio_data = audio_data_map.get(&self.path)?.clone();
+            let sound = audio_data.lock().sound.clone();
+            sound
+        };
+
+        let (custom_source, custom_source_controller) = CustomSource::new(&sound, reader(&sound));
+
+        if let Some(audio) = &self.audio {
+            let mut controller = custom_source_controller.lock();
+            *controller = audio.source.controller.lock().clone();
+            controller.reset_loop_count();
+            custom_source.reserve_crossfade(controller.loop_crossfade_frames);
+            custom_source.edits.set((*audio.source.edits.get()).clone());
+        }
+
+        let stretch = Arc::new(TimeStretchControls::default());
+        stretch.set_rate(self.playback_rate());
+
+        Some(TimeStretch::new(
+            Resample::new(
+                custom_source,
+                sample_rate,
+                Arc::new(ResampleControls::default()),
+            ),
+            stretch,
+        ))
+    }
+
+    // An independent source auditioning the jump from beat `from_beat` to `to_beat`,
+    // with `pre_roll_beats` leading up to it and as many after it. It plays the
+    // whole file, untrimmed, at the clip's tempo. Returns it with how long it lasts
+    pub fn preview_source(
+        &self,
+        sample_rate: u32,
+        from_beat: usize,
+        to_beat: usize,
+        pre_roll_beats: usize,
+    ) -> Result<(ClipSource, Duration), AllomereError> {
+        let beat_track = self
+            .audio_data()
+            .and_then(|audio_data| audio_data.lock().beat_track.clone())
+            .ok_or_else(|| AllomereError::AnalysisPending(self.name.clone()))?;
+        let beats = beat_track.len();
+        if let Some(&beat) = [from_beat, to_beat].iter().find(|&&beat| beat >= beats) {
+            return Err(AllomereError::BeatOutOfRange { beat, beats });
+        }
+
+        // Auditioned in real time, so it mustn't wait on the disk
+        let source = self
+            .copy_source(sample_rate, Sound::stream)
+            .ok_or_else(|| AllomereError::AudioDataNotFound(self.path.clone()))?;
+        let custom_source = source.inner().inner();
+
+        let (to_frame, from_frame) = if self.loop_zero_crossing() {
+            self.snap_to_zero_crossings(beat_track[to_beat], beat_track[from_beat])
+        } else {
+            (beat_track[to_beat], beat_track[from_beat])
+        };
+        let start_frame = beat_track[from_beat.saturating_sub(pre_roll_beats)].min(from_frame);
+        let end_frame = beat_track
+            .get(to_beat + pre_roll_beats)
+            .copied()
+            .or_else(|| custom_source.source_frames())
+            .unwrap_or(to_frame);
+
+        custom_source.edits.set(ClipEdits::default());
+        custom_source
+            .controller
+            .lock()
+            .set_loop_with_count(to_frame, from_frame, 2);
+        custom_source.seek_frame(start_frame as u64)?;
+
+        let frames = (from_frame - start_frame) + end_frame.saturating_sub(to_frame);
+        let duration = Duration::from_secs_f64(
+            (frames as f64) / (self.sample_rate() as f64) / (self.playback_rate() as f64),
+        );
+        Ok((source, duration))
+    }
+}
+
+// Resampled to the timeline rate once, then stretched at that rate
+pub type ClipSource = TimeStretch<Resample<CustomSource>>;
+
+// A clip on a track's timeline, converted to the playback format. The clip
+// source is already at the timeline rate, so only its channels are converted
+struct ScheduledClip {
+    id: ClipId,
+    start_frame: u64,
+    source: source::UniformSourceIterator<ClipSource, f32>,
+    // Set by the clip's source when it runs out and cleared again when it's seeked
+    is_finished: Arc<AtomicBool>,
+    ended: bool,
+}
+
+// The clips of a track by start frame and the frame the track is at, shared
+// between the track and the TrackTimeline playing it
+pub struct TimelineSchedule {
+    clips: Vec<ScheduledClip>,
+    position: u64,
+    channels: u16,
+    sample_rate: u32,
+    // Set once the track is dropped, ending its timeline takes it out of the mixer
+    closed: bool,
+}
+
+// Deserialized tracks need one, they aren't connected to the mixer
+impl Default for TimelineSchedule {
+    fn default() -> Self {
+        TimelineSchedule {
+            clips: Vec::new(),
+            position: 0,
+            channels: NullBackend::CHANNELS,
+            sample_rate: NullBackend::SAMPLE_RATE,
+            closed: false,
+        }
+    }
+}
+
+impl TimelineSchedule {
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    pub fn add(&mut self, id: ClipId, start_frame: TimelineFrame, source: ClipSource) {
+        let is_finished = source.inner().inner().is_finished.clone();
+        self.clips.push(ScheduledClip {
+            id,
+            start_frame: start_frame.0,
+            source: source::UniformSourceIterator::new(source, self.channels, self.sample_rate),
+            is_finished,
+            ended: false,
+        });
+    }
+
+    pub fn remove(&mut self, id: ClipId) {
+        self.clips.retain(|clip| clip.id != id);
+    }
+
+    // Adds up the clips playing at the current frame, then moves on a frame
+    fn mix_frame(&mut self, frame: &mut [f32]) {
+        frame.iter_mut().for_each(|sample| *sample = 0.0);
+
+        let position = self.position;
+        self.position += 1;
+
+        for clip in &mut self.clips {
+            if clip.start_frame > position {
+                continue;
+            }
+            if clip.ended {
+                if clip.is_finished.load(SeqCst) {
+                    continue;
+                }
+                clip.ended = false;
+            }
+            for sample in frame.iter_mut() {
+                match clip.source.next() {
+                    Some(clip_sample) => *sample += clip_sample,
+                    None => {
+                        clip.ended = true;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Plays each of a track's clips from its start_at, so clips can leave gaps or
+// overlap, whichever order they were added in. Like an idle sink it only ends
+// once its track is dropped
+pub struct TrackTimeline {
+    schedule: Arc<Mutex<TimelineSchedule>>,
+    frame: Vec<f32>,
+    channel: usize,
+}
+
+impl TrackTimeline {
+    pub fn new(
+        channels: u16,
+        sample_rate: u32,
+        position: u64,
+    ) -> (Self, Arc<Mutex<TimelineSchedule>>) {
+        let schedule = Arc::new(Mutex::new(TimelineSchedule {
+            clips: Vec::new(),
+            position,
+            channels,
+            sample_rate,
+            closed: false,
+        }));
+        (
+            TrackTimeline {
+                schedule: schedule.clone(),
+                frame: vec![0f32; channels as usize],
+                channel: 0,
             },
+            schedule,
+        )
+    }
+}
+
+impl Iterator for TrackTimeline {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.channel == 0 {
+            let mut schedule = self.schedule.lock();
+            if schedule.closed {
+                return None;
+            }
+            schedule.mix_frame(&mut self.frame);
         }
+        let sample = self.frame[self.channel];
+        self.channel = (self.channel + 1) % self.frame.len();
+        Some(sample)
+    }
+}
+
+impl Source for TrackTimeline {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.frame.len() as u16
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.schedule.lock().sample_rate
     }
 
-    pub fn try_seek(&mut self, pos: Duration) -> Result<(), source::SeekError> {
-        self.audio.as_mut().unwrap().source.try_seek(pos)
-    }
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+
+    // Tracks are seeked through `Track::try_seek`, which cues every clip
+    fn try_seek(&mut self, _pos: Duration) -> Result<(), source::SeekError> {
+        Err(source::SeekError::NotSupported {
+            underlying_source: std::any::type_name::<Self>(),
+        })
+    }
+}
+
+// Gain, pan and mute/solo state shared between a track and the ChannelStrip
+// sources playing it, so changes land without rebuilding the audio graph
+#[derive(Debug)]
+pub struct ChannelStripControls {
+    gain: AtomicU32,
+    pan: AtomicU32,
+    audible: AtomicBool,
+}
+
+impl Default for ChannelStripControls {
+    fn default() -> Self {
+        ChannelStripControls {
+            gain: AtomicU32::new(1f32.to_bits()),
+            pan: AtomicU32::new(0f32.to_bits()),
+            audible: AtomicBool::new(true),
+        }
+    }
+}
+
+impl ChannelStripControls {
+    pub fn gain(&self) -> f32 {
+        f32::from_bits(self.gain.load(SeqCst))
+    }
+
+    pub fn pan(&self) -> f32 {
+        f32::from_bits(self.pan.load(SeqCst))
+    }
+
+    pub fn audible(&self) -> bool {
+        self.audible.load(SeqCst)
+    }
+
+    // Per-channel gain, pan uses a balance law so a centred track stays at unity
+    fn channel_gain(&self, channel: u16, channels: u16) -> f32 {
+        if !self.audible() {
+            return 0.0;
+        }
+        let gain = self.gain();
+        let pan = self.pan();
+        match (channels, channel) {
+            (1, _) => gain,
+            (_, 0) => gain * (1.0 - pan).min(1.0),
+            (_, 1) => gain * (1.0 + pan).min(1.0),
+            _ => gain,
+        }
+    }
+}
+
+// Converts a track's output to the playback format and applies its channel strip
+// Gain changes are smoothed over a few milliseconds so moving a fader doesn't click
+pub struct ChannelStrip<S>
+where
+    S: Source<Item = f32>,
+{
+    inner: source::UniformSourceIterator<S, f32>,
+    controls: Arc<ChannelStripControls>,
+    channels: u16,
+    sample_rate: u32,
+    channel: u16,
+    gains: Vec<f32>,
+    smoothing: f32,
+}
+
+impl<S> ChannelStrip<S>
+where
+    S: Source<Item = f32>,
+{
+    const SMOOTHING_SECONDS: f32 = 0.005;
+
+    pub fn new(
+        inner: S,
+        channels: u16,
+        sample_rate: u32,
+        controls: Arc<ChannelStripControls>,
+    ) -> Self {
+        let gains = (0..channels)
+            .map(|channel| controls.channel_gain(channel, channels))
+            .collect();
+        ChannelStrip {
+            inner: source::UniformSourceIterator::new(inner, channels, sample_rate),
+            controls,
+            channels,
+            sample_rate,
+            channel: 0,
+            gains,
+            smoothing: 1.0 - (-1.0 / (Self::SMOOTHING_SECONDS * sample_rate as f32)).exp(),
+        }
+    }
+}
+
+impl<S> Iterator for ChannelStrip<S>
+where
+    S: Source<Item = f32>,
+{
+    type Item = f32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let sample = self.inner.next()?;
 
-    pub fn set_loop(&mut self, start_pos: Duration, end_pos: Duration) {
-        if let Some(audio) = &self.audio {
-            let sample_rate = audio.source.sample_rate();
-            let start_frame = ((sample_rate as f64) * start_pos.as_secs_f64()) as u32;
-            let end_frame = ((sample_rate as f64) * end_pos.as_secs_f64()) as u32;
-            self.set_loop_frames(start_frame, end_frame)
-        }
-    }
+        let target = self.controls.channel_gain(self.channel, self.channels);
+        let gain = &mut self.gains[self.channel as usize];
+        *gain += (target - *gain) * self.smoothing;
+        let sample = sample * *gain;
 
-    pub fn clear_loop(&mut self) {
-        self.audio
-            .as_mut() // Get mutable reference to Option<Audio>
-            .and_then(|audio| {
-                audio.controller.as_mut() // Get mutable reference to Option<Controller>
-            })
-            .map(|controller| {
-                controller
-                    .lock() // Get lock on the Mutex
-                    .clear_loop()
-            });
+        self.channel = (self.channel + 1) % self.channels;
+        Some(sample)
     }
+}
 
-    pub fn set_loop_frames(&mut self, start_frame: u32, end_frame: u32) {
-        self.audio
-            .as_mut() // Get mutable reference to Option<Audio>
-            .and_then(|audio| {
-                audio.controller.as_mut() // Get mutable reference to Option<Controller>
-            })
-            .map(|controller| {
-                controller
-                    .lock() // Get lock on the Mutex
-                    .set_loop(start_frame, end_frame)
-            });
+impl<S> Source for ChannelStrip<S>
+where
+    S: Source<Item = f32>,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        self.inner.current_frame_len()
     }
 
-    pub fn get_preferred_transition_beats(
-        &self,
-        beat_index_ref: Arc<Mutex<Index>>,
-        beat: usize,
-        count: usize,
-    ) -> usearch::ffi::Matches {
-        let beat_features = {
-            let audio_data_map = AUDIO_DATA_MAP.lock(); // Lock the mutex here
-            audio_data_map
-                .get(&self.path)
-                .unwrap()
-                .clone()
-                .lock()
-                .beat_features
-                .clone()
-        };
+    fn channels(&self) -> u16 {
+        self.channels
+    }
 
-        let beat_features_guard = beat_features.lock();
-        let beat_features_ref = beat_features_guard.as_ref().unwrap();
-        // let beat_index_ref = self.beat_index.as_ref().unwrap().clone();
-        let beat_index = beat_index_ref.lock();
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
 
-        let results = beat_index
-            .search(&beat_features_ref[beat], count)
-            .expect("Search failed.");
-        results
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
     }
 
-    pub fn total_frames(&self) -> u64 {
-        self.audio.as_ref().unwrap().total_frames()
+    fn try_seek(&mut self, pos: Duration) -> Result<(), source::SeekError> {
+        self.inner.try_seek(pos)
     }
 }
 
@@ -748,16 +2105,18 @@ impl<T> AllomereMutex<T> {
 #[derivative(Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct Track {
+    pub id: TrackId,
     pub name: String,
+    // Linear gain, 1.0 is unity
+    pub gain: f32,
+    // -1.0 is hard left, 1.0 hard right
+    pub pan: f32,
+    pub muted: bool,
+    pub soloed: bool,
     // Should have the sections to be played
     #[serde(skip_deserializing)]
     pub clips: Vec<Arc<AllomereMutex<Clip>>>,
 
-    #[serde(skip_serializing)]
-    #[serde(skip_deserializing)]
-    #[derivative(Debug = "ignore")]
-    pub sink: Option<Sink>,
-
     // #[serde(skip_serializing)]
     // #[serde(skip_deserializing)]
     // #[derivative(Debug = "ignore")]
@@ -770,26 +2129,30 @@ pub struct Track {
     #[serde(skip_serializing)]
     #[serde(skip_deserializing)]
     #[derivative(Debug = "ignore")]
-    pub sources_queue_output: Option<SourcesQueueOutput<f32>>,
+    pub output: Option<ChannelStrip<TrackTimeline>>,
 
     #[serde(skip_serializing)]
     #[serde(skip_deserializing)]
     #[derivative(Debug = "ignore")]
-    pub playback_config: Option<Arc<SupportedStreamConfig>>,
+    schedule: Arc<Mutex<TimelineSchedule>>,
 
     #[serde(skip_serializing)]
     #[serde(skip_deserializing)]
     #[derivative(Debug = "ignore")]
-    beat_index_options: Option<Arc<Mutex<IndexOptions>>>,
+    channel_strip: Arc<ChannelStripControls>,
+
     #[serde(skip_serializing)]
     #[serde(skip_deserializing)]
     #[derivative(Debug = "ignore")]
-    pub beat_index: Option<Arc<Mutex<Index>>>,
+    pub playback_config: Option<Arc<SupportedStreamConfig>>,
 
     current: Option<usize>,
     #[serde(skip_serializing)]
     #[serde(skip_deserializing)]
     total_frames: Arc<RwLock<u64>>,
+    #[serde(skip_serializing)]
+    #[serde(skip_deserializing)]
+    transport: Arc<RwLock<Transport>>,
 }
 
 impl<T: Serialize> Serialize for AllomereMutex<T> {
@@ -814,8 +2177,16 @@ impl<T: Serialize> Serialize for AllomereMutex<T> {
 
 unsafe impl Send for Track {}
 
+impl Drop for Track {
+    fn drop(&mut self) {
+        self.schedule.lock().closed = true;
+        registry::unregister_track(self.id, &self.clips);
+    }
+}
+
 impl Track {
-    fn id() -> u64 {
+    // Numbers the default track names, the ids themselves are random
+    fn number() -> u64 {
         static COUNTER: AtomicU64 = AtomicU64::new(1);
 
         COUNTER.fetch_add(1, SeqCst)
@@ -825,38 +2196,92 @@ impl Track {
         name: Option<String>,
         playback_config: Arc<SupportedStreamConfig>,
         total_frames: Arc<RwLock<u64>>,
+        transport: Arc<RwLock<Transport>>,
     ) -> Self {
-        let (sink, sources_queue_output) = Sink::new_idle();
         // let (mixer_controller, mixer) = dynamic_mixer::mixer::<f32>(2, 44_100);
-        // sink.append(mixer);
 
         // match volume {
         //     Some(volume) => sink.set_volume(volume),
         //     _ => {}
         // }
 
-        let mut index_options = IndexOptions::default();
-        index_options.dimensions = 512; // Set the number of dimensions for vectors
-        index_options.metric = MetricKind::Cos; // Use cosine similarity for distance measurement
-        index_options.quantization = ScalarKind::F64; // Use 32-bit floating point numbers
-
-        let index = Index::new(&index_options).unwrap();
+        // The timeline starts wherever playback is, so a new track lines up with the others
+        let (timeline, schedule) = TrackTimeline::new(
+            playback_config.channels(),
+            playback_config.sample_rate().0,
+            *total_frames.read(),
+        );
 
-        let _ = index.reserve(1000);
+        let channel_strip = Arc::new(ChannelStripControls::default());
+        let output = ChannelStrip::new(
+            timeline,
+            playback_config.channels(),
+            playback_config.sample_rate().0,
+            channel_strip.clone(),
+        );
 
         Track {
-            name: name.unwrap_or_else(|| format!("Track {}", Self::id())),
+            id: TrackId::default(),
+            name: name.unwrap_or_else(|| format!("Track {}", Self::number())),
+            gain: 1.0,
+            pan: 0.0,
+            muted: false,
+            soloed: false,
             clips: (Vec::new()),
-            sink: Some(sink),
-            beat_index: Some(Arc::new(Mutex::new(index))),
-            beat_index_options: Some(Arc::new(Mutex::new(index_options))),
             // mixer_controller: Some(mixer_controller),
 
             // mixer_output: None,
             playback_config: Some(playback_config),
-            sources_queue_output: Some(sources_queue_output),
+            output: Some(output),
+            schedule,
+            channel_strip,
             current: None,
             total_frames,
+            transport,
+        }
+    }
+
+    pub fn set_gain(&mut self, gain: f32) {
+        self.gain = gain.max(0.0);
+        self.channel_strip.gain.store(self.gain.to_bits(), SeqCst);
+    }
+
+    pub fn set_pan(&mut self, pan: f32) {
+        self.pan = pan.clamp(-1.0, 1.0);
+        self.channel_strip.pan.store(self.pan.to_bits(), SeqCst);
+    }
+
+    // Mute and solo only take effect through `update_audible`, solo depends on every track
+    pub fn set_muted(&mut self, muted: bool) {
+        self.muted = muted;
+    }
+
+    pub fn set_soloed(&mut self, soloed: bool) {
+        self.soloed = soloed;
+    }
+
+    // With any track soloed only soloed tracks play, muted tracks never do
+    pub fn update_audible(tracks: &[Track]) {
+        let any_soloed = tracks.iter().any(|track| track.soloed);
+        for track in tracks {
+            track
+                .channel_strip
+                .audible
+                .store(!track.muted && (!any_soloed || track.soloed), SeqCst);
+        }
+    }
+
+    // Quiets a track that's been taken out of the project but is still in the
+    // mixer, `update_audible` brings it back
+    pub fn silence(&self) {
+        self.channel_strip.audible.store(false, SeqCst);
+    }
+
+    // Restretches every clip after the master tempo changes
+    pub fn sync_tempo(&self) {
+        let master_tempo = self.transport.read().master_tempo();
+        for clip in &self.clips {
+            clip.0.lock().sync_tempo(master_tempo);
         }
     }
 
@@ -870,114 +2295,70 @@ impl Track {
         //     None
         // };
 
-        // self.playback_config.unwrap().sample_rate()
-        // find a clip with a start_at, every other clip follows that
-        // unless explicitly states with another start_at
-
-        if self
-            .clips
-            .iter()
-            .find(|&clip| clip.0.lock().start_at.is_some())
-            .is_none()
-        {
-            let track_frames = self.total_frames();
-            // println!("Track Duration: {}", track_duration);
-            match track_frames {
-                Some(track_frames) => {
-                    let playback_frames = { *self.total_frames.read() };
-
-                    if track_frames > playback_frames {
-                        clip.start_at.replace(track_frames);
-                    } else {
-                        clip.start_at.replace(playback_frames);
-                    }
-                }
-                _ => {}
-            }
-        }
-        match clip.audio {
-            Some(ref audio) => {
-                let source = &audio.source;
-                match self.sink {
-                    Some(ref sink) => {
-                        sink.append(source.clone().convert_samples::<f32>());
-                    }
-                    _ => {}
-                }
-            }
-            None => todo!(),
+        // Clips without a start_at go after the last one, or at the playhead once
+        // playback has moved past it
+        if clip.start_at.is_none() {
+            let playback_frames = { *self.total_frames.read() };
+            let end_frame = self.end_frame(self.sample_rate()).unwrap_or_default();
+            clip.start_at.replace(end_frame.0.max(playback_frames));
         }
+        clip.sync_tempo(self.transport.read().master_tempo());
         // {
         //     clip.start_at = Some(*self.total_frames.read());
         // }
         let path = clip.path.clone();
         let clip_ref = Arc::new(AllomereMutex::new(clip));
-        self.clips.push(clip_ref.clone());
+        self.schedule_clip(clip_ref.clone());
 
         // let audio_data_ref = audio_data.clone();
 
         // need to fix this, will prolly spawn a thread
 
-        let beat_index_ref = self.beat_index.as_ref().unwrap().clone();
+        let transport = self.transport.clone();
 
-        tauri::async_runtime::spawn(async move {
-            println!("Adding features to beat index");
-            let beat_features = {
+        // Blocks until analysis finishes, so it goes on the blocking pool where it
+        // can't hold up the analysis tasks themselves
+        tauri::async_runtime::spawn_blocking(move || {
+            let analysis = {
                 let audio_data_map = AUDIO_DATA_MAP.lock(); // Lock the mutex here
                 audio_data_map
                     .get(&path)
                     .unwrap()
                     .clone()
                     .lock()
-                    .beat_features
+                    .analysis
                     .clone()
             };
 
-            let beat_features_guard = beat_features.lock();
-            let beat_features_ref = beat_features_guard.as_ref().unwrap();
-            println!("After beat features lock");
+            // By then the tempo is known and the song is in the library
+            // Analysis failed, the clip still plays but has no transitions
+            if !analysis.wait() {
+                return;
+            }
 
-            let beat_index = beat_index_ref.lock();
-            for (i, feature) in beat_features_ref.iter().enumerate() {
-                let now = Instant::now();
+            let mut clip = clip_ref.0.lock();
+            clip.sync_tempo(transport.read().master_tempo());
 
-                // Cause of opt, each beat is shifted
-                // if let Err(e) = beat_index.add((i * 4) as u64, feature) {
-                if let Err(e) = beat_index.add((i) as u64, feature) {
-                    eprintln!("Failed to add feature to beat index: {:?}", e);
-                }
-                println!(
-                    "{}/{}: {:?}",
-                    i,
-                    beat_features_ref.len(),
-                    now.elapsed().as_secs_f32()
-                );
-            }
-            let results = beat_index
-                .search(&beat_features_ref[0], 5)
-                .expect("Search failed.");
-            for (key, distance) in results.keys.iter().zip(results.distances.iter()) {
-                println!("Key: {}, Distance: {}", key, distance);
+            // Keep loops that were restored or set before analysis finished
+            if clip.has_loop() {
+                return;
             }
-
-            let beat_track = {
-                let audio_data_map = AUDIO_DATA_MAP.lock(); // Lock the mutex here
-                audio_data_map
-                    .get(&path)
-                    .unwrap()
-                    .clone()
-                    .lock()
-                    .beat_track
-                    .clone()
+            let beat_track = match clip.audio_data() {
+                Some(audio_data) => audio_data.lock().beat_track.clone(),
+                None => None,
             };
-
-            {
-                clip_ref.0.lock().set_loop_frames(
-                    beat_track.as_ref().unwrap()[0],
-                    beat_track.as_ref().unwrap()[results.keys[1] as usize],
-                );
+            match clip.get_preferred_transition_beats(0, 1) {
+                Ok(transitions) => {
+                    if let (Some(beat_track), Some(&(beat, _))) =
+                        (beat_track.as_ref(), transitions.first())
+                    {
+                        if let Err(e) = clip.set_loop_frames(beat_track[0], beat_track[beat]) {
+                            eprintln!("Failed to loop {}: {}", clip.name, e);
+                        }
+                    }
+                }
+                Err(e) => eprintln!("Failed to find a loop for {}: {}", clip.name, e),
             }
-            println!("Added features to beat index");
         });
 
         // {
@@ -1006,69 +2387,185 @@ impl Track {
         // }
     }
 
-    pub fn total_duration(&mut self) -> Option<Duration> {
-        let mut total_duration = Duration::new(0, 0);
-        for clip in &mut self.clips {
-            let clip_duration = clip
-                .0
-                .lock()
-                .audio
-                .as_ref()
-                .unwrap()
-                .source
-                .total_duration();
-            match clip_duration {
-                Some(clip_duration) => match total_duration.checked_add(clip_duration) {
-                    Some(new_total_duration) => {
-                        total_duration = new_total_duration;
-                    }
-                    _ => {
-                        return None;
-                    }
-                },
-                _ => {
-                    return None;
+    // Puts a clip on the timeline at its start_at, cued to wherever the track is
+    pub fn schedule_clip(&mut self, clip_ref: Arc<AllomereMutex<Clip>>) {
+        let sample_rate = self.sample_rate();
+        {
+            let clip = clip_ref.0.lock();
+            let position = {
+                let mut schedule = self.schedule.lock();
+                if let Some(source) = clip.timeline_source(sample_rate) {
+                    schedule.add(clip.id, clip.start_frame(), source);
+                }
+                TimelineFrame(schedule.position())
+            };
+            if let Err(e) = Self::cue(&clip, position, sample_rate) {
+                eprintln!("Failed to cue {}: {:?}", clip.name, e);
+            }
+            registry::register_clip(clip.id, &clip_ref, self.id);
+        }
+        self.clips.push(clip_ref);
+    }
+
+    // Clip `id` if it's on this track
+    pub fn clip(&self, id: ClipId) -> Option<Arc<AllomereMutex<Clip>>> {
+        registry::clip(id)
+            .filter(|(_, track_id)| *track_id == self.id)
+            .map(|(clip, _)| clip)
+    }
+
+    // Takes clip `id` off the track, None if it isn't on it
+    pub fn take_clip(&mut self, id: ClipId) -> Option<Arc<AllomereMutex<Clip>>> {
+        let clip = self.clip(id)?;
+        let index = self
+            .clips
+            .iter()
+            .position(|clip_ref| Arc::ptr_eq(clip_ref, &clip))?;
+        self.schedule.lock().remove(id);
+        registry::unregister_clip(id, self.id);
+        Some(self.clips.remove(index))
+    }
+
+    // Cuts clip `id` at `frame` on the timeline, the part after it becomes a new
+    // clip on the same track. Returns the new clip's id
+    pub fn split_clip(
+        &mut self,
+        id: ClipId,
+        frame: TimelineFrame,
+    ) -> Result<ClipId, AllomereError> {
+        let sample_rate = self.sample_rate();
+        let clip_ref = self.clip(id).ok_or(AllomereError::ClipNotFound(id))?;
+
+        let tail = {
+            let mut clip = clip_ref.0.lock();
+            let start_frame = clip.start_frame().0;
+            if frame.0 <= start_frame
+                || frame.0 >= start_frame + clip.timeline_frames(sample_rate).0
+            {
+                return Err(AllomereError::FrameOutOfRange { id, frame: frame.0 });
+            }
+
+            let split_frame = (clip.trim_start_frame().0
+                + clip
+                    .frame_map(sample_rate)
+                    .to_source(TimelineFrame(frame.0 - start_frame))
+                    .0) as u32;
+            let edits = clip.edits().unwrap_or_default();
+
+            // The fade in stays on the head and the fade out moves to the tail
+            let mut tail = Clip::new(&clip.path)?;
+            tail.start_at = Some(frame.0);
+            tail.set_edits(ClipEdits {
+                trim_start_frame: split_frame,
+                fade_in_frames: 0,
+                ..edits.clone()
+            });
+            tail.sync_tempo(self.transport.read().master_tempo());
+
+            clip.set_edits(ClipEdits {
+                trim_end_frame: Some(split_frame),
+                fade_out_frames: 0,
+                ..edits
+            });
+            tail
+        };
+
+        let tail_id = tail.id;
+        self.schedule_clip(Arc::new(AllomereMutex::new(tail)));
+        Ok(tail_id)
+    }
+
+    // Frame on the playback timeline where the track's last clip stops playing
+    pub fn end_frame(&self, sample_rate: u32) -> Option<TimelineFrame> {
+        self.clips
+            .iter()
+            .map(|clip_ref| {
+                let clip = clip_ref.0.lock();
+                TimelineFrame(clip.start_frame().0 + clip.played_timeline_frames(sample_rate).0)
+            })
+            .max()
+    }
+
+    // Schedules fresh sources the same way so the track can be rendered
+    // without touching the live stream
+    pub fn render_source(&self, sample_rate: u32) -> Option<ChannelStrip<TrackTimeline>> {
+        if self.clips.is_empty() {
+            return None;
+        }
+        let (timeline, schedule) = TrackTimeline::new(self.channels(), sample_rate, 0);
+
+        for clip_ref in &self.clips {
+            let clip = clip_ref.0.lock();
+            match clip.render_source(sample_rate) {
+                Some(source) => schedule.lock().add(clip.id, clip.start_frame(), source),
+                None => {
+                    eprintln!("Failed to build render source for clip");
                 }
             }
         }
-        Some(total_duration)
+
+        Some(ChannelStrip::new(
+            timeline,
+            self.channels(),
+            sample_rate,
+            self.channel_strip.clone(),
+        ))
+    }
+
+    pub fn channels(&self) -> u16 {
+        self.playback_config
+            .as_ref()
+            .expect("Playback config should exist")
+            .channels()
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.playback_config
+            .as_ref()
+            .expect("Playback config should exist")
+            .sample_rate()
+            .0
+    }
+
+    // Frame in the source of clip `id` that plays at `frame` on the timeline,
+    // None if the clip isn't on this track
+    pub fn source_frame(&self, id: ClipId, frame: TimelineFrame) -> Option<SourceFrame> {
+        let sample_rate = self.sample_rate();
+        let clip_ref = self.clip(id)?;
+        let clip = clip_ref.0.lock();
+        let offset = TimelineFrame(frame.0.saturating_sub(clip.start_frame().0));
+
+        Some(SourceFrame(
+            clip.trim_start_frame().0 + clip.frame_map(sample_rate).to_source(offset).0,
+        ))
     }
 
-    pub fn total_frames(&self) -> Option<u64> {
-        let mut total_duration: u64 = 0;
-        for ref clip in &self.clips {
-            let clip_duration = clip.0.lock().audio.as_ref().unwrap().total_frames();
-            total_duration += clip_duration;
+    // Moves the clip's source to what it plays at `frame` on the timeline: its
+    // start while the timeline hasn't reached it and nothing once it's past its end
+    // Between source frames the resampler picks up part way, so a seek lands on
+    // the exact timeline frame whatever the file's rate
+    fn cue(clip: &Clip, frame: TimelineFrame, sample_rate: u32) -> Result<(), source::SeekError> {
+        let start_frame = clip.start_frame().0;
+        if frame.0 <= start_frame {
+            clip.seek_frame(SourceFrame(0), 0)?;
+        } else if frame.0 < start_frame + clip.timeline_frames(sample_rate).0 {
+            let (offset, remainder) = clip
+                .frame_map(sample_rate)
+                .to_source_position(TimelineFrame(frame.0 - start_frame));
+            clip.seek_frame(SourceFrame(clip.trim_start_frame().0 + offset.0), remainder)?;
+        } else {
+            clip.finish();
         }
-        Some(total_duration)
+        Ok(())
     }
 
-    pub fn try_seek(&mut self, pos: Duration) -> Result<(), source::SeekError> {
-        println!("Track try_seek {:?}", pos);
-        // This fails when paused cause periodic_access doesn't run any more
-        // And the sink is not aware that it is paused
-        // So it waits for an event to be released to seek, but that does not happen
-        // We might have to keep track of the source that is being played then seek in the source itself
-        let _ = self.sink.as_ref().unwrap().try_seek(pos);
-        println!("Track Finished try_seek");
-
-        // let mut current_pos = Duration::from_secs(0);
-        // for ref mut clip in &mut self.clips {
-        //     let clip_duration = clip
-        //         .audio
-        //         .as_ref()
-        //         .unwrap()
-        //         .source
-        //         .total_duration()
-        //         .unwrap();
-        //     if current_pos + clip_duration >= pos {
-        //         let seek_duration = pos - current_pos;
-        //         clip.try_seek(seek_duration)?;
-        //         return Ok(());
-        //     }
-        //     current_pos += clip_duration;
-        // }
-        // Err(source::SeekError)
+    // Cues every clip for `frame` and moves the track's timeline there
+    pub fn try_seek(&mut self, frame: TimelineFrame) -> Result<(), source::SeekError> {
+        let sample_rate = self.sample_rate();
+        for clip_ref in &self.clips {
+            Self::cue(&clip_ref.0.lock(), frame, sample_rate)?;
+        }
+        self.schedule.lock().position = frame.0;
         Ok(())
     }
 }
@@ -1118,15 +2615,22 @@ impl Track {
 pub struct PlaybackState {
     #[derivative(Debug = "ignore")]
     pub mixer: Arc<DynamicMixerController<f32>>,
+    // Kept so the backend can be swapped for one on another device
     #[derivative(Debug = "ignore")]
-    pub stream: Arc<Mutex<Stream>>,
+    pub mixer_rx: MixerOutput,
+    #[derivative(Debug = "ignore")]
+    pub backend: Arc<Mutex<Box<dyn OutputBackend>>>,
     #[derivative(Debug = "ignore")]
     pub config: Arc<SupportedStreamConfig>,
+    // Other mixers played on spare channels of the output, kept across device switches
     #[derivative(Debug = "ignore")]
-    pub device: Device,
+    pub channel_route: SharedChannelRoute,
+    // Set while playback is moving off a lost device
+    recovering: AtomicBool,
 
     pub is_paused: Arc<RwLock<bool>>,
     pub total_frames: Arc<RwLock<u64>>,
+    pub transport: Arc<RwLock<Transport>>,
 }
 
 unsafe impl Send for PlaybackState {}
@@ -1144,61 +2648,408 @@ impl Serialize for PlaybackState {
         let mut helper = HashMap::new();
         helper.insert("isPaused", Value::Boolean(*self.is_paused.read()));
         helper.insert("totalFrames", Value::UInt64(*self.total_frames.read()));
+
+        // The beat grid position is derived from the playback clock each time
+        let transport = *self.transport.read();
+        let position = transport.position(*self.total_frames.read(), self.config.sample_rate().0);
+        helper.insert("tempo", Value::Float32(transport.tempo));
+        helper.insert("tempoSync", Value::Boolean(transport.tempo_sync));
+        helper.insert(
+            "beatsPerBar",
+            Value::UInt32(transport.time_signature.beats_per_bar),
+        );
+        helper.insert(
+            "beatUnit",
+            Value::UInt32(transport.time_signature.beat_unit),
+        );
+        helper.insert("gridOrigin", Value::UInt64(transport.grid_origin));
+        helper.insert("bar", Value::Int64(position.bar));
+        helper.insert("beat", Value::UInt32(position.beat));
+        helper.insert("tick", Value::UInt32(position.tick));
         helper.insert("channels", Value::UInt16(self.config.channels()));
         helper.insert("sampleRate", Value::UInt32(self.config.sample_rate().0));
+        let backend = self.backend.lock();
+        helper.insert("outputBackend", Value::String(backend.name().to_string()));
+        if let Some(device) = backend.device() {
+            helper.insert("outputDevice", Value::String(device.name));
+        }
+        drop(backend);
 
         helper.serialize(serializer)
     }
 }
 
+impl PlaybackState {
+    // Playback without a sound card, see `NullBackend::new` for how `period` is used
+    pub fn headless(period: Option<Duration>) -> PlaybackState {
+        let config = NullBackend::config();
+
+        let (mixer_tx, mixer_rx) =
+            dynamic_mixer::mixer::<f32>(config.channels(), config.sample_rate().0);
+        let mixer_rx = Arc::new(Mutex::new(mixer_rx));
+        let total_frames = Arc::new(RwLock::new(0u64));
+
+        let backend = NullBackend::new(&config, mixer_rx.clone(), total_frames.clone(), period);
+
+        PlaybackState {
+            mixer: mixer_tx,
+            mixer_rx,
+            backend: Arc::new(Mutex::new(Box::new(backend))),
+            config: Arc::new(config),
+            channel_route: Arc::new(Mutex::new(None)),
+            recovering: AtomicBool::new(false),
+            is_paused: Arc::new(RwLock::new(true)),
+            total_frames,
+            transport: Arc::new(RwLock::new(Transport::default())),
+        }
+    }
+
+    // Moves playback onto device `name`, or the default device for None. The mixer,
+    // the tracks on it and the playback clock carry over, only the stream is rebuilt
+    pub fn set_output_device(
+        &self,
+        name: Option<&str>,
+        sample_rate: Option<u32>,
+        buffer_size: Option<u32>,
+    ) -> Result<(), AllomereError> {
+        let device = output::find_device(name)?;
+        let config = output::stream_config(&device, sample_rate, buffer_size)?;
+        self.replace_backend(|| {
+            let backend = CpalBackend::new(
+                device,
+                config,
+                self.mixer_rx.clone(),
+                &self.config,
+                self.total_frames.clone(),
+                self.channel_route.clone(),
+                on_output_device_lost,
+            )?;
+            Ok(Box::new(backend))
+        })
+    }
+
+    // Runs when the stream reports its device gone, playback moves to the
+    // default device or to null output when there isn't one
+    fn recover_output(&self) {
+        if let Err(e) = self.set_output_device(None, None, None) {
+            eprintln!(
+                "No output device to move to, falling back to null output: {}",
+                e
+            );
+            let backend = NullBackend::new(
+                &self.config,
+                self.mixer_rx.clone(),
+                self.total_frames.clone(),
+                Some(Duration::from_millis(10)),
+            );
+            if let Err(e) = self.replace_backend(|| Ok(Box::new(backend))) {
+                eprintln!("Failed to start null output: {}", e);
+            }
+        }
+    }
+
+    // The old backend is paused before `build` makes the new one so they never both
+    // pull from the mixer, and resumed if the new one fails to build or start
+    fn replace_backend(
+        &self,
+        build: impl FnOnce() -> Result<Box<dyn OutputBackend>, AllomereError>,
+    ) -> Result<(), AllomereError> {
+        let mut current = self.backend.lock();
+        // A lost device can't be paused any more, it's dropped all the same
+        if let Err(e) = current.pause() {
+            eprintln!("Failed to pause the old output: {}", e);
+        }
+
+        let is_paused = *self.is_paused.read();
+        let started = build().and_then(|backend| {
+            if is_paused {
+                backend.pause()?;
+            } else {
+                backend.play()?;
+            }
+            Ok(backend)
+        });
+        match started {
+            Ok(backend) => {
+                *current = backend;
+                Ok(())
+            }
+            Err(e) => {
+                if !is_paused {
+                    if let Err(e) = current.play() {
+                        eprintln!("Failed to resume the old output: {}", e);
+                    }
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+// Called on the stream's own thread, which the stream can't be dropped from
+fn on_output_device_lost() {
+    thread::spawn(|| {
+        let Some(app_handle) = states::APP_HANDLE.lock().clone() else {
+            return;
+        };
+        let playback_state = app_handle.state::<PlaybackState>();
+        // Already moving, the stream can report the loss more than once
+        if playback_state.recovering.swap(true, SeqCst) {
+            return;
+        }
+        let lost_device = playback_state
+            .backend
+            .lock()
+            .device()
+            .map(|device| device.name)
+            .unwrap_or_default();
+        playback_state.recover_output();
+        playback_state.recovering.store(false, SeqCst);
+        if app_handle
+            .state::<PreviewState>()
+            .fit_main_output(playback_state.inner())
+        {
+            let _ = states::emit_state_sync_handle(
+                "previewOutput",
+                &None::<PreviewOutput>,
+                &app_handle,
+            );
+        }
+
+        let _ = states::emit_state_sync_handle("playback", playback_state.inner(), &app_handle);
+        let _ = states::emit_state_sync_handle(
+            "error",
+            &AllomereError::OutputDeviceLost(lost_device),
+            &app_handle,
+        );
+    });
+}
+
 pub fn set_default_state() -> PlaybackState {
-    let default_device = cpal::default_host()
-        .default_output_device()
-        .ok_or(StreamError::NoDevice)
-        .expect("Here");
+    if env::var("ALLOMERE_OUTPUT").is_ok_and(|output| output == "null") {
+        return PlaybackState::headless(Some(Duration::from_millis(10)));
+    }
 
-    let config = default_device.default_output_config().expect("Here 2");
-    let error_callback = |err| eprintln!("an error occurred on output stream: {}", err);
+    let (device, config) = match output::default_device() {
+        Ok(default_device) => default_device,
+        Err(e) => {
+            eprintln!("No output device, falling back to null output: {}", e);
+            return PlaybackState::headless(Some(Duration::from_millis(10)));
+        }
+    };
 
-    let (mixer_tx, mut mixer_rx) =
+    let (mixer_tx, mixer_rx) =
         dynamic_mixer::mixer::<f32>(config.channels(), config.sample_rate().0);
+    let mixer_rx = Arc::new(Mutex::new(mixer_rx));
     let total_frames = Arc::new(RwLock::new(0u64));
-    let total_frames_clone = total_frames.clone();
-
-    let channels = config.channels() as usize;
-
-    let stream = default_device
-        .build_output_stream::<f32, _, _>(
-            &config.config(),
-            move |data, _| {
-                {
-                    // let mut total_samples_guard = total_samples_clone.write();
-                    *(total_frames_clone.write()) += (data.len() / channels) as u64;
-                    // let total_samples = *(total_samples_guard);
-                    // drop(total_samples_guard);
-
-                    // match *window_clone.lock() {
-                    //     Some(ref window) => {
-                    //         states::emit_state_sync("totalSamples", &total_samples, &window);
-                    //     }
-                    //     _ => {}
-                    // }
-                    // states::emit_state_sync("totalSamples", &total_samples, &window);
-                }
-                data.iter_mut()
-                    .for_each(|d| *d = mixer_rx.next().unwrap_or(0f32))
-            },
-            error_callback,
-            None,
-        )
-        .expect("Here");
+    let channel_route: SharedChannelRoute = Arc::new(Mutex::new(None));
+
+    let backend = match CpalBackend::new(
+        device,
+        config.config(),
+        mixer_rx.clone(),
+        &config,
+        total_frames.clone(),
+        channel_route.clone(),
+        on_output_device_lost,
+    ) {
+        Ok(backend) => backend,
+        Err(e) => {
+            eprintln!(
+                "Failed to build output stream, falling back to null output: {}",
+                e
+            );
+            return PlaybackState::headless(Some(Duration::from_millis(10)));
+        }
+    };
 
     PlaybackState {
         mixer: mixer_tx,
-        stream: Arc::new(Mutex::new(stream)),
-        device: default_device,
+        mixer_rx,
+        backend: Arc::new(Mutex::new(Box::new(backend))),
         config: Arc::new(config),
+        channel_route,
+        recovering: AtomicBool::new(false),
         is_paused: Arc::new(RwLock::new(true)),
         total_frames,
+        transport: Arc::new(RwLock::new(Transport::default())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::path::PathBuf;
+
+    use crate::states::pcm::CHUNK_FRAMES;
+
+    // Chunks of decoded audio in the test file, enough that the loop target is
+    // one playback never reads ahead to
+    const TEST_CHUNKS: u64 = 8;
+
+    // Stereo at the null output's rate, so nothing is resampled. The left channel
+    // is constant and the right one tells which chunk of the file is playing
+    fn write_test_file() -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("allomere-headless-{}.wav", std::process::id()));
+        let mut writer = hound::WavWriter::create(
+            &path,
+            hound::WavSpec {
+                channels: NullBackend::CHANNELS,
+                sample_rate: NullBackend::SAMPLE_RATE,
+                bits_per_sample: 16,
+                sample_format: hound::SampleFormat::Int,
+            },
+        )
+        .unwrap();
+        for frame in 0..TEST_CHUNKS * CHUNK_FRAMES {
+            writer.write_sample(8192i16).unwrap();
+            writer
+                .write_sample(((frame / CHUNK_FRAMES + 1) * 1024) as i16)
+                .unwrap();
+        }
+        writer.finalize().unwrap();
+        path
+    }
+
+    // Without going through AUDIO_DATA_MAP, so no analysis reads the file
+    fn test_clip(path: &Path) -> Clip {
+        let sound = Sound::load(path.to_str().unwrap()).unwrap();
+        // Waits for the decoder to finish, only the last chunk is read and cached
+        sound.slice((TEST_CHUNKS * CHUNK_FRAMES - 1) as i64, 2);
+        let (source, controller) = CustomSource::new(&sound, sound.stream());
+        Clip {
+            path: path.to_string_lossy().to_string(),
+            name: "headless".to_string(),
+            audio: Some(Audio {
+                source,
+                controller: Some(controller),
+                stretch: Arc::new(TimeStretchControls::default()),
+                resample: Arc::new(ResampleControls::default()),
+            }),
+            start_at: Some(0),
+            id: ClipId::default(),
+        }
+    }
+
+    // Gives the prefetch thread time to read, as a real device's period would
+    fn render(backend: &dyn OutputBackend, frames: u64) -> Vec<f32> {
+        thread::sleep(Duration::from_millis(200));
+        backend.advance(frames).unwrap()
+    }
+
+    // Every frame came from `chunk` of the test file, none of it is silence
+    fn assert_chunk(rendered: &[f32], chunk: u64) {
+        let level = (chunk + 1) as f32 / 32.0;
+        for (i, frame) in rendered.chunks(2).enumerate() {
+            assert!(
+                (frame[0] - 0.25).abs() < 1e-6 && (frame[1] - level).abs() < 1e-6,
+                "frame {} is {:?}, expected chunk {}",
+                i,
+                frame,
+                chunk
+            );
+        }
+    }
+
+    #[test]
+    fn null_output_plays_seeks_and_loops_without_dropouts() {
+        let path = write_test_file();
+        let state = PlaybackState::headless(None);
+        let mut track = Track::new(
+            None,
+            state.config.clone(),
+            state.total_frames.clone(),
+            state.transport.clone(),
+        );
+        state.mixer.add(track.output.take().unwrap());
+        let clip_ref = Arc::new(AllomereMutex::new(test_clip(&path)));
+        track.schedule_clip(clip_ref.clone());
+        let current_frame = || {
+            clip_ref
+                .0
+                .lock()
+                .audio
+                .as_ref()
+                .unwrap()
+                .source
+                .current_frame()
+        };
+
+        let backend = state.backend.lock();
+        backend.play().unwrap();
+
+        assert_chunk(&render(&**backend, 4800), 0);
+        assert_eq!(*state.total_frames.read(), 4800);
+        assert_eq!(current_frame(), 4800);
+
+        // Far past what's been read ahead
+        let seek_frame = 6 * CHUNK_FRAMES;
+        track.try_seek(TimelineFrame(seek_frame)).unwrap();
+        assert_chunk(&render(&**backend, 4800), 6);
+        assert_eq!(current_frame(), seek_frame + 4800);
+
+        // Back to a chunk nothing has read yet, the seam has to play it straight away
+        let loop_start = 4 * CHUNK_FRAMES;
+        let loop_end = seek_frame + 9600;
+        clip_ref
+            .0
+            .lock()
+            .set_loop_frames(loop_start as u32, loop_end as u32)
+            .unwrap();
+        assert_chunk(&render(&**backend, 4800), 6);
+        assert_chunk(&render(&**backend, 4800), 4);
+        assert_eq!(current_frame(), loop_start + 4800);
+
+        drop(backend);
+        drop(track);
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn frame_map_doesnt_drift_between_rates() {
+        let frame_map = FrameMap::new(44_100, 48_000, 1.0);
+
+        for seconds in [1, 60, 3600] {
+            assert_eq!(
+                frame_map.to_timeline(SourceFrame(44_100 * seconds)),
+                TimelineFrame(48_000 * seconds)
+            );
+            assert_eq!(
+                frame_map.to_source_position(TimelineFrame(48_000 * seconds)),
+                (SourceFrame(44_100 * seconds), 0)
+            );
+        }
+    }
+
+    #[test]
+    fn frame_map_keeps_the_offset_past_a_source_frame() {
+        let frame_map = FrameMap::new(44_100, 48_000, 1.0);
+
+        assert_eq!(
+            frame_map.to_source_position(TimelineFrame(1)),
+            (SourceFrame(0), 44_100)
+        );
+        assert_eq!(frame_map.to_source(TimelineFrame(1)), SourceFrame(1));
+        for frame in 0..10_000 {
+            let timeline = frame_map.to_timeline(SourceFrame(frame));
+            assert_eq!(frame_map.to_source(timeline), SourceFrame(frame));
+        }
+    }
+
+    #[test]
+    fn frame_map_follows_the_playback_rate() {
+        let frame_map = FrameMap::new(48_000, 48_000, 2.0);
+
+        assert_eq!(
+            frame_map.to_timeline(SourceFrame(96_000)),
+            TimelineFrame(48_000)
+        );
+        assert_eq!(
+            frame_map.to_source(TimelineFrame(48_000)),
+            SourceFrame(96_000)
+        );
     }
 }
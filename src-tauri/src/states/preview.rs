@@ -0,0 +1,228 @@
+use anyhow::anyhow;
+
+use parking_lot::{Mutex, RwLock};
+
+use rodio::cpal::{SampleFormat, SupportedBufferSize, SupportedStreamConfig};
+use rodio::dynamic_mixer::{self, DynamicMixerController};
+use rodio::Source;
+
+use serde::{Deserialize, Serialize};
+
+use tauri::Manager;
+
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::error::AllomereError;
+use crate::states;
+use crate::states::output::{self, ChannelRoute, CpalBackend, MixerOutput, OutputBackend};
+use crate::states::playback::{ClipSource, CustomSource, PlaybackState};
+
+// Fade at the start of an audition so it doesn't click in
+const FADE_IN: Duration = Duration::from_millis(10);
+
+// Where the preview bus is heard
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum PreviewOutput {
+    // A stream of its own on device `name`, the default device for None
+    Device {
+        name: Option<String>,
+    },
+    // Channels of the main output from `first_channel` on, counting from 0 so 2 is
+    // channels 3/4. Only heard while the main output is playing
+    #[serde(rename_all = "camelCase")]
+    Channels {
+        first_channel: u16,
+    },
+}
+
+// A cue bus for auditioning transitions, usually in headphones. It has its own
+// mixer, nothing played on it reaches the main mix or moves the playhead
+pub struct PreviewState {
+    mixer: Arc<DynamicMixerController<f32>>,
+    mixer_rx: MixerOutput,
+    // Stereo at the project's rate, whichever output the bus is on
+    config: SupportedStreamConfig,
+    output: Mutex<Option<PreviewOutput>>,
+    // Only set while the bus has a device of its own
+    backend: Mutex<Option<Box<dyn OutputBackend>>>,
+    total_frames: Arc<RwLock<u64>>,
+    // Audition playing now, finished as soon as the next one starts
+    playing: Mutex<Option<CustomSource>>,
+}
+
+unsafe impl Send for PreviewState {}
+unsafe impl Sync for PreviewState {}
+
+impl PreviewState {
+    pub fn new(playback_config: &SupportedStreamConfig) -> Self {
+        let config = SupportedStreamConfig::new(
+            2,
+            playback_config.sample_rate(),
+            SupportedBufferSize::Unknown,
+            SampleFormat::F32,
+        );
+        let (mixer, mixer_rx) =
+            dynamic_mixer::mixer::<f32>(config.channels(), config.sample_rate().0);
+
+        PreviewState {
+            mixer,
+            mixer_rx: Arc::new(Mutex::new(mixer_rx)),
+            config,
+            output: Mutex::new(None),
+            backend: Mutex::new(None),
+            total_frames: Arc::new(RwLock::new(0)),
+            playing: Mutex::new(None),
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.config.sample_rate().0
+    }
+
+    pub fn output(&self) -> Option<PreviewOutput> {
+        self.output.lock().clone()
+    }
+
+    // Moves the bus to `output`, None takes it off every output. The old output
+    // keeps playing if the new one can't be opened
+    pub fn set_output(
+        &self,
+        output: Option<PreviewOutput>,
+        playback_state: &PlaybackState,
+    ) -> Result<(), AllomereError> {
+        let mut backend: Option<Box<dyn OutputBackend>> = None;
+        let mut route = None;
+        match &output {
+            Some(PreviewOutput::Device { name }) => {
+                let device = output::find_device(name.as_deref())?;
+                let config = output::stream_config(&device, None, None)?;
+                let device_backend = CpalBackend::new(
+                    device,
+                    config,
+                    self.mixer_rx.clone(),
+                    &self.config,
+                    self.total_frames.clone(),
+                    Arc::new(Mutex::new(None)),
+                    on_preview_device_lost,
+                )?;
+                device_backend.play()?;
+                backend = Some(Box::new(device_backend));
+            }
+            Some(PreviewOutput::Channels { first_channel }) => {
+                let channel_route =
+                    ChannelRoute::new(self.mixer_rx.clone(), &self.config, *first_channel);
+                let channels = playback_state
+                    .backend
+                    .lock()
+                    .device()
+                    .map_or(0, |device| device.channels);
+                if channel_route.last_channel() > channels {
+                    return Err(AllomereError::Other(anyhow!(
+                        "The main output has {} channels, the preview needs channels {} to {}",
+                        channels,
+                        first_channel + 1,
+                        channel_route.last_channel()
+                    )));
+                }
+                route = Some(channel_route);
+            }
+            None => {}
+        }
+
+        *playback_state.channel_route.lock() = route;
+        *self.backend.lock() = backend;
+        *self.output.lock() = output;
+        Ok(())
+    }
+
+    // Takes the bus off the main output's channels once the main output is on a
+    // device that doesn't have them. Returns true if it did
+    pub fn fit_main_output(&self, playback_state: &PlaybackState) -> bool {
+        let channels = playback_state
+            .backend
+            .lock()
+            .device()
+            .map_or(0, |device| device.channels);
+        let mut channel_route = playback_state.channel_route.lock();
+        if !channel_route
+            .as_ref()
+            .is_some_and(|route| route.last_channel() > channels)
+        {
+            return false;
+        }
+        *channel_route = None;
+        drop(channel_route);
+
+        self.stop();
+        *self.output.lock() = None;
+        true
+    }
+
+    // Plays `source` on the bus for `duration`, ending the audition before it. On
+    // the main output's channels that only works while the main output plays
+    pub fn play(
+        &self,
+        source: ClipSource,
+        duration: Duration,
+        playback_state: &PlaybackState,
+    ) -> Result<(), AllomereError> {
+        match *self.output.lock() {
+            None => return Err(AllomereError::NoPreviewOutput),
+            Some(PreviewOutput::Channels { .. }) if *playback_state.is_paused.read() => {
+                return Err(AllomereError::PreviewOutputPaused)
+            }
+            Some(_) => {}
+        }
+
+        let custom_source = source.inner().inner().clone();
+        if let Some(previous) = self.playing.lock().replace(custom_source) {
+            previous.finish();
+        }
+        self.mixer
+            .add(source.fade_in(FADE_IN).take_duration(duration));
+        Ok(())
+    }
+
+    // False if nothing was playing
+    pub fn stop(&self) -> bool {
+        match self.playing.lock().take() {
+            Some(source) => {
+                source.finish();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+// Called on the stream's own thread, which the stream can't be dropped from.
+// The bus goes silent rather than moving to another device, the main output
+// may well be on the default one
+fn on_preview_device_lost() {
+    thread::spawn(|| {
+        let Some(app_handle) = states::APP_HANDLE.lock().clone() else {
+            return;
+        };
+        let preview_state = app_handle.state::<PreviewState>();
+        let lost_device = preview_state
+            .backend
+            .lock()
+            .take()
+            .and_then(|backend| backend.device())
+            .map(|device| device.name)
+            .unwrap_or_default();
+        preview_state.stop();
+        *preview_state.output.lock() = None;
+
+        let _ =
+            states::emit_state_sync_handle("previewOutput", &None::<PreviewOutput>, &app_handle);
+        let _ = states::emit_state_sync_handle(
+            "error",
+            &AllomereError::OutputDeviceLost(lost_device),
+            &app_handle,
+        );
+    });
+}
@@ -0,0 +1,179 @@
+use anyhow::{anyhow, Result};
+
+use serde::{Deserialize, Serialize};
+
+use std::fs;
+use std::path::Path;
+
+use crate::error::AllomereError;
+use crate::states::playback::{Clip, ClipEdits, CustomSourceController, PlaybackState, Track};
+use crate::states::registry::{ClipId, TrackId};
+use crate::states::transport::Transport;
+
+// Bump when the document layout changes, documents from newer versions are refused
+pub const PROJECT_VERSION: u32 = 2;
+pub const PROJECT_EXTENSION: &str = "allomere";
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProjectDocument {
+    pub version: u32,
+    // Rate the start_at frames were counted at
+    pub sample_rate: u32,
+    // Tempo, time signature and grid origin, the origin counted at `sample_rate`
+    #[serde(default)]
+    pub transport: Transport,
+    pub tracks: Vec<TrackDocument>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TrackDocument {
+    // Version 1 documents have none, their tracks get new ids
+    #[serde(default)]
+    pub id: Option<TrackId>,
+    pub name: String,
+    #[serde(default = "unity_gain")]
+    pub gain: f32,
+    #[serde(default)]
+    pub pan: f32,
+    #[serde(default)]
+    pub muted: bool,
+    #[serde(default)]
+    pub soloed: bool,
+    pub clips: Vec<ClipDocument>,
+}
+
+fn unity_gain() -> f32 {
+    1.0
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClipDocument {
+    #[serde(default)]
+    pub id: Option<ClipId>,
+    pub path: String,
+    pub start_at: Option<u64>,
+    // Loop region, count and seam settings
+    pub controller: Option<CustomSourceController>,
+    // Trim points, fades and gain envelope, in source frames
+    #[serde(default)]
+    pub edits: ClipEdits,
+}
+
+impl ProjectDocument {
+    pub fn from_tracks(tracks: &[Track], sample_rate: u32, transport: Transport) -> Self {
+        ProjectDocument {
+            version: PROJECT_VERSION,
+            sample_rate,
+            transport,
+            tracks: tracks
+                .iter()
+                .map(|track| TrackDocument {
+                    id: Some(track.id),
+                    name: track.name.clone(),
+                    gain: track.gain,
+                    pan: track.pan,
+                    muted: track.muted,
+                    soloed: track.soloed,
+                    clips: track
+                        .clips
+                        .iter()
+                        .map(|clip_ref| {
+                            let clip = clip_ref.0.lock();
+                            ClipDocument {
+                                id: Some(clip.id),
+                                path: clip.path.clone(),
+                                start_at: clip.start_at,
+                                controller: clip.controller(),
+                                edits: clip.edits().unwrap_or_default(),
+                            }
+                        })
+                        .collect(),
+                })
+                .collect(),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let document: ProjectDocument = serde_json::from_str(&fs::read_to_string(path)?)?;
+
+        if document.version > PROJECT_VERSION {
+            return Err(anyhow!(
+                "Project version {} is newer than supported version {}",
+                document.version,
+                PROJECT_VERSION
+            ));
+        }
+
+        Ok(document)
+    }
+
+    // Rebuilds every track, sink and clip source, the playback clock restarts at 0
+    // so each track waits for its first clip's start_at like it did when saved
+    // The transport is put back if a track can't be built
+    pub fn restore(&self, playback_state: &PlaybackState) -> Result<Vec<Track>, AllomereError> {
+        let sample_rate = playback_state.config.sample_rate().0;
+        let previous_transport = std::mem::replace(
+            &mut *playback_state.transport.write(),
+            Transport {
+                grid_origin: ((self.transport.grid_origin as f64) * (sample_rate as f64)
+                    / (self.sample_rate as f64)) as u64,
+                ..self.transport
+            },
+        );
+
+        self.tracks
+            .iter()
+            .map(|track_document| {
+                let mut track = Track::new(
+                    Some(track_document.name.clone()),
+                    playback_state.config.clone(),
+                    playback_state.total_frames.clone(),
+                    playback_state.transport.clone(),
+                );
+                if let Some(id) = track_document.id {
+                    track.id = id;
+                }
+
+                track.set_gain(track_document.gain);
+                track.set_pan(track_document.pan);
+                track.set_muted(track_document.muted);
+                track.set_soloed(track_document.soloed);
+
+                if let Some(output) = track.output.take() {
+                    playback_state.mixer.add(output);
+                }
+
+                for clip_document in &track_document.clips {
+                    let mut clip = Clip::new(&clip_document.path)?;
+                    if let Some(id) = clip_document.id {
+                        clip.id = id;
+                    }
+                    clip.start_at = clip_document.start_at.map(|start_at| {
+                        ((start_at as f64) * (sample_rate as f64) / (self.sample_rate as f64))
+                            as u64
+                    });
+                    if let Some(controller) = &clip_document.controller {
+                        clip.set_controller(controller.clone());
+                    }
+                    clip.set_edits(clip_document.edits.clone());
+                    track.add_clip(clip);
+                }
+
+                Ok(track)
+            })
+            .collect::<Result<Vec<Track>, AllomereError>>()
+            .map(|tracks| {
+                Track::update_audible(&tracks);
+                tracks
+            })
+            .inspect_err(|_| *(playback_state.transport.write()) = previous_transport)
+    }
+}
@@ -0,0 +1,135 @@
+use anyhow::Result;
+
+use parking_lot::Mutex;
+
+use pyo3::ffi::c_str;
+use pyo3::prelude::*;
+
+use lazy_static::lazy_static;
+
+use std::ffi::CStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Once;
+
+lazy_static! {
+    static ref BEATS_MODULE: Mutex<Option<Py<PyModule>>> = Mutex::new(None);
+    static ref FEATURES_MODULE: Mutex<Option<Py<PyModule>>> = Mutex::new(None);
+}
+
+static CONFIGURE_PATH: Once = Once::new();
+
+// Virtualenv holding librosa and the CLAP model, ALLOMERE_PYTHON_VENV overrides
+// the src-python/venv checkout next to this crate
+fn venv_dir() -> PathBuf {
+    match std::env::var_os("ALLOMERE_PYTHON_VENV") {
+        Some(venv) => PathBuf::from(venv),
+        None => Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("..")
+            .join("src-python")
+            .join("venv"),
+    }
+}
+
+// Windows venvs keep packages in Lib/site-packages, everything else in lib/pythonX.Y/site-packages
+fn site_packages(venv: &Path) -> Vec<PathBuf> {
+    if cfg!(windows) {
+        return vec![venv.join("Lib").join("site-packages")];
+    }
+
+    fs::read_dir(venv.join("lib"))
+        .map(|entries| {
+            entries
+                .flatten()
+                .map(|entry| entry.path())
+                .filter(|path| {
+                    path.file_name()
+                        .is_some_and(|name| name.to_string_lossy().starts_with("python"))
+                })
+                .map(|path| path.join("site-packages"))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn configure_path(py: Python) -> PyResult<()> {
+    let sys = py.import("sys")?;
+    let path = sys.getattr("path")?;
+
+    let venv = venv_dir();
+    let mut found = false;
+    for site_packages in site_packages(&venv) {
+        if site_packages.is_dir() {
+            found = true;
+            path.call_method1("append", (site_packages.to_string_lossy(),))?;
+            path.call_method1(
+                "append",
+                (site_packages.join("tokenizers").to_string_lossy(),),
+            )?;
+        }
+    }
+    if !found {
+        println!(
+            "No virtualenv at {:?}, using the interpreter's own packages",
+            venv
+        );
+    }
+
+    match std::env::var("PATH") {
+        Ok(val) => {
+            let paths: Vec<PathBuf> = std::env::split_paths(&val).collect();
+            path.call_method1("extend", (paths,))?;
+        }
+        Err(e) => println!("Error {}: {}", "PATH", e),
+    }
+
+    Ok(())
+}
+
+// Each module is loaded the first time it's asked for, so the librosa beat
+// tracker doesn't pull in features.py and the CLAP model it downloads
+fn load_module(
+    py: Python,
+    module: &Mutex<Option<Py<PyModule>>>,
+    code: &CStr,
+    file_name: &CStr,
+    module_name: &CStr,
+) -> Result<Py<PyModule>> {
+    if let Some(loaded) = module.lock().as_ref() {
+        return Ok(loaded.clone_ref(py));
+    }
+
+    CONFIGURE_PATH.call_once(|| {
+        if let Err(e) = configure_path(py) {
+            eprintln!("Failed to set up the Python path: {}", e);
+        }
+    });
+
+    println!("Loading {}...", file_name.to_string_lossy());
+    let loaded: Py<PyModule> = PyModule::from_code(py, code, file_name, module_name)?.into();
+    println!("Loaded {}", file_name.to_string_lossy());
+
+    // Another thread may have loaded it while the GIL was released
+    Ok(module.lock().get_or_insert(loaded).clone_ref(py))
+}
+
+pub fn beats_module(py: Python) -> Result<Py<PyModule>> {
+    load_module(
+        py,
+        &BEATS_MODULE,
+        c_str!(include_str!("../../../src-python/src/beats.py")),
+        c_str!("beats.py"),
+        c_str!("beats"),
+    )
+}
+
+// Importing it downloads and builds the CLAP model
+pub fn features_module(py: Python) -> Result<Py<PyModule>> {
+    load_module(
+        py,
+        &FEATURES_MODULE,
+        c_str!(include_str!("../../../src-python/src/features.py")),
+        c_str!("features.py"),
+        c_str!("features"),
+    )
+}
@@ -0,0 +1,95 @@
+use parking_lot::Mutex;
+
+use lazy_static::lazy_static;
+
+use serde::{Deserialize, Serialize};
+
+use uuid::Uuid;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Weak};
+
+use crate::states::playback::{AllomereMutex, Clip};
+
+// Identifies a clip for as long as it exists, saved with the project so it's
+// the same clip after reopening it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ClipId(Uuid);
+
+// Identifies a track the same way
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct TrackId(Uuid);
+
+impl Default for ClipId {
+    fn default() -> Self {
+        ClipId(Uuid::new_v4())
+    }
+}
+
+impl Default for TrackId {
+    fn default() -> Self {
+        TrackId(Uuid::new_v4())
+    }
+}
+
+impl fmt::Display for ClipId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl fmt::Display for TrackId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+struct ClipEntry {
+    clip: Weak<AllomereMutex<Clip>>,
+    track: TrackId,
+}
+
+lazy_static! {
+    // Every clip scheduled on a track and the track it's on. Tracks taken out of
+    // the project keep their entries, so undoing the removal finds them again
+    static ref CLIPS: Mutex<HashMap<ClipId, ClipEntry>> = Mutex::new(HashMap::new());
+}
+
+pub fn register_clip(id: ClipId, clip: &Arc<AllomereMutex<Clip>>, track: TrackId) {
+    CLIPS.lock().insert(
+        id,
+        ClipEntry {
+            clip: Arc::downgrade(clip),
+            track,
+        },
+    );
+}
+
+// Leaves the entry alone if the clip has since moved to another track
+pub fn unregister_clip(id: ClipId, track: TrackId) {
+    let mut clips = CLIPS.lock();
+    if clips.get(&id).is_some_and(|entry| entry.track == track) {
+        clips.remove(&id);
+    }
+}
+
+// Only `clips`, the ones on this copy of the track. Another copy with the same
+// id, such as the one a project restore or an undo brings back, keeps its own
+pub fn unregister_track(track: TrackId, clips: &[Arc<AllomereMutex<Clip>>]) {
+    CLIPS.lock().retain(|_, entry| {
+        entry.track != track
+            || !clips
+                .iter()
+                .any(|clip| entry.clip.as_ptr() == Arc::as_ptr(clip))
+    });
+}
+
+// Clip `id` and the track it's on, None once it's off every track
+pub fn clip(id: ClipId) -> Option<(Arc<AllomereMutex<Clip>>, TrackId)> {
+    let clips = CLIPS.lock();
+    let entry = clips.get(&id)?;
+    Some((entry.clip.upgrade()?, entry.track))
+}
@@ -0,0 +1,340 @@
+use rodio::source::{self, Source};
+
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering::SeqCst};
+use std::sync::Arc;
+use std::time::Duration;
+
+// Where a clip's Resample picks up once the source underneath it is seeked
+#[derive(Debug, Default)]
+pub struct ResampleControls {
+    flush: AtomicBool,
+    // How far past the seeked frame the first output frame lands, in
+    // fractions of a source frame with the output rate as denominator
+    offset: AtomicU32,
+}
+
+impl ResampleControls {
+    // Buffered input belongs to the old position once the clip underneath is seeked
+    pub fn flush(&self, offset: u32) {
+        self.offset.store(offset, SeqCst);
+        self.flush.store(true, SeqCst);
+    }
+}
+
+// Zero crossings of the sinc either side of the centre tap, before widening
+// it for downsampling
+const ZERO_CROSSINGS: usize = 16;
+// Phases with their own kernel, rate pairs needing more share the nearest one
+const MAX_PHASES: u64 = 1024;
+// Kaiser window shape, about 80dB of stopband rejection
+const KAISER_BETA: f64 = 8.0;
+// Passband edge as a fraction of the lower Nyquist frequency
+const CUTOFF: f64 = 0.95;
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+// Zeroth order modified Bessel function of the first kind, for the Kaiser window
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    for k in 1..32 {
+        term *= (x / (2.0 * k as f64)).powi(2);
+        sum += term;
+        if term < sum * 1e-12 {
+            break;
+        }
+    }
+    sum
+}
+
+// Windowed sinc resampling from the inner source's rate to `sample_rate`. The
+// ratio is kept as a fraction so output frame n always lands exactly n * from / to
+// frames into the input, a 44.1kHz file never drifts against a 48kHz timeline.
+// Matching rates pass samples straight through
+pub struct Resample<S>
+where
+    S: Source<Item = f32>,
+{
+    inner: S,
+    controls: Arc<ResampleControls>,
+    channels: usize,
+    sample_rate: u32,
+    // Input frames per output frame is `step / denominator`, both reduced
+    step: u64,
+    denominator: u64,
+    // Taps either side of the output position, in input frames
+    half_taps: usize,
+    // One kernel of 2 * half_taps weights per phase
+    kernels: Vec<Vec<f32>>,
+    // Interleaved input frames `frame - half_taps + 1` to `frame + half_taps`
+    input: VecDeque<f32>,
+    // Input frames read since the last reset, and the one the output is past
+    read: u64,
+    frame: u64,
+    // Output position past `frame`, out of `denominator`
+    phase: u64,
+    // Input is only read once output is asked for, after any seek has landed
+    primed: bool,
+    input_ended: bool,
+    output: Vec<f32>,
+    output_channel: usize,
+}
+
+impl<S> Resample<S>
+where
+    S: Source<Item = f32>,
+{
+    pub fn new(inner: S, sample_rate: u32, controls: Arc<ResampleControls>) -> Self {
+        let channels = inner.channels().max(1) as usize;
+        let from = inner.sample_rate().max(1) as u64;
+        let to = sample_rate.max(1) as u64;
+        let divisor = gcd(from, to);
+        let (step, denominator) = (from / divisor, to / divisor);
+
+        // Downsampling lowers the cutoff, which widens the kernel by as much
+        let cutoff = CUTOFF * (to as f64 / from as f64).min(1.0);
+        let half_taps = (ZERO_CROSSINGS as f64 / cutoff).ceil() as usize;
+        let phases = denominator.min(MAX_PHASES);
+        let kernels = if step == denominator {
+            Vec::new()
+        } else {
+            (0..phases)
+                .map(|phase| Self::kernel(phase as f64 / phases as f64, half_taps, cutoff))
+                .collect()
+        };
+
+        Resample {
+            inner,
+            controls,
+            channels,
+            sample_rate,
+            step,
+            denominator,
+            half_taps,
+            kernels,
+            input: VecDeque::new(),
+            read: 0,
+            frame: 0,
+            phase: 0,
+            primed: false,
+            input_ended: false,
+            output: vec![0.0; channels],
+            output_channel: channels,
+        }
+    }
+
+    // Weights for an output `fraction` of a frame past the centre tap, normalised
+    // so a constant input comes out unchanged
+    fn kernel(fraction: f64, half_taps: usize, cutoff: f64) -> Vec<f32> {
+        let weights: Vec<f64> = (0..2 * half_taps)
+            .map(|tap| {
+                let distance = fraction + (half_taps - 1) as f64 - tap as f64;
+                let x = cutoff * distance;
+                let sinc = if x.abs() < 1e-9 {
+                    1.0
+                } else {
+                    (PI * x).sin() / (PI * x)
+                };
+                let window = (distance / half_taps as f64).clamp(-1.0, 1.0);
+                sinc * bessel_i0(KAISER_BETA * (1.0 - window * window).sqrt())
+                    / bessel_i0(KAISER_BETA)
+            })
+            .collect();
+        let sum: f64 = weights.iter().sum();
+        weights.iter().map(|weight| (weight / sum) as f32).collect()
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    fn passthrough(&self) -> bool {
+        self.step == self.denominator
+    }
+
+    // Starts again from the inner source's current frame, `offset` out of the
+    // output rate past it
+    fn reset(&mut self, offset: u32) {
+        self.input.clear();
+        self.read = 0;
+        self.frame = 0;
+        self.phase = (offset as u64 * self.denominator / self.sample_rate.max(1) as u64)
+            .min(self.denominator - 1);
+        self.primed = false;
+        self.input_ended = false;
+        self.output_channel = self.channels;
+    }
+
+    // Silence before the first frame, then enough input for the first output frame
+    fn prime(&mut self) {
+        self.input.resize((self.half_taps - 1) * self.channels, 0.0);
+        for _ in 0..=self.half_taps {
+            self.push_frame();
+        }
+        self.primed = true;
+    }
+
+    // Reads the next input frame, silence once the inner source has ended
+    fn push_frame(&mut self) {
+        for channel in 0..self.channels {
+            let sample = if self.input_ended {
+                None
+            } else {
+                self.inner.next()
+            };
+            match sample {
+                Some(sample) => self.input.push_back(sample),
+                None => {
+                    // A frame cut short still counts, the rest of it is silent
+                    if !self.input_ended && channel > 0 {
+                        self.read += 1;
+                    }
+                    self.input_ended = true;
+                    self.input.push_back(0.0);
+                }
+            }
+        }
+        if !self.input_ended {
+            self.read += 1;
+        }
+    }
+
+    // Fills `output` with the next frame, false once the input has run out
+    fn resample_frame(&mut self) -> bool {
+        if !self.primed {
+            self.prime();
+        }
+        if self.input_ended && self.frame >= self.read {
+            return false;
+        }
+
+        let phases = self.kernels.len() as u64;
+        let kernel = &self.kernels[(self.phase * phases / self.denominator) as usize];
+        for (channel, output) in self.output.iter_mut().enumerate() {
+            *output = kernel
+                .iter()
+                .zip(self.input.iter().skip(channel).step_by(self.channels))
+                .map(|(weight, sample)| weight * sample)
+                .sum();
+        }
+
+        self.phase += self.step;
+        while self.phase >= self.denominator {
+            self.phase -= self.denominator;
+            self.input.drain(..self.channels);
+            self.push_frame();
+            self.frame += 1;
+        }
+        true
+    }
+}
+
+impl<S> Iterator for Resample<S>
+where
+    S: Source<Item = f32>,
+{
+    type Item = f32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.controls.flush.swap(false, SeqCst) {
+            let offset = self.controls.offset.load(SeqCst);
+            if !self.passthrough() {
+                self.reset(offset);
+            }
+        }
+        if self.passthrough() {
+            return self.inner.next();
+        }
+
+        if self.output_channel == self.channels {
+            if !self.resample_frame() {
+                return None;
+            }
+            self.output_channel = 0;
+        }
+        let sample = self.output[self.output_channel];
+        self.output_channel += 1;
+        Some(sample)
+    }
+}
+
+impl<S> Source for Resample<S>
+where
+    S: Source<Item = f32>,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        if self.passthrough() {
+            self.inner.current_frame_len()
+        } else {
+            None
+        }
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels as u16
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), source::SeekError> {
+        self.inner.try_seek(pos)?;
+        if !self.passthrough() {
+            self.reset(0);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rodio::buffer::SamplesBuffer;
+
+    fn resample(samples: Vec<f32>, channels: u16, from: u32, to: u32) -> Vec<f32> {
+        let inner = SamplesBuffer::new(channels, from, samples);
+        Resample::new(inner, to, Arc::new(ResampleControls::default())).collect()
+    }
+
+    #[test]
+    fn constant_input_stays_constant() {
+        let output = resample(vec![0.5; 44_100 * 2], 2, 44_100, 48_000);
+
+        // The kernel reaches past either end of the input, where it's silent
+        let edge = 64 * 2;
+        for sample in &output[edge..output.len() - edge] {
+            assert!((sample - 0.5).abs() < 1e-4, "{}", sample);
+        }
+    }
+
+    #[test]
+    fn frame_count_doesnt_drift() {
+        for seconds in [1, 10] {
+            let output = resample(vec![0.0; 44_100 * seconds], 1, 44_100, 48_000);
+            assert_eq!(output.len(), 48_000 * seconds);
+
+            let output = resample(vec![0.0; 48_000 * seconds], 1, 48_000, 44_100);
+            assert_eq!(output.len(), 44_100 * seconds);
+        }
+    }
+
+    #[test]
+    fn matching_rates_pass_through() {
+        let samples: Vec<f32> = (0..1000).map(|i| (i as f32 * 0.01).sin()).collect();
+        assert_eq!(resample(samples.clone(), 2, 48_000, 48_000), samples);
+    }
+}
@@ -0,0 +1,401 @@
+use rodio::source::{self, Source};
+
+use std::collections::VecDeque;
+use std::f32::consts::{FRAC_1_SQRT_2, PI, SQRT_2};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering::SeqCst};
+use std::sync::Arc;
+use std::time::Duration;
+
+// Playback rate and a flush flag shared between a clip and the TimeStretch
+// sources playing it, so tempo changes land without rebuilding the sink queue
+#[derive(Debug)]
+pub struct TimeStretchControls {
+    rate: AtomicU32,
+    flush: AtomicBool,
+}
+
+impl Default for TimeStretchControls {
+    fn default() -> Self {
+        TimeStretchControls {
+            rate: AtomicU32::new(1f32.to_bits()),
+            flush: AtomicBool::new(false),
+        }
+    }
+}
+
+impl TimeStretchControls {
+    const MIN_RATE: f32 = 0.25;
+    const MAX_RATE: f32 = 4.0;
+
+    pub fn rate(&self) -> f32 {
+        f32::from_bits(self.rate.load(SeqCst))
+    }
+
+    pub fn set_rate(&self, rate: f32) {
+        let rate = if rate.is_finite() {
+            rate.clamp(Self::MIN_RATE, Self::MAX_RATE)
+        } else {
+            1.0
+        };
+        self.rate.store(rate.to_bits(), SeqCst);
+    }
+
+    // Buffered audio belongs to the old position once the clip underneath is seeked
+    pub fn flush(&self) {
+        self.flush.store(true, SeqCst);
+    }
+}
+
+// Rate that brings `tempo` to `master_tempo`, halved or doubled until it's within
+// a factor of √2 so a 70 BPM clip follows a 140 BPM project at half time
+pub fn tempo_ratio(tempo: f32, master_tempo: f32) -> f32 {
+    if !(tempo > 0.0 && master_tempo > 0.0) {
+        return 1.0;
+    }
+    let mut rate = master_tempo / tempo;
+    while rate > SQRT_2 {
+        rate /= 2.0;
+    }
+    while rate < FRAC_1_SQRT_2 {
+        rate *= 2.0;
+    }
+    rate
+}
+
+// WSOLA time stretching, pitch is kept. Each output hop overlap-adds a Hann-windowed
+// segment taken near `hop * rate` further into the input, at the offset that best
+// continues the previous segment's waveform. A rate of 1 passes samples straight through
+pub struct TimeStretch<S>
+where
+    S: Source<Item = f32>,
+{
+    inner: S,
+    controls: Arc<TimeStretchControls>,
+    channels: usize,
+    sample_rate: u32,
+    // Segment length, output hop and how far either side of the nominal position
+    // a segment may be taken from, all in frames
+    segment_frames: usize,
+    hop_frames: usize,
+    tolerance_frames: usize,
+    window: Vec<f32>,
+    // Interleaved input, `input_start` counts frames read since the last reset
+    input: VecDeque<f32>,
+    input_start: usize,
+    input_ended: bool,
+    analysis_position: f64,
+    previous_segment: Option<usize>,
+    overlap: Vec<f32>,
+    output: VecDeque<f32>,
+    // Scratch for `best_segment`, allocated once so blocks don't allocate on the audio thread
+    template: Vec<f32>,
+    candidates: Vec<f32>,
+    stretching: bool,
+    finished: bool,
+}
+
+impl<S> TimeStretch<S>
+where
+    S: Source<Item = f32>,
+{
+    // 40ms segments and up to 10ms of alignment either way
+    const SEGMENT_SECONDS: f32 = 0.04;
+    const TOLERANCE_SECONDS: f32 = 0.01;
+
+    pub fn new(inner: S, controls: Arc<TimeStretchControls>) -> Self {
+        let channels = inner.channels().max(1) as usize;
+        let sample_rate = inner.sample_rate();
+        let segment_frames = ((Self::SEGMENT_SECONDS * sample_rate as f32) as usize).max(64) & !1;
+        let hop_frames = segment_frames / 2;
+
+        // Periodic Hann, overlapping by half it sums to one
+        let window = (0..segment_frames)
+            .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / segment_frames as f32).cos())
+            .collect();
+
+        let tolerance_frames = (Self::TOLERANCE_SECONDS * sample_rate as f32) as usize;
+        // Input spans the search range and a segment either side of it, at the fastest rate
+        let input_frames = 2 * (segment_frames + tolerance_frames)
+            + hop_frames * TimeStretchControls::MAX_RATE as usize;
+
+        TimeStretch {
+            inner,
+            controls,
+            channels,
+            sample_rate,
+            segment_frames,
+            hop_frames,
+            tolerance_frames,
+            window,
+            input: VecDeque::with_capacity(input_frames * channels),
+            input_start: 0,
+            input_ended: false,
+            analysis_position: 0.0,
+            previous_segment: None,
+            overlap: vec![0.0; segment_frames * channels],
+            output: VecDeque::with_capacity(hop_frames * channels),
+            template: Vec::with_capacity(segment_frames.div_ceil(4)),
+            candidates: Vec::with_capacity(2 * tolerance_frames + segment_frames + 1),
+            stretching: false,
+            finished: false,
+        }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    fn reset(&mut self) {
+        self.input.clear();
+        self.input_start = 0;
+        self.input_ended = false;
+        self.analysis_position = 0.0;
+        self.previous_segment = None;
+        self.overlap.iter_mut().for_each(|sample| *sample = 0.0);
+        self.output.clear();
+        self.stretching = false;
+        self.finished = false;
+    }
+
+    fn input_end(&self) -> usize {
+        self.input_start + self.input.len() / self.channels
+    }
+
+    // Reads whole frames from the inner source until `frame` is buffered
+    fn fill(&mut self, frame: usize) {
+        while !self.input_ended && self.input_end() < frame {
+            for channel in 0..self.channels {
+                match self.inner.next() {
+                    Some(sample) => self.input.push_back(sample),
+                    None => {
+                        self.input_ended = true;
+                        if channel > 0 {
+                            (channel..self.channels).for_each(|_| self.input.push_back(0.0));
+                        }
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    fn discard_before(&mut self, frame: usize) {
+        let frames = frame
+            .saturating_sub(self.input_start)
+            .min(self.input.len() / self.channels);
+        self.input.drain(..frames * self.channels);
+        self.input_start += frames;
+    }
+
+    fn sample(&self, frame: usize, channel: usize) -> f32 {
+        frame
+            .checked_sub(self.input_start)
+            .and_then(|frame| self.input.get(frame * self.channels + channel))
+            .copied()
+            .unwrap_or(0.0)
+    }
+
+    fn mono(&self, frame: usize) -> f32 {
+        (0..self.channels)
+            .map(|channel| self.sample(frame, channel))
+            .sum()
+    }
+
+    // Candidate whose normalised cross-correlation with `template` is highest,
+    // every other candidate and every 4th frame is enough to find the alignment
+    fn best_segment(&mut self, template_frame: usize, lowest: usize, highest: usize) -> usize {
+        let mut template = std::mem::take(&mut self.template);
+        template.clear();
+        template.extend(
+            (0..self.segment_frames)
+                .step_by(4)
+                .map(|i| self.mono(template_frame + i)),
+        );
+        let mut candidates = std::mem::take(&mut self.candidates);
+        candidates.clear();
+        candidates.extend((lowest..highest + self.segment_frames).map(|frame| self.mono(frame)));
+
+        let mut best = (lowest, f32::NEG_INFINITY);
+        for offset in (0..=highest - lowest).step_by(2) {
+            let (mut correlation, mut energy) = (0f32, 0f32);
+            for (value, template) in candidates[offset..].iter().step_by(4).zip(&template) {
+                correlation += value * template;
+                energy += value * value;
+            }
+            let score = correlation / (energy.sqrt() + 1e-6);
+            if score > best.1 {
+                best = (lowest + offset, score);
+            }
+        }
+
+        self.template = template;
+        self.candidates = candidates;
+        best.0
+    }
+
+    // Adds one segment and moves a hop of finished output into `output`
+    fn process(&mut self, rate: f32) {
+        let nominal = self.analysis_position.round() as usize;
+        let (lowest, highest) = match self.previous_segment {
+            Some(_) => (
+                nominal
+                    .saturating_sub(self.tolerance_frames)
+                    .max(self.input_start),
+                nominal + self.tolerance_frames,
+            ),
+            None => (nominal, nominal),
+        };
+        let template = self
+            .previous_segment
+            .map(|previous| previous + self.hop_frames);
+
+        self.fill(highest.max(template.unwrap_or(0)) + self.segment_frames);
+
+        if self.input_ended && lowest >= self.input_end() {
+            // Only the tail of the last segment is left
+            self.output
+                .extend(self.overlap.drain(..self.hop_frames * self.channels));
+            self.finished = true;
+            return;
+        }
+
+        let segment = match template {
+            Some(template) => self.best_segment(template, lowest, highest),
+            None => nominal,
+        };
+
+        for i in 0..self.segment_frames {
+            let weight = self.window[i];
+            for channel in 0..self.channels {
+                self.overlap[i * self.channels + channel] +=
+                    self.sample(segment + i, channel) * weight;
+            }
+        }
+        self.output
+            .extend(self.overlap.drain(..self.hop_frames * self.channels));
+        self.overlap
+            .resize(self.segment_frames * self.channels, 0.0);
+
+        self.previous_segment = Some(segment);
+        self.analysis_position += (self.hop_frames as f64) * (rate as f64);
+
+        // Everything before the next template and search range can go
+        let next_lowest =
+            (self.analysis_position.round() as usize).saturating_sub(self.tolerance_frames);
+        self.discard_before((segment + self.hop_frames).min(next_lowest));
+    }
+}
+
+impl<S> Iterator for TimeStretch<S>
+where
+    S: Source<Item = f32>,
+{
+    type Item = f32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.controls.flush.swap(false, SeqCst) {
+            self.reset();
+        }
+        if let Some(sample) = self.output.pop_front() {
+            return Some(sample);
+        }
+
+        let rate = self.controls.rate();
+        if (rate - 1.0).abs() < 1e-3 {
+            if self.stretching {
+                self.reset();
+            }
+            return self.inner.next();
+        }
+
+        self.stretching = true;
+        if self.finished {
+            return None;
+        }
+        self.process(rate);
+        self.output.pop_front()
+    }
+}
+
+impl<S> Source for TimeStretch<S>
+where
+    S: Source<Item = f32>,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        if self.stretching {
+            None
+        } else {
+            self.inner.current_frame_len()
+        }
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels as u16
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        let rate = self.controls.rate();
+        self.inner
+            .total_duration()
+            .map(|total_duration| total_duration.div_f32(rate))
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), source::SeekError> {
+        self.inner.try_seek(pos.mul_f32(self.controls.rate()))?;
+        self.reset();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rodio::buffer::SamplesBuffer;
+
+    fn stretch(samples: Vec<f32>, channels: u16, rate: f32) -> Vec<f32> {
+        let controls = Arc::new(TimeStretchControls::default());
+        controls.set_rate(rate);
+        TimeStretch::new(SamplesBuffer::new(channels, 48_000, samples), controls).collect()
+    }
+
+    #[test]
+    fn tempo_ratio_follows_half_and_double_time() {
+        assert_eq!(tempo_ratio(70.0, 140.0), 1.0);
+        assert_eq!(tempo_ratio(140.0, 70.0), 1.0);
+        assert_eq!(tempo_ratio(120.0, 120.0), 1.0);
+        assert!((tempo_ratio(120.0, 126.0) - 1.05).abs() < 1e-6);
+        // Tempo isn't known yet
+        assert_eq!(tempo_ratio(0.0, 120.0), 1.0);
+    }
+
+    #[test]
+    fn constant_input_stays_constant() {
+        for rate in [0.5, 1.5] {
+            let output = stretch(vec![0.5; 48_000 * 2 * 2], 2, rate);
+
+            // The first segment fades in and the last ones run past the input
+            let edge = 4096 * 2;
+            assert!(output.len() > 2 * edge);
+            for sample in &output[edge..output.len() - edge] {
+                assert!((sample - 0.5).abs() < 1e-4, "{}", sample);
+            }
+        }
+    }
+
+    #[test]
+    fn output_length_follows_the_rate() {
+        let output = stretch(vec![0.0; 48_000], 1, 2.0);
+        assert!(output.len().abs_diff(24_000) < 2_000, "{}", output.len());
+    }
+
+    #[test]
+    fn unit_rate_passes_through() {
+        let samples: Vec<f32> = (0..1000).map(|i| (i as f32 * 0.01).sin()).collect();
+        assert_eq!(stretch(samples.clone(), 2, 1.0), samples);
+    }
+}
@@ -0,0 +1,115 @@
+use serde::{Deserialize, Serialize};
+
+// Resolution of the beat grid, ticks per beat as in MIDI files
+pub const TICKS_PER_BEAT: u32 = 960;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TimeSignature {
+    pub beats_per_bar: u32,
+    // Note value of one beat, the tempo counts these
+    pub beat_unit: u32,
+}
+
+impl Default for TimeSignature {
+    fn default() -> Self {
+        TimeSignature {
+            beats_per_bar: 4,
+            beat_unit: 4,
+        }
+    }
+}
+
+// Bars and beats count from 1, bars before the grid origin are 0 or negative
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MusicalPosition {
+    pub bar: i64,
+    #[serde(default = "first_beat")]
+    pub beat: u32,
+    #[serde(default)]
+    pub tick: u32,
+}
+
+fn first_beat() -> u32 {
+    1
+}
+
+// A point on the playback timeline in whichever unit the caller has
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Position {
+    Seconds(f64),
+    Frame(u64),
+    Musical(MusicalPosition),
+}
+
+// Master clock of the project, the beat grid starts at `grid_origin` and advances at
+// `tempo`. With `tempo_sync` on every clip is stretched to the same tempo
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Transport {
+    pub tempo: f32,
+    pub tempo_sync: bool,
+    pub time_signature: TimeSignature,
+    // Frame on the playback timeline where bar 1 starts
+    pub grid_origin: u64,
+}
+
+impl Default for Transport {
+    fn default() -> Self {
+        Transport {
+            tempo: 120.0,
+            tempo_sync: false,
+            time_signature: TimeSignature::default(),
+            grid_origin: 0,
+        }
+    }
+}
+
+impl Transport {
+    // Tempo clips are stretched to, None plays them at their own tempo
+    pub fn master_tempo(&self) -> Option<f32> {
+        self.tempo_sync.then_some(self.tempo)
+    }
+
+    fn frames_per_tick(&self, sample_rate: u32) -> f64 {
+        (sample_rate as f64) * 60.0 / (self.tempo as f64) / (TICKS_PER_BEAT as f64)
+    }
+
+    fn ticks_per_bar(&self) -> i64 {
+        (TICKS_PER_BEAT * self.time_signature.beats_per_bar.max(1)) as i64
+    }
+
+    pub fn position(&self, frame: u64, sample_rate: u32) -> MusicalPosition {
+        let ticks = (((frame as f64) - (self.grid_origin as f64))
+            / self.frames_per_tick(sample_rate))
+        .floor() as i64;
+        let ticks_in_bar = ticks.rem_euclid(self.ticks_per_bar()) as u32;
+
+        MusicalPosition {
+            bar: ticks.div_euclid(self.ticks_per_bar()) + 1,
+            beat: ticks_in_bar / TICKS_PER_BEAT + 1,
+            tick: ticks_in_bar % TICKS_PER_BEAT,
+        }
+    }
+
+    // Positions before the start of the timeline land on frame 0
+    pub fn frame(&self, position: MusicalPosition, sample_rate: u32) -> u64 {
+        let ticks = (position.bar - 1) * self.ticks_per_bar()
+            + (position.beat.max(1) as i64 - 1) * (TICKS_PER_BEAT as i64)
+            + position.tick as i64;
+
+        ((self.grid_origin as f64) + (ticks as f64) * self.frames_per_tick(sample_rate))
+            .round()
+            .max(0.0) as u64
+    }
+
+    pub fn resolve(&self, position: Position, sample_rate: u32) -> u64 {
+        match position {
+            Position::Seconds(seconds) => (seconds.max(0.0) * (sample_rate as f64)).round() as u64,
+            Position::Frame(frame) => frame,
+            Position::Musical(position) => self.frame(position, sample_rate),
+        }
+    }
+}
@@ -0,0 +1,182 @@
+use crate::states::playback::Sound;
+
+// Frames per peak of the finest level, closer zooms are drawn from it too
+pub const BASE_FRAMES_PER_PEAK: u32 = 256;
+
+// Levels stop once they're down to this many peaks
+const MIN_LEVEL_PEAKS: usize = 16;
+
+// Extremes and loudness of a run of frames, scaled to i16 like the decoded audio
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Peak {
+    pub min: i16,
+    pub max: i16,
+    pub rms: i16,
+}
+
+impl Peak {
+    fn merge(peaks: &[Peak]) -> Peak {
+        if peaks.is_empty() {
+            return Peak::default();
+        }
+        let squares: f64 = peaks
+            .iter()
+            .map(|peak| (peak.rms as f64) * (peak.rms as f64))
+            .sum();
+        Peak {
+            min: peaks.iter().map(|peak| peak.min).min().unwrap_or(0),
+            max: peaks.iter().map(|peak| peak.max).max().unwrap_or(0),
+            rms: (squares / peaks.len() as f64).sqrt().round() as i16,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct PeakAccumulator {
+    min: f32,
+    max: f32,
+    squares: f64,
+    frames: u32,
+}
+
+impl Default for PeakAccumulator {
+    fn default() -> Self {
+        PeakAccumulator {
+            min: f32::MAX,
+            max: f32::MIN,
+            squares: 0.0,
+            frames: 0,
+        }
+    }
+}
+
+impl PeakAccumulator {
+    fn add(&mut self, sample: f32) {
+        self.min = self.min.min(sample);
+        self.max = self.max.max(sample);
+        self.squares += (sample as f64) * (sample as f64);
+        self.frames += 1;
+    }
+
+    fn take(&mut self) -> Peak {
+        let scale = |sample: f32| (sample.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16;
+        let peak = Peak {
+            min: scale(self.min),
+            max: scale(self.max),
+            rms: scale((self.squares / self.frames.max(1) as f64).sqrt() as f32),
+        };
+        *self = PeakAccumulator::default();
+        peak
+    }
+}
+
+// Min/max/RMS pyramid of a file, every level has twice the frames per peak of
+// the one before, starting at BASE_FRAMES_PER_PEAK
+pub struct WaveformPeaks {
+    sample_rate: u32,
+    frames: u64,
+    // Levels for each channel, then for the channels mixed down
+    pyramids: Vec<Vec<Vec<Peak>>>,
+}
+
+impl WaveformPeaks {
+    // Reads the whole file once, waiting for it to finish decoding
+    pub fn new(sound: &Sound) -> Self {
+        let channels = sound.channels() as usize;
+        let mut accumulators = vec![PeakAccumulator::default(); channels + 1];
+        let mut base_levels: Vec<Vec<Peak>> = vec![Vec::new(); channels + 1];
+        let mut mixed = 0f32;
+        let mut frames = 0u64;
+
+        for (i, sample) in sound.reader().enumerate() {
+            let channel = i % channels;
+            accumulators[channel].add(sample);
+            mixed += sample;
+            if channel + 1 < channels {
+                continue;
+            }
+
+            accumulators[channels].add(mixed / channels as f32);
+            mixed = 0.0;
+            frames += 1;
+            if frames % BASE_FRAMES_PER_PEAK as u64 == 0 {
+                for (accumulator, level) in accumulators.iter_mut().zip(&mut base_levels) {
+                    level.push(accumulator.take());
+                }
+            }
+        }
+        if frames % BASE_FRAMES_PER_PEAK as u64 != 0 {
+            for (accumulator, level) in accumulators.iter_mut().zip(&mut base_levels) {
+                level.push(accumulator.take());
+            }
+        }
+
+        let pyramids = base_levels
+            .into_iter()
+            .map(|base_level| {
+                let mut levels = vec![base_level];
+                while let Some(level) = levels.last().filter(|level| level.len() > MIN_LEVEL_PEAKS)
+                {
+                    let next_level = level.chunks(2).map(Peak::merge).collect();
+                    levels.push(next_level);
+                }
+                levels
+            })
+            .collect();
+
+        WaveformPeaks {
+            sample_rate: sound.sample_rate(),
+            frames,
+            pyramids,
+        }
+    }
+
+    pub fn channels(&self) -> u16 {
+        (self.pyramids.len() - 1) as u16
+    }
+
+    // Finer than BASE_FRAMES_PER_PEAK is served at BASE_FRAMES_PER_PEAK
+    pub fn frames_per_peak(frames_per_peak: u32) -> u32 {
+        frames_per_peak.max(BASE_FRAMES_PER_PEAK)
+    }
+
+    // One peak per `frames_per_peak` frames of `channel`, None mixes every channel
+    // Built from the coarsest level that still has a peak per pixel
+    pub fn peaks(&self, channel: Option<u16>, frames_per_peak: u32) -> Vec<Peak> {
+        let frames_per_peak = Self::frames_per_peak(frames_per_peak) as u64;
+        let levels = &self.pyramids[channel.map_or(self.pyramids.len() - 1, |c| c as usize)];
+
+        let level_index = ((frames_per_peak / BASE_FRAMES_PER_PEAK as u64).ilog2() as usize)
+            .min(levels.len() - 1);
+        let level = &levels[level_index];
+        let level_frames_per_peak = (BASE_FRAMES_PER_PEAK as u64) << level_index;
+
+        (0..self.frames.div_ceil(frames_per_peak))
+            .map(|i| {
+                let start = (i * frames_per_peak / level_frames_per_peak) as usize;
+                let end = (((i + 1) * frames_per_peak).min(self.frames))
+                    .div_ceil(level_frames_per_peak) as usize;
+                Peak::merge(&level[start.min(level.len())..end.min(level.len())])
+            })
+            .collect()
+    }
+
+    // Little endian: sample rate (u32), frames per peak (u32), length of the file
+    // in frames (u64) and number of peaks (u32), then min, max and RMS (i16 each)
+    // for every peak. The peaks start 20 bytes in, so they can be read as an Int16Array
+    pub fn to_bytes(&self, channel: Option<u16>, frames_per_peak: u32) -> Vec<u8> {
+        let peaks = self.peaks(channel, frames_per_peak);
+
+        let mut bytes = Vec::with_capacity(20 + peaks.len() * 6);
+        bytes.extend(self.sample_rate.to_le_bytes());
+        bytes.extend(Self::frames_per_peak(frames_per_peak).to_le_bytes());
+        bytes.extend(self.frames.to_le_bytes());
+        bytes.extend((peaks.len() as u32).to_le_bytes());
+        for peak in peaks {
+            bytes.extend(peak.min.to_le_bytes());
+            bytes.extend(peak.max.to_le_bytes());
+            bytes.extend(peak.rms.to_le_bytes());
+        }
+        bytes
+    }
+}
@@ -25,7 +25,7 @@ export const Clip: FC<{clip: any}> = ({clip}) => {
 		console.log("Clicked", clip.id)
 		console.log(clip)
 		// setCurrentClipFocus(clip.id)
-		invoke<{id:number, path:string}>("get_clip", { id: clip.id }).then((clip) => {
+		invoke<{id:string, path:string}>("get_clip", { id: clip.id }).then((clip) => {
 				console.log(clip)
 			setCurrentClipFocus(clip)
 			invoke("get_audio_data", { path: clip.path }).then((data) => {